@@ -23,15 +23,24 @@ use clap::{App, Arg};
 use cursive::view::*;
 use cursive::views::*;
 use cursive::Cursive;
+use sharedlib::epoch;
+use sharedlib::keys::{self, Encrypted, FileStore};
+use sharedlib::onion::PublicKey;
 use std::collections::HashMap;
+use std::process;
+use std::thread;
 
-use crate::send::rpc_put;
+use crate::send::{rotate_key, rpc_put};
+use crate::session::run_session;
 use crate::tarpc::futures::compat::Executor01CompatExt;
 use crate::tarpc::futures::FutureExt;
 use crate::tarpc::futures::TryFutureExt;
 
 pub mod fetch;
 pub mod send;
+pub mod session;
+
+const ROTATE_COMMAND: &str = "/rotate";
 
 lazy_static! {
     // quick hack to get args into callback function without modifying the
@@ -64,6 +73,14 @@ lazy_static! {
                             .long("port")
                             .help("Specifies the port of the head server in the Vuvuzela chain")
                             .takes_value(true))
+                        .arg(Arg::with_name("keys")
+                            .long("keys")
+                            .help("Specifies the directory holding the key files")
+                            .takes_value(true))
+                        .arg(Arg::with_name("passphrase_file")
+                            .long("passphrase_file")
+                            .help("Specifies a file holding the passphrase of the key files, asked for on the terminal if unset")
+                            .takes_value(true))
                         .get_matches();
 
         // if these unwraps fail, we must panic!
@@ -81,12 +98,20 @@ lazy_static! {
         let server_ip = String::from(matches.value_of("addr").unwrap_or("127.0.0.1").clone());
         let server_port = String::from(matches.value_of("port").unwrap_or("8080").clone());
 
+        let keys_dir = String::from(matches.value_of("keys").unwrap_or("./keys").clone());
+        let passphrase_file = String::from(matches.value_of("passphrase_file").unwrap_or("").clone());
+
+        m.insert(String::from("keys"), keys_dir);
+        m.insert(String::from("passphrase_file"), passphrase_file);
         m.insert(String::from("server_ip"), server_ip);
         m.insert(String::from("server_port"), server_port);
         m.insert(String::from("uid"), uid);
         m.insert(String::from("remote_uid"), remote_uid);
         m.clone()
     };
+
+    // the keys the servers sign their onion keys with, read once at startup
+    pub static ref SERVER_IDENTITY_PKS: Vec<PublicKey> = epoch::identity_pks().unwrap();
 }
 
 fn send_message(s: &mut Cursive, message: &str) {
@@ -107,12 +132,17 @@ fn send_message(s: &mut Cursive, message: &str) {
         .parse::<usize>()
         .unwrap();
 
-    let ip = HASHMAP.get(&String::from("server_ip")).unwrap();
-    let port = HASHMAP
-        .get(&String::from("server_port"))
-        .unwrap()
-        .parse::<u16>()
-        .unwrap();
+    // typed instead of a message, makes a new key and sends it to our contact
+    if message == ROTATE_COMMAND {
+        text_area.append("Changing our key, it takes effect once the round ends.\n");
+        tokio::run(
+            rotate_key(uid, remote_uid)
+                .map_err(|e| eprintln!("RPC Error: {}", e))
+                .boxed()
+                .compat(),
+        );
+        return;
+    }
 
     let mut input: String = "".to_string();
 
@@ -120,16 +150,9 @@ fn send_message(s: &mut Cursive, message: &str) {
     input.push_str("\n");
     text_area.append(input.clone());
 
-    // set up cross thread cb for response
-    let communication = s.cb_sink();
+    // the reply comes back over the session, see session::run_session
+    let send = rpc_put(input.clone(), uid, remote_uid);
 
-    let send = rpc_put(ip.to_string(),
-                       port,
-                       input.clone(),
-                       uid,
-                       remote_uid, communication.clone());
-
-    // TODO: set ip/port combo via cli flags
     tokio::run(
         (send)
         .map_err(|e| eprintln!("RPC Error: {}", e))
@@ -144,10 +167,37 @@ fn receive_message(s: &mut Cursive, message: &str) {
     text_area.append(message);
 }
 
+fn set_status(s: &mut Cursive, status: &str) {
+    let mut status_line: ViewRef<TextView> = s.find_id("status").unwrap();
+
+    status_line.set_content(status);
+}
+
 
 fn main() {
     tarpc::init(tokio::executor::DefaultExecutor::current().compat());
 
+    // private keys are sealed on disk, make sure ours opens before we start,
+    // it is kept in memory from then on
+    let uid = HASHMAP
+        .get(&String::from("uid"))
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    let keys_dir = HASHMAP.get(&String::from("keys")).unwrap();
+    let passphrase_file = HASHMAP.get(&String::from("passphrase_file")).unwrap();
+    let unlocked = keys::read_passphrase(passphrase_file).and_then(|p| {
+        let own = keys::PartyType::Client.with_id(uid);
+        keys::configure_store(Encrypted::new(FileStore::new(keys_dir), p), Some(own.clone()))?;
+        keys::get_keypair(own)?;
+        epoch::identity_pks()
+    });
+    if let Err(e) = unlocked {
+        eprintln!("Unable to open our key: {}", e);
+        process::exit(1);
+    }
+    lazy_static::initialize(&SERVER_IDENTITY_PKS);
+
     // set up main TUI context
     let mut cursive = Cursive::default();
     //cursive.set_fps(10);
@@ -177,23 +227,32 @@ fn main() {
                 SizeConstraint::Full,
                 Panel::new(scrollbar),
             ))
+            .child(TextView::new("Connecting...").with_id("status"))
             .child(text_box_view),
     );
 
-    // start fetching data from server once GUI is initialized
-    /*
-    let handler = thread::spawn(|| loop {
+    // keep a session open with the head server once GUI is initialized
+    let remote_uid = HASHMAP
+        .get(&String::from("remote_uid"))
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    let ip = HASHMAP.get(&String::from("server_ip")).unwrap();
+    let port = HASHMAP
+        .get(&String::from("server_port"))
+        .unwrap()
+        .parse::<u16>()
+        .unwrap();
+    let communication = cursive.cb_sink().clone();
+    let _handler = thread::spawn(move || {
         tokio::run(
-            (rpc_get("127.0.0.1".to_string(), 8080))
-                .map_err(|e| eprintln!("Fetch Error: {}", e))
+            run_session(ip.to_string(), port, uid, remote_uid, communication)
+                .map_err(|e| eprintln!("Session Error: {}", e))
                 .boxed()
                 .compat(),
         );
-        thread::sleep(time::Duration::from_millis(1000));
     });
-    */
 
     // Starts the event loop.
     cursive.run();
-    //handler.join().unwrap();
 }
@@ -1,77 +1,201 @@
-use sharedlib::client_util::{unwrap, wrap};
-use sharedlib::head_rpc::new_stub;
+use crate::session::{Pending, PENDING, SESSION};
+use crate::SERVER_IDENTITY_PKS;
+use sharedlib::client_util::{keep_ratchet, load_ratchet, unwrap, wrap};
+use sharedlib::conn;
+use sharedlib::epoch::{self, epoch_of};
+use sharedlib::head_rpc::Refused;
+use sharedlib::key_change::KeyChange;
 use sharedlib::keys::{get, get_keypair, PartyType};
-use sharedlib::onion::derive;
+use sharedlib::message;
+use sharedlib::onion::{self, configure_suite, DerivedKey, KeyPair, PrivateKey, PublicKey};
+use sharedlib::ratchet::{Ratchet, RoundKeys};
+use sharedlib::session::unix_millis;
 use std::io;
-use std::net::{IpAddr, SocketAddr};
 use std::string::String;
-use tarpc::{client, context};
-use tarpc_bincode_transport::connect;
-use crossbeam_channel::Sender;
-use cursive::CbFunc;
-use cursive::Cursive;
-use crate::receive_message;
-use std::ffi::CString;
-
-pub async fn rpc_put(
-    server_addr: String,
-    port: u16,
-    message: String,
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tarpc::context;
+
+// don't submit into a round that closes sooner than this
+const SUBMIT_MARGIN_MS: u64 = 100;
+
+lazy_static! {
+    // our side of the conversation's key schedule, read from the key store
+    // on the first send and written back there whenever it moves
+    static ref RATCHET: Mutex<Option<Ratchet>> = Mutex::new(None);
+}
+
+/// Start the conversation over from `sk` and `remote_pk`, after one side
+/// changed its key.
+pub fn restart_ratchet(
+    uid: usize,
+    remote_uid: usize,
+    sk: &PrivateKey,
+    remote_pk: &PublicKey,
+) -> io::Result<()> {
+    let ratchet = Ratchet::new(sk, remote_pk)?;
+    keep_ratchet(uid, remote_uid, &ratchet)?;
+    *RATCHET.lock().unwrap() = Some(ratchet);
+    Ok(())
+}
+
+/// Open our contact's message in the reply to ours of the round of
+/// `keys`, giving its padded text, and keep the conversation moved on by
+/// the ephemeral key it carried.
+pub fn open_reply(
+    uid: usize,
+    remote_uid: usize,
+    reply: onion::Message,
+    keys: &RoundKeys,
+    server_dks: Vec<DerivedKey>,
+) -> io::Result<Vec<u8>> {
+    let mut ratchet = RATCHET.lock().unwrap();
+    let ratchet = match ratchet.as_mut() {
+        Some(r) => r,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "no conversation yet")),
+    };
+    let text = unwrap(reply, keys, ratchet, server_dks)?;
+    keep_ratchet(uid, remote_uid, ratchet)?;
+    Ok(text)
+}
+
+pub async fn rpc_put(message: String, uid: usize, remote_uid: usize) -> io::Result<()> {
+    await!(submit(message.into_bytes(), uid, remote_uid, None))
+}
+
+/// Make a new key pair and send the new public key to our contact, bound
+/// to the old key. The old pair stays in use until the contact's message
+/// for that round comes back, which shows they got the notice; see
+/// `session::show_reply`.
+pub async fn rotate_key(uid: usize, remote_uid: usize) -> io::Result<()> {
+    let rotating = PENDING.lock().unwrap().values().any(|p| p.rotation.is_some());
+    if rotating {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "already changing our key, wait for the round to end",
+        ));
+    }
+    let (old_sk, _) = get_keypair(PartyType::Client.with_id(uid))?;
+    let remote_pub_key = get(PartyType::Client.with_id(remote_uid))?;
+    let (new_sk, new_pk) = onion::keygen()?;
+    let notice = KeyChange::new(&old_sk, &remote_pub_key, new_pk.clone())?;
+    await!(submit(notice.to_bytes(), uid, remote_uid, Some((new_sk, new_pk))))
+}
+
+// send `payload` in the next round we can make, `rotation` is the key
+// pair a key change notice announces
+async fn submit(
+    payload: Vec<u8>,
     uid: usize,
     remote_uid: usize,
-    comm: Sender<Box<CbFunc>>,
+    rotation: Option<KeyPair>,
 ) -> io::Result<()> {
-    let server_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
-    let transport = await!(connect(&server_addr)).unwrap();
-    let mut client = await!(new_stub(client::Config::default(), transport)).unwrap();
-    // put this here just to get stuff to work, can fix later
-    // TODO: get keys statically
-    // get client keypair
-    let (priv_key, pub_key) = get_keypair(PartyType::Client.with_id(uid)).unwrap();
+    // reuse the session's connection rather than dialing the server again
+    let (mut client, session) = match SESSION.lock().unwrap().clone() {
+        Some(s) => s,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no session with the head server yet",
+            ))
+        }
+    };
+
+    // get client keypair, read from disk once and held by keys after that
+    let (priv_key, _) = get_keypair(PartyType::Client.with_id(uid))?;
 
     // get other client public key
-    let remote_pub_key = get(PartyType::Client.with_id(remote_uid)).unwrap();
-    let dk = derive(&priv_key, &remote_pub_key);
-
-    // get round num, this is temporary, actual client that times itself doesnt need this
-    let rn = await!(client.getrn(context::current())).unwrap();
-
-    // get vec of server pkeys
-    let mut server_pub_keys = vec![];
-    server_pub_keys.push(get(PartyType::Server.with_id(0)).unwrap());
-    server_pub_keys.push(get(PartyType::Server.with_id(1)).unwrap());
-    server_pub_keys.push(get(PartyType::Server.with_id(2)).unwrap());
-
-    let (d_key, enc_msg) = wrap(
-        rn,
-        message.as_bytes().to_vec(),
-        &remote_pub_key,
-        &dk,
-        &server_pub_keys,
-    );
+    let remote_pub_key = get(PartyType::Client.with_id(remote_uid))?;
+
+    // the servers' identity keys, which sign the onion keys of each epoch
+    let identity_pks = &*SERVER_IDENTITY_PKS;
+
+    // learn the open round and check we agree with the chain's parameters
+    let info = await!(client.round_info(context::current()))?;
+    if info.server_pks != *identity_pks {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "server public keys do not match the chain's",
+        ));
+    }
+    if info.message_size != message::RAW_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message size does not match the chain's",
+        ));
+    }
+    // wrap under the chain's cipher suite
+    configure_suite(info.suite);
+
+    // too close to the deadline to make it, aim for the next round instead
+    let mut rn = info.round;
+    let left = info.deadline.saturating_sub(unix_millis(SystemTime::now()));
+    if left < SUBMIT_MARGIN_MS {
+        await!(conn::sleep(Duration::from_millis(left)))?;
+        rn += 1;
+    }
+    // the onion keys for the round's epoch, each signed by its server
+    let server_pub_keys = epoch::chain_pks(&info.epoch_keys, identity_pks, 0, epoch_of(rn))?;
+
+    // each round's keys are handed out once, and are gone after this message
+    let keys = {
+        let mut ratchet = RATCHET.lock().unwrap();
+        if ratchet.is_none() {
+            *ratchet = Some(load_ratchet(uid, remote_uid, &priv_key, &remote_pub_key)?);
+        }
+        let ratchet = ratchet.as_mut().unwrap();
+        if rn < ratchet.round() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("already sent a message in round {}, wait for the next", rn),
+            ));
+        }
+        let keys = ratchet.keys(rn)?;
+        keep_ratchet(uid, remote_uid, ratchet)?;
+        keys
+    };
+
+    let (d_key, enc_msg) = wrap(payload, &keys, &server_pub_keys)?;
+
+    // send it, the reply is pushed to us over the session when the round ends
+    let ticket = match await!(client.session_put(context::current(), session, enc_msg))? {
+        Ok(t) => t,
+        Err(Refused::UnknownSession) => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "session expired before the message was sent",
+            ))
+        }
+        Err(Refused::Malformed) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the head server refused the message as malformed",
+            ))
+        }
+        Err(Refused::UnsupportedSuite) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the head server uses another cipher suite",
+            ))
+        }
+    };
+    // sealed for round rn, nobody can open it in another round
+    if ticket.round != rn {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("round {} closed before our message arrived, it was lost", rn),
+        ));
+    }
+
     // store the d_keys for when we receive a message at the end of the round
-    // send it
-    //println!("Encrypted msg: {:?}, len: {:?}", enc_msg, enc_msg.len());
-    let return_msg = await!(client.put(context::current(), enc_msg)).unwrap();
-    //println!("Response: {:?}, len: {:?}", return_msg, return_msg.len());
-    match unwrap(rn, return_msg.clone(), &pub_key, &dk, d_key.clone()) {
-        Ok(unwrapped_msg) => {
-            let mut output = String::from_utf8(unwrapped_msg.to_vec()).unwrap().clone();
-            output = output.trim_matches(char::from(0)).to_string();
-
-            output.insert_str(0, ": ");
-            output.insert_str(0, &remote_uid.to_string());
-            output.insert_str(0, "From ");
-
-            // make string c compat
-            let c_str = CString::new(output).unwrap();
-            let f = c_str.into_string().unwrap();
-
-            let _res = comm.send(Box::new(move |s: &mut Cursive| receive_message(s, &f)));
+    PENDING.lock().unwrap().insert(
+        ticket,
+        Pending {
+            keys,
+            server_dks: d_key,
+            rotation,
         },
-        Ok(_) => println!("Received original message: remote not present."),
-        Err(e) => println!("Could not decrypt: system error."),
-    }
+    );
 
     Ok(())
 }
@@ -0,0 +1,187 @@
+use crate::fetch::rpc_fetch;
+use crate::send::{open_reply, restart_ratchet};
+use crate::{receive_message, set_status};
+use crossbeam_channel::Sender;
+use cursive::CbFunc;
+use cursive::Cursive;
+use sharedlib::conn;
+use sharedlib::error::Result;
+use sharedlib::head_rpc::{new_stub, Client, FetchResult, Ticket};
+use sharedlib::key_change::KeyChange;
+use sharedlib::keys::{self, get, get_keypair, PartyType};
+use sharedlib::onion::{DerivedKey, KeyPair, PublicKey};
+use sharedlib::ratchet::RoundKeys;
+use sharedlib::session::{unix_millis, Event, SessionId};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tarpc::{client, context};
+use tarpc_bincode_transport::connect;
+
+lazy_static! {
+    // our session with the head server, shared with the send path
+    pub static ref SESSION: Mutex<Option<(Client, SessionId)>> = Mutex::new(None);
+    // messages we sent whose replies have not arrived yet
+    pub static ref PENDING: Mutex<HashMap<Ticket, Pending>> = Mutex::new(HashMap::new());
+}
+
+/// What we need to open the reply to a message we sent.
+pub struct Pending {
+    pub keys: RoundKeys,
+    pub server_dks: Vec<DerivedKey>,
+    // the key pair the message announced, if it was a key change notice
+    pub rotation: Option<KeyPair>,
+}
+
+/// Hold one connection to the head server for the lifetime of the client,
+/// showing round announcements and pushed replies as they arrive.
+pub async fn run_session(
+    server_addr: String,
+    port: u16,
+    uid: usize,
+    remote_uid: usize,
+    comm: Sender<Box<CbFunc>>,
+) -> io::Result<()> {
+    let socket_addr = conn::socket_addr(&server_addr, port)?;
+    let transport = await!(connect(&socket_addr))?;
+    let mut client = await!(new_stub(client::Config::default(), transport))?;
+
+    loop {
+        let id = await!(client.open_session(context::current()))?;
+        *SESSION.lock().unwrap() = Some((client.clone(), id));
+
+        // replies owed to an expired session can still be fetched by ticket
+        let orphaned: Vec<Ticket> = PENDING.lock().unwrap().keys().cloned().collect();
+        for ticket in orphaned {
+            let reply = await!(rpc_fetch(server_addr.clone(), port, ticket))?;
+            show_reply(ticket, reply, uid, remote_uid, &comm);
+        }
+
+        while let Some(events) = await!(client.next_events(context::current(), id))? {
+            for e in events {
+                match e {
+                    Event::RoundOpen { round, deadline } => {
+                        let left = deadline.saturating_sub(unix_millis(SystemTime::now()));
+                        let status = format!("Round {} open, closes in {} ms", round, left);
+                        let _res =
+                            comm.send(Box::new(move |s: &mut Cursive| set_status(s, &status)));
+                    }
+                    Event::RoundResult { ticket, reply } => {
+                        show_reply(ticket, reply, uid, remote_uid, &comm)
+                    }
+                }
+            }
+        }
+        println!("Session {} expired, opening a new one.", id);
+    }
+}
+
+fn show_reply(
+    ticket: Ticket,
+    reply: FetchResult,
+    uid: usize,
+    remote_uid: usize,
+    comm: &Sender<Box<CbFunc>>,
+) {
+    let p = match PENDING.lock().unwrap().remove(&ticket) {
+        Some(p) => p,
+        None => return,
+    };
+    let reply = match reply {
+        FetchResult::Reply(r) => r,
+        FetchResult::Failed => {
+            let status = format!("Round {} failed, the message was not delivered", ticket.round);
+            let _res = comm.send(Box::new(move |s: &mut Cursive| set_status(s, &status)));
+            return;
+        }
+        FetchResult::Expired => {
+            println!("Reply for round {} expired before it was fetched.", ticket.round);
+            return;
+        }
+    };
+
+    let unwrapped_msg = match open_reply(uid, remote_uid, reply, &p.keys, p.server_dks) {
+        Ok(m) => m,
+        Err(e) => {
+            // no message from our contact in it, only our own back
+            if p.rotation.is_some() {
+                // no message from our contact, so they may not have our notice either
+                show(comm, "Key change not delivered, still using the old key.".to_string());
+            }
+            println!("Could not decrypt: {}", e);
+            return;
+        }
+    };
+
+    // checked against the keys both sides held when it was sent
+    let notice = KeyChange::parse(&unwrapped_msg).map(|n| -> Result<PublicKey> {
+        let (sk, _) = get_keypair(PartyType::Client.with_id(uid))?;
+        n.verify(&sk, &get(PartyType::Client.with_id(remote_uid))?)?;
+        Ok(n.new_pk)
+    });
+
+    // our contact answered in the round of our notice, so they have it
+    if let Some(pair) = p.rotation {
+        match finish_rotation(uid, remote_uid, pair) {
+            Ok(()) => show(comm, "Our key changed, new messages use the new key.".to_string()),
+            Err(e) => println!("Could not switch to the new key: {}", e),
+        }
+    }
+
+    match notice {
+        None => {
+            let mut output = String::from_utf8_lossy(&unwrapped_msg).into_owned();
+            output = output.trim_matches(char::from(0)).to_string();
+
+            output.insert_str(0, ": ");
+            output.insert_str(0, &remote_uid.to_string());
+            output.insert_str(0, "From ");
+
+            // make string c compat
+            let c_str = CString::new(output).unwrap();
+            let f = c_str.into_string().unwrap();
+
+            let _res = comm.send(Box::new(move |s: &mut Cursive| receive_message(s, &f)));
+        }
+        Some(Ok(new_pk)) => match accept_key_change(uid, remote_uid, new_pk) {
+            Ok(()) => show(
+                comm,
+                format!(
+                    "WARNING: {} changed their key. If they did not mean to, their old key \
+                     may have been stolen.",
+                    remote_uid
+                ),
+            ),
+            Err(e) => println!("Could not store the new key of {}: {}", remote_uid, e),
+        },
+        Some(Err(e)) => show(
+            comm,
+            format!(
+                "WARNING: ignored a key change for {} that failed to verify: {}",
+                remote_uid, e
+            ),
+        ),
+    }
+}
+
+fn show(comm: &Sender<Box<CbFunc>>, line: String) {
+    let line = format!("{}\n", line);
+    let _res = comm.send(Box::new(move |s: &mut Cursive| receive_message(s, &line)));
+}
+
+// start the conversation over with our announced key pair and store it in
+// place of the old one, which is cleared
+fn finish_rotation(uid: usize, remote_uid: usize, pair: KeyPair) -> io::Result<()> {
+    let remote_pk = get(PartyType::Client.with_id(remote_uid))?;
+    restart_ratchet(uid, remote_uid, &pair.0, &remote_pk)?;
+    Ok(keys::put(PartyType::Client.with_id(uid), pair)?)
+}
+
+// store the contact's announced key and start the conversation over with it
+fn accept_key_change(uid: usize, remote_uid: usize, new_pk: PublicKey) -> io::Result<()> {
+    keys::put_public(PartyType::Client.with_id(remote_uid), new_pk.clone())?;
+    let (sk, _) = get_keypair(PartyType::Client.with_id(uid))?;
+    restart_ratchet(uid, remote_uid, &sk, &new_pk)
+}
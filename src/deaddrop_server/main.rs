@@ -15,11 +15,18 @@ use crate::tarpc::futures::{compat::Executor01CompatExt, FutureExt, TryFutureExt
 use tarpc::server::Handler;
 use tarpc_bincode_transport::listen;
 
+use sharedlib::conn::socket_addr;
 use sharedlib::deaddrop_rpc::serve;
 use sharedlib::deaddrop_rpc::DeadDropServer;
+use sharedlib::keys::{self, Encrypted, FileStore, PartyType};
+use sharedlib::onion::{configure_suite, CipherSuite};
+use sharedlib::pipeline::configure_timeout;
+use sharedlib::shard_rpc::configure_shards;
 
 use std::io;
-use std::net::{IpAddr, SocketAddr};
+use std::net::SocketAddr;
+use std::process;
+use std::time::Duration;
 
 use tarpc::server;
 
@@ -56,14 +63,45 @@ lazy_static! {
                             .long("variance")
                             .help("Specifies the variance of the noise distribution, for differential privacy")
                             .takes_value(true))
+                        .arg(Arg::with_name("shards")
+                            .long("shards")
+                            .help("Comma separated addr:port list of deaddrop shard servers, the exchange is done here if empty")
+                            .takes_value(true))
+                        .arg(Arg::with_name("timeout")
+                            .long("timeout")
+                            .help("Specifies how many milliseconds each phase of a round may take before the round is aborted")
+                            .takes_value(true))
+                        .arg(Arg::with_name("suite")
+                            .long("suite")
+                            .help("Specifies the cipher suite of the chain, aes256gcm, chacha20poly1305 or (built with the hybrid feature) hybrid-mlkem768, the same on every server")
+                            .takes_value(true))
+                        .arg(Arg::with_name("keys")
+                            .long("keys")
+                            .help("Specifies the directory holding the key files")
+                            .takes_value(true))
+                        .arg(Arg::with_name("passphrase_file")
+                            .long("passphrase_file")
+                            .help("Specifies a file holding the passphrase of the key files, asked for on the terminal if unset")
+                            .takes_value(true))
                         .get_matches();
 
         let server_ip = String::from(matches.value_of("addr").unwrap_or("127.0.0.1").clone());
         let server_port = String::from(matches.value_of("port").unwrap_or("8082").clone());
         let micro = String::from(matches.value_of("micro").unwrap_or("10").clone());
         let b = String::from(matches.value_of("variance").unwrap_or("0").clone());
+        let timeout = String::from(matches.value_of("timeout").unwrap_or("30000").clone());
+        let suite = String::from(matches.value_of("suite").unwrap_or("aes256gcm").clone());
+        let shards = String::from(matches.value_of("shards").unwrap_or("").clone());
 
+        let keys_dir = String::from(matches.value_of("keys").unwrap_or("./keys").clone());
+        let passphrase_file = String::from(matches.value_of("passphrase_file").unwrap_or("").clone());
+
+        m.insert(String::from("keys"), keys_dir);
+        m.insert(String::from("passphrase_file"), passphrase_file);
         m.insert(String::from("variance"), b);
+        m.insert(String::from("timeout"), timeout);
+        m.insert(String::from("suite"), suite);
+        m.insert(String::from("shards"), shards);
         m.insert(String::from("micro"), micro);
         m.insert(String::from("server_ip"), server_ip);
         m.insert(String::from("server_port"), server_port);
@@ -72,7 +110,7 @@ lazy_static! {
 }
 
 async fn run_service(server_addr: &str, port: u16) -> io::Result<()> {
-    let server_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
+    let server_addr = socket_addr(server_addr, port)?;
     let transport = listen(&server_addr)?;
     let _addr = transport.local_addr();
 
@@ -90,6 +128,34 @@ async fn run_service(server_addr: &str, port: u16) -> io::Result<()> {
         None => panic!("No input provided for the micro flag!"),
     };
 
+    let shards: Vec<SocketAddr> = match HASHMAP.get(&String::from("shards")) {
+        // param was passed
+        Some(x) => x
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<SocketAddr>().expect("Bad deaddrop shard address"))
+            .collect(),
+        // no param!
+        None => panic!("No input provided for the shards flag!"),
+    };
+    configure_shards(shards);
+
+    let timeout: u64 = match HASHMAP.get(&String::from("timeout")) {
+        // param was passed
+        Some(x) => x.parse::<u64>().unwrap(),
+        // no param!
+        None => panic!("No input provided for the timeout flag!"),
+    };
+    configure_timeout(Duration::from_millis(timeout));
+
+    let suite = match HASHMAP.get(&String::from("suite")) {
+        // param was passed
+        Some(x) => CipherSuite::from_name(x).expect("Unknown cipher suite"),
+        // no param!
+        None => panic!("No input provided for the suite flag!"),
+    };
+    configure_suite(suite);
+
     // The server is configured with the defaults.
     let server = server::new(server::Config::default())
         // Server can listen on any type that implements the Transport trait.
@@ -109,6 +175,20 @@ async fn run_service(server_addr: &str, port: u16) -> io::Result<()> {
 fn main() {
     tarpc::init(tokio::executor::DefaultExecutor::current().compat());
 
+    // private keys are sealed on disk, make sure ours opens before we start,
+    // it is kept in memory from then on
+    let keys_dir = HASHMAP.get(&String::from("keys")).unwrap();
+    let passphrase_file = HASHMAP.get(&String::from("passphrase_file")).unwrap();
+    let unlocked = keys::read_passphrase(passphrase_file).and_then(|p| {
+        let own = PartyType::Server.with_id(sharedlib::NUM_SERVERS - 1);
+        keys::configure_store(Encrypted::new(FileStore::new(keys_dir), p), Some(own.clone()))?;
+        keys::get_identity_private(own)
+    });
+    if let Err(e) = unlocked {
+        eprintln!("Unable to open our identity key: {}", e);
+        process::exit(1);
+    }
+
     let ip = HASHMAP.get(&String::from("server_ip")).unwrap();
     let port = HASHMAP
         .get(&String::from("server_port"))
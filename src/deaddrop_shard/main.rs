@@ -0,0 +1,86 @@
+#![feature(futures_api, arbitrary_self_types, await_macro, async_await)]
+
+#[macro_use]
+extern crate lazy_static;
+
+extern crate clap;
+extern crate sharedlib;
+extern crate tarpc;
+extern crate tarpc_bincode_transport;
+extern crate tokio;
+
+use crate::tarpc::futures::{compat::Executor01CompatExt, FutureExt, TryFutureExt};
+
+use tarpc::server::Handler;
+use tarpc_bincode_transport::listen;
+
+use sharedlib::conn::socket_addr;
+use sharedlib::shard_rpc::serve;
+use sharedlib::shard_rpc::DeaddropShard;
+
+use std::io;
+
+use tarpc::server;
+
+use clap::{App, Arg};
+use std::collections::HashMap;
+
+lazy_static! {
+    static ref HASHMAP: HashMap<String, String> = {
+        let mut m = HashMap::new();
+        let matches = App::new("Vuvuzela Deaddrop Shard")
+                        .version("1.0")
+                        .about("Exchanges the messages of a range of deaddrops for the last Vuvuzela server")
+                        .author("Sam Ginzburg")
+                        .author("Benjamin Kuykendall")
+                        .arg(Arg::with_name("addr")
+                            .short("a")
+                            .long("addr")
+                            .help("Specifies which addr to bind the RPC server to")
+                            .takes_value(true))
+                        .arg(Arg::with_name("port")
+                            .short("p")
+                            .long("port")
+                            .help("Specifies which port to bind the RPC server to")
+                            .takes_value(true))
+                        .get_matches();
+
+        let server_ip = String::from(matches.value_of("addr").unwrap_or("127.0.0.1").clone());
+        let server_port = String::from(matches.value_of("port").unwrap_or("8090").clone());
+
+        m.insert(String::from("server_ip"), server_ip);
+        m.insert(String::from("server_port"), server_port);
+        m.clone()
+    };
+}
+
+async fn run_service(server_addr: &str, port: u16) -> io::Result<()> {
+    let server_addr = socket_addr(server_addr, port)?;
+    let transport = listen(&server_addr)?;
+
+    let server = server::new(server::Config::default())
+        .incoming(transport)
+        .respond_with(serve(DeaddropShard));
+
+    await!(server);
+
+    Ok(())
+}
+
+fn main() {
+    tarpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    let ip = HASHMAP.get(&String::from("server_ip")).unwrap();
+    let port = HASHMAP
+        .get(&String::from("server_port"))
+        .unwrap()
+        .parse::<u16>()
+        .unwrap();
+
+    tokio::run(
+        run_service(ip, port)
+            .map_err(|e| eprintln!("RPC Error: {}", e))
+            .boxed()
+            .compat(),
+    );
+}
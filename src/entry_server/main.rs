@@ -0,0 +1,173 @@
+#![feature(futures_api, arbitrary_self_types, await_macro, async_await)]
+
+#[macro_use]
+extern crate lazy_static;
+
+extern crate clap;
+extern crate sharedlib;
+extern crate tarpc;
+extern crate tarpc_bincode_transport;
+extern crate tokio;
+
+use crate::tarpc::futures::compat::Executor01CompatExt;
+use crate::tarpc::futures::future;
+use crate::tarpc::futures::FutureExt;
+use crate::tarpc::futures::StreamExt;
+use crate::tarpc::futures::TryFutureExt;
+
+use clap::{App, Arg};
+use std::collections::HashMap;
+
+use tarpc::server::Handler;
+use tarpc::Transport;
+use tarpc_bincode_transport::listen;
+
+use sharedlib::entry_rpc::{self, join_chain, EntryServer};
+use sharedlib::head_rpc::{self, ClientsOnly};
+
+use std::net::{IpAddr, SocketAddr};
+use std::{io, thread};
+use tarpc::server;
+use tokio::runtime::Builder;
+
+lazy_static! {
+    static ref HASHMAP: HashMap<String, String> = {
+        let mut m = HashMap::new();
+        let matches = App::new("Vuvuzela Entry Server")
+                        .version("1.0")
+                        .about("Takes client connections for the Vuvuzela head server")
+                        .author("Sam Ginzburg")
+                        .author("Benjamin Kuykendall")
+                        .arg(Arg::with_name("addr")
+                            .short("a")
+                            .long("addr")
+                            .help("Specifies which addr to bind the RPC servers to")
+                            .takes_value(true))
+                        .arg(Arg::with_name("port")
+                            .short("p")
+                            .long("port")
+                            .help("Specifies which port clients connect to")
+                            .takes_value(true))
+                        .arg(Arg::with_name("intakeport")
+                            .long("intakeport")
+                            .help("Specifies which port the head server collects messages from")
+                            .takes_value(true))
+                        .arg(Arg::with_name("headaddr")
+                            .long("headaddr")
+                            .help("Specifies the addr of the head server")
+                            .takes_value(true))
+                        .arg(Arg::with_name("headport")
+                            .long("headport")
+                            .help("Specifies the port of the head server")
+                            .takes_value(true))
+                        .arg(Arg::with_name("retain")
+                            .long("retain")
+                            .help("Specifies how many rounds of replies to keep for fetch")
+                            .takes_value(true))
+                        .get_matches();
+
+        let server_ip = String::from(matches.value_of("addr").unwrap_or("127.0.0.1").clone());
+        let server_port = String::from(matches.value_of("port").unwrap_or("8070").clone());
+        let intake_port = String::from(matches.value_of("intakeport").unwrap_or("8071").clone());
+        let head_ip = String::from(matches.value_of("headaddr").unwrap_or("127.0.0.1").clone());
+        let head_port = String::from(matches.value_of("headport").unwrap_or("8080").clone());
+        let retain = String::from(matches.value_of("retain").unwrap_or("10").clone());
+
+        m.insert(String::from("server_ip"), server_ip);
+        m.insert(String::from("server_port"), server_port);
+        m.insert(String::from("intake_port"), intake_port);
+        m.insert(String::from("head_ip"), head_ip);
+        m.insert(String::from("head_port"), head_port);
+        m.insert(String::from("retain"), retain);
+        m.clone()
+    };
+}
+
+fn socket_addr(ip: &str, port: &str) -> SocketAddr {
+    SocketAddr::new(
+        IpAddr::V4(HASHMAP.get(ip).unwrap().parse().unwrap()),
+        HASHMAP.get(port).unwrap().parse::<u16>().unwrap(),
+    )
+}
+
+// the head server's RPCs for clients, without the chain's own
+async fn run_client_service(addr: SocketAddr) -> io::Result<()> {
+    let transport = listen(&addr)?;
+    let server = server::new(server::Config::default())
+        .incoming(transport)
+        .respond_with(head_rpc::serve(ClientsOnly));
+
+    await!(server);
+
+    Ok(())
+}
+
+// where the head server collects our messages and returns the replies,
+// nobody else may close our rounds or hand us replies
+async fn run_intake_service(addr: SocketAddr, retain: usize) -> io::Result<()> {
+    let head = socket_addr("head_ip", "head_port");
+    await!(join_chain(head))?;
+
+    let transport = listen(&addr)?.filter(move |t| {
+        let from = t.as_ref().ok().and_then(|t| t.peer_addr().ok());
+        match from {
+            Some(a) if a.ip() == head.ip() => future::ready(true),
+            Some(a) => {
+                eprintln!("Refused an intake connection from {}", a);
+                future::ready(false)
+            }
+            None => future::ready(false),
+        }
+    });
+    let server = server::new(server::Config::default())
+        .incoming(transport)
+        .respond_with(entry_rpc::serve(EntryServer { retain, head }));
+
+    await!(server);
+
+    Ok(())
+}
+
+fn main() {
+    let runtime = Builder::new()
+        .blocking_threads(32768 / 2) // max value allowed / 2
+        .core_threads(16)
+        .name_prefix("rpc-tpool-")
+        .stack_size(3 * 1024 * 1024)
+        .build()
+        .unwrap();
+    tarpc::init(runtime.executor().compat());
+
+    let retain = HASHMAP
+        .get(&String::from("retain"))
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+
+    let intake = thread::Builder::new()
+        .name("intake_thread".to_string())
+        .spawn(move || {
+            tokio::run(
+                run_intake_service(socket_addr("server_ip", "intake_port"), retain)
+                    .map_err(|e| eprintln!("RPC Error: {}", e))
+                    .boxed()
+                    .compat(),
+            );
+        })
+        .unwrap();
+
+    let clients = thread::Builder::new()
+        .name("rpc_thread".to_string())
+        .spawn(move || {
+            tokio::run(
+                run_client_service(socket_addr("server_ip", "server_port"))
+                    .map_err(|e| eprintln!("RPC Error: {}", e))
+                    .boxed()
+                    .compat(),
+            );
+        })
+        .unwrap();
+
+    clients.join().unwrap();
+    intake.join().unwrap();
+}
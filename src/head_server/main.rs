@@ -4,6 +4,7 @@
 extern crate lazy_static;
 
 extern crate clap;
+extern crate crossbeam_channel;
 extern crate rand;
 extern crate sharedlib;
 extern crate tarpc;
@@ -11,6 +12,7 @@ extern crate tarpc_bincode_transport;
 extern crate tokio;
 
 mod round;
+mod schedule;
 
 use crate::tarpc::futures::compat::Executor01CompatExt;
 use crate::tarpc::futures::FutureExt;
@@ -25,18 +27,27 @@ use tarpc_bincode_transport::listen;
 use sharedlib::head_rpc::serve;
 use sharedlib::head_rpc::HeadServer;
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::{io, thread, time};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::{io, process, thread, time};
 
+use crate::schedule::Scheduler;
 use crate::round::{
-    block_on_replies, cleanup, end_round, round_status_check, send_m_vec, start_round,
-    waiting_for_next,
+    abort_round, cleanup, collect_entries, end_round, refresh_epoch_keys, round_status_check,
+    send_m_vec, start_round, waiting_for_next,
 };
 use sharedlib::head_rpc::{
-    BACKWARDS_MESSAGES, LOCAL_ROUND_ENDED, MESSAGES, PROCESSED_BACKWARDS_MESSAGES,
-    REQUEST_RESPONSE_BLOCK,
+    announce_round, configure_chain, ROUND_NUM,
 };
-use std::time::Instant;
+use sharedlib::conn::within;
+use sharedlib::epoch;
+use sharedlib::framed;
+use sharedlib::keys::{self, Encrypted, FileStore, PartyType};
+use sharedlib::onion::{configure_suite, CipherSuite};
+use sharedlib::pipeline::{configure_timeout, MAX_IN_FLIGHT};
+use sharedlib::session::unix_millis;
+use sharedlib::util::State;
+use crossbeam_channel::bounded;
+use std::time::{Instant, SystemTime};
 use tarpc::server;
 use tokio::runtime::Builder;
 
@@ -79,7 +90,43 @@ lazy_static! {
                         .arg(Arg::with_name("roundtime")
                             .short("r")
                             .long("roundtime")
-                            .help("Specifies the time between rounds in seconds")
+                            .help("Specifies the time between rounds in milliseconds")
+                            .takes_value(true))
+                        .arg(Arg::with_name("batch")
+                            .long("batch")
+                            .help("Starts a round early once this many messages arrive (0 to disable)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("pipeline")
+                            .long("pipeline")
+                            .help("Specifies how many rounds may wait on the rest of the chain at once")
+                            .takes_value(true))
+                        .arg(Arg::with_name("retain")
+                            .long("retain")
+                            .help("Specifies how many rounds of replies to keep for fetch")
+                            .takes_value(true))
+                        .arg(Arg::with_name("timeout")
+                            .long("timeout")
+                            .help("Specifies how many milliseconds each phase of a round may take before the round is aborted")
+                            .takes_value(true))
+                        .arg(Arg::with_name("suite")
+                            .long("suite")
+                            .help("Specifies the cipher suite of the chain, aes256gcm, chacha20poly1305 or (built with the hybrid feature) hybrid-mlkem768, the same on every server")
+                            .takes_value(true))
+                        .arg(Arg::with_name("entries")
+                            .long("entries")
+                            .help("Comma separated addr:port list of entry servers to collect messages from each round")
+                            .takes_value(true))
+                        .arg(Arg::with_name("frame_port")
+                            .long("frame_port")
+                            .help("Specifies which port to serve clients speaking the frame format on, none if unset")
+                            .takes_value(true))
+                        .arg(Arg::with_name("keys")
+                            .long("keys")
+                            .help("Specifies the directory holding the key files")
+                            .takes_value(true))
+                        .arg(Arg::with_name("passphrase_file")
+                            .long("passphrase_file")
+                            .help("Specifies a file holding the passphrase of the key files, asked for on the terminal if unset")
                             .takes_value(true))
                         .get_matches();
 
@@ -88,9 +135,28 @@ lazy_static! {
         let server_port = String::from(matches.value_of("port").unwrap_or("8080").clone());
         let micro = String::from(matches.value_of("micro").unwrap_or("10").clone());
         let b = String::from(matches.value_of("variance").unwrap_or("0").clone());
-        let rt = String::from(matches.value_of("roundtime").unwrap_or("2").clone());
+        let rt = String::from(matches.value_of("roundtime").unwrap_or("2000").clone());
+        let batch = String::from(matches.value_of("batch").unwrap_or("0").clone());
+        let retain = String::from(matches.value_of("retain").unwrap_or("10").clone());
+        let pipeline = String::from(matches.value_of("pipeline").unwrap_or("2").clone());
+        let entries = String::from(matches.value_of("entries").unwrap_or("").clone());
+        let timeout = String::from(matches.value_of("timeout").unwrap_or("30000").clone());
+        let suite = String::from(matches.value_of("suite").unwrap_or("aes256gcm").clone());
+        let frame_port = String::from(matches.value_of("frame_port").unwrap_or("").clone());
+
+        let keys_dir = String::from(matches.value_of("keys").unwrap_or("./keys").clone());
+        let passphrase_file = String::from(matches.value_of("passphrase_file").unwrap_or("").clone());
 
+        m.insert(String::from("keys"), keys_dir);
+        m.insert(String::from("passphrase_file"), passphrase_file);
         m.insert(String::from("roundtime"), rt);
+        m.insert(String::from("batch"), batch);
+        m.insert(String::from("retain"), retain);
+        m.insert(String::from("pipeline"), pipeline);
+        m.insert(String::from("entries"), entries);
+        m.insert(String::from("timeout"), timeout);
+        m.insert(String::from("suite"), suite);
+        m.insert(String::from("frame_port"), frame_port);
         m.insert(String::from("variance"), b);
         m.insert(String::from("server_id"), server_uid);
         m.insert(String::from("server_ip"), server_ip);
@@ -129,6 +195,34 @@ fn main() {
         .unwrap();
     tarpc::init(runtime.executor().compat());
 
+    // private keys are sealed on disk, make sure ours opens before we start,
+    // it is kept in memory from then on
+    let server_id = HASHMAP
+        .get(&String::from("server_id"))
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    let keys_dir = HASHMAP.get(&String::from("keys")).unwrap();
+    let passphrase_file = HASHMAP.get(&String::from("passphrase_file")).unwrap();
+    let unlocked = keys::read_passphrase(passphrase_file).and_then(|p| {
+        let own = PartyType::Server.with_id(server_id);
+        keys::configure_store(Encrypted::new(FileStore::new(keys_dir), p), Some(own.clone()))?;
+        keys::get_identity_private(own)
+    });
+    if let Err(e) = unlocked {
+        eprintln!("Unable to open our identity key: {}", e);
+        process::exit(1);
+    }
+
+    // before any client can send us a message
+    let suite = match HASHMAP.get(&String::from("suite")) {
+        // param was passed
+        Some(x) => CipherSuite::from_name(x).expect("Unknown cipher suite"),
+        // no param!
+        None => panic!("No input provided for the suite flag!"),
+    };
+    configure_suite(suite);
+
     let ip = HASHMAP.get(&String::from("server_ip")).unwrap();
     let port = HASHMAP
         .get(&String::from("server_port"))
@@ -148,6 +242,23 @@ fn main() {
         })
         .unwrap();
 
+    let frame_port = HASHMAP.get(&String::from("frame_port")).unwrap();
+    if !frame_port.is_empty() {
+        let port = frame_port.parse::<u16>().unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        let listener = match TcpListener::bind(addr) {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("Unable to serve framed clients on {}: {}", addr, e);
+                process::exit(1);
+            }
+        };
+        thread::Builder::new()
+            .name("frame_thread".to_string())
+            .spawn(move || framed::serve(listener))
+            .unwrap();
+    }
+
     // start fetching data from server once GUI is initialized
     let handler2 = thread::Builder::new()
         .name("round_thread".to_string())
@@ -158,78 +269,155 @@ fn main() {
                 // no param!
                 None => panic!("No input provided for the micro flag!"),
             };
-            loop {
-                {
-                    let mut m_vec = MESSAGES.lock().unwrap();
-                    *m_vec = vec![];
-                    let mut p_backwards_msgs_m_vec = BACKWARDS_MESSAGES.lock().unwrap();
-                    *p_backwards_msgs_m_vec = vec![];
-
-                    // reset the 'messages received' buffer at the start of each round
-                    let p_backwards_m_vec = PROCESSED_BACKWARDS_MESSAGES.lock();
-                    let mut unwrapped_backwards = match p_backwards_m_vec {
-                        Err(e) => e.into_inner(),
-                        Ok(a) => a,
-                    };
-                    *unwrapped_backwards = vec![];
+            let batch: usize = match HASHMAP.get(&String::from("batch")) {
+                // param was passed
+                Some(x) => x.parse::<usize>().unwrap(),
+                // no param!
+                None => panic!("No input provided for the batch flag!"),
+            };
+            let pipeline: usize = match HASHMAP.get(&String::from("pipeline")) {
+                // param was passed
+                Some(x) => x.parse::<usize>().unwrap(),
+                // no param!
+                None => panic!("No input provided for the pipeline flag!"),
+            };
+            // the rounds waiting on the chain and the one being mixed are all held
+            // by the other servers at once
+            if pipeline >= MAX_IN_FLIGHT as usize {
+                eprintln!("--pipeline must be below {}", MAX_IN_FLIGHT);
+                process::exit(1);
+            }
+            let retain: usize = match HASHMAP.get(&String::from("retain")) {
+                // param was passed
+                Some(x) => x.parse::<usize>().unwrap(),
+                // no param!
+                None => panic!("No input provided for the retain flag!"),
+            };
+            let entries: Vec<SocketAddr> = match HASHMAP.get(&String::from("entries")) {
+                // param was passed
+                Some(x) => x
+                    .split(',')
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse::<SocketAddr>().expect("Bad entry server address"))
+                    .collect(),
+                // no param!
+                None => panic!("No input provided for the entries flag!"),
+            };
+            let timeout: u64 = match HASHMAP.get(&String::from("timeout")) {
+                // param was passed
+                Some(x) => x.parse::<u64>().unwrap(),
+                // no param!
+                None => panic!("No input provided for the timeout flag!"),
+            };
+            let timeout = time::Duration::from_millis(timeout);
+            configure_timeout(timeout);
+            // publish the chain's identity keys so clients can check theirs
+            let server_pks = match epoch::identity_pks() {
+                Ok(k) => k,
+                Err(e) => {
+                    eprintln!("Unable to read the chain's keys: {}", e);
+                    process::exit(1);
                 }
+            };
+            let period = time::Duration::from_millis(roundtime);
+            configure_chain(roundtime, server_pks);
+
+            // backward stage: collect each round's replies once the chain is done with it,
+            // while the forward stage below is already mixing the next round
+            let (handoff, in_flight) = bounded::<(u32, State, Instant)>(pipeline);
+            let _backward = thread::Builder::new()
+                .name("backward_thread".to_string())
+                .spawn(move || {
+                    for (rn, s, started) in in_flight.iter() {
+                        let wait = waiting_for_next(s, rn);
+                        let done = wait
+                            .and_then(move |s| cleanup(s, rn, retain))
+                            .or_else(move |e| {
+                                abort_round(rn, retain, e, "127.0.0.1".to_string(), 8081)
+                            });
+
+                        tokio::run(
+                            (done)
+                                .map_err(|e| eprintln!("Fetch Error: {}", e))
+                                .boxed()
+                                .compat(),
+                        );
+
+                        println!("ROUND TIME ELAPSED (ms): {}", started.elapsed().as_millis());
+                    }
+                })
+                .unwrap();
+
+            // clients need the keys of the first round's epoch before it opens
+            let first = *ROUND_NUM.lock().unwrap();
+            let keys_ready = refresh_epoch_keys(first, "127.0.0.1".to_string(), 8081);
+            tokio::run(
+                keys_ready
+                    .map_err(|e| {
+                        eprintln!("Unable to fetch the chain's epoch keys: {}", e);
+                        process::exit(1);
+                    })
+                    .boxed()
+                    .compat(),
+            );
+
+            let mut scheduler = Scheduler::new(period, batch);
+            // let subscribed clients know when the open round closes at the latest
+            announce_round(*ROUND_NUM.lock().unwrap(), unix_millis(SystemTime::now() + period));
+            loop {
                 // wait until round ends
-                thread::sleep(time::Duration::from_secs(roundtime));
+                let (rn, m_vec, trigger) = scheduler.close_round();
 
                 // start timing the round
                 let now = Instant::now();
+                let deadline = unix_millis(SystemTime::now() + period);
+                // only does any work as an epoch nears, the old keys stay
+                // published if the chain cannot be reached
+                let keys_ready = refresh_epoch_keys(rn + 1, "127.0.0.1".to_string(), 8081);
+                tokio::run(
+                    keys_ready
+                        .map_err(|e| eprintln!("Epoch Key Error: {}", e))
+                        .boxed()
+                        .compat(),
+                );
+                announce_round(rn + 1, deadline);
+                println!("Starting round {} ({:?}) with {} messages", rn, trigger, m_vec.len());
 
-                // acquire lock on MESSAGES
-                println!("Starting round!!");
-                let m_vec = MESSAGES.lock().unwrap();
-                println!("m_vec lock acquired!");
-
-                let shuffle = round_status_check(m_vec.to_vec(), "127.0.0.1".to_string(), 8081);
+                // add the messages held by the entry servers
+                let collect = collect_entries(rn, m_vec, deadline, entries.clone());
+                let shuffle = collect
+                    .and_then(move |v| round_status_check(v, rn));
                 // signal int_server to start round
                 let start_new_round =
                     shuffle.and_then(|(s, v)| start_round(s, v, "127.0.0.1".to_string(), 8081));
                 // begin sending messages in batches
                 let send_msgs = start_new_round
-                    .and_then(|(s, v)| send_m_vec(s, v, "127.0.0.1".to_string(), 8081));
+                    .and_then(move |(s, v)| send_m_vec(s, v, rn, "127.0.0.1".to_string(), 8081));
                 // signal end of round
-                let end_round =
-                    send_msgs.and_then(|(s, v)| end_round(s, v, "127.0.0.1".to_string(), 8081));
-                let wait = end_round.and_then(|(s, _)| waiting_for_next(s));
-                let almost_done_cleanup = wait.and_then(|s| cleanup(s));
-                //let block_until_replies_done = almost_done_cleanup.and_then(|_| block_on_replies());
-
-                tokio::run(
-                    (almost_done_cleanup)
-                        .map_err(|e| eprintln!("Fetch Error: {}", e))
-                        .boxed()
-                        .compat(),
-                );
-
-                let almost = block_on_replies();
-                let finally = almost.and_then(move |_| {
-                    async {
-                        // empty our message buffer for the next round
-                        // cleanup end round cvar
-                        let tuple = LOCAL_ROUND_ENDED.clone();
-                        let &(ref b, _) = &*tuple;
-                        let mut flag = b.lock().unwrap();
-                        *flag = false;
-                        // cleanup response handling
-                        let &(ref b, ref _cvar) = &*REQUEST_RESPONSE_BLOCK.clone();
-                        let mut flag = b.lock().unwrap();
-                        *(flag.get_mut()) = 0;
-                        Ok(())
-                    }
-                });
+                let end_round = send_msgs
+                    .and_then(move |s| end_round(s, rn, "127.0.0.1".to_string(), 8081));
+                let handoff = handoff.clone();
+                // give up on the round if the chain does not take it in time
+                let forwarded = within(rn, timeout, end_round)
+                    .and_then(move |s| {
+                        async move {
+                            // blocks while `pipeline` rounds are already waiting on the chain
+                            handoff.send((rn, s, now)).unwrap();
+                            Ok(())
+                        }
+                    })
+                    .or_else(move |e| {
+                        abort_round(rn, retain, e, "127.0.0.1".to_string(), 8081)
+                    });
 
                 tokio::run(
-                    (finally)
+                    (forwarded)
                         .map_err(|e| eprintln!("Fetch Error: {}", e))
                         .boxed()
                         .compat(),
                 );
 
-                println!("ROUND TIME ELAPSED (ms): {}", now.elapsed().as_millis());
+                scheduler.finished(now);
             }
         })
         .unwrap();
@@ -1,24 +1,176 @@
 use crate::HASHMAP;
-use sharedlib::keys::get_keypair;
-use sharedlib::keys::{get, PartyType};
+use sharedlib::epoch::{self, epoch_of, Announced, SharedKeys};
+use sharedlib::error::{Error, Result, RoundError};
 use sharedlib::laplace::{Laplace, TransformedDistribution};
+use sharedlib::noise::{self, SharedPool};
+use sharedlib::batch::{self, Batch};
+use sharedlib::frame::Kind;
+use sharedlib::message;
 use sharedlib::onion;
+use sharedlib::transfer::stream_chunks;
 use sharedlib::util::{backward, forward, Settings, State};
-use std::cmp::min;
+use sharedlib::wire::{self, Hop};
+use std::collections::HashMap;
 use std::io;
-use std::net::{IpAddr, SocketAddr};
-use tarpc::{client, context};
-use tarpc_bincode_transport::connect;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tarpc::context;
+use tarpc::futures::future;
+use tarpc::futures::FutureExt;
+use tarpc::futures::stream::{self, StreamExt};
 
-// we want to make sure we connect to the intermediate server in our rounds
+use sharedlib::conn::{socket_addr, within, Peers, ABORT_RETRY_LIMIT};
+use sharedlib::entry_rpc;
 use sharedlib::head_rpc::{
-    BACKWARDS_MESSAGES, LOCAL_ROUND_ENDED, PROCESSED_BACKWARDS_MESSAGES, REMOTE_ROUND_ENDED,
-    REQUEST_RESPONSE_BLOCK, ROUND_NUM,
+    self, publish_epoch_keys, publish_failure, publish_results, BACKWARDS_MESSAGES,
+    REMOTE_ROUND_ENDED,
 };
-use sharedlib::int_rpc::new_stub;
-use std::time::Instant;
+use sharedlib::int_rpc;
+use sharedlib::pipeline::phase_timeout;
+use std::time::{Duration, Instant};
+use tokio::prelude::Async;
 use tokio_threadpool::blocking;
 
+// entry servers asked at once
+const ENTRY_WINDOW: usize = 16;
+// how long an unreachable entry server may hold up closing a round or
+// getting its replies
+const ENTRY_RETRY_LIMIT: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    // noise onions for the rest of the chain, made between rounds
+    pub static ref NOISE_POOL: SharedPool = SharedPool::new();
+    // where each entry server's messages sit in a round, per round in flight
+    static ref ENTRY_BATCHES: Mutex<HashMap<u32, Vec<EntryBatch>>> = Mutex::new(HashMap::new());
+    // connections kept open to the intermediate server and the entry servers
+    static ref NEXT_SERVER: Peers<int_rpc::Client> = Peers::new();
+    static ref ENTRY_PEERS: Peers<entry_rpc::Client> = Peers::new();
+    // our onion keys, and the signed keys of the whole chain by epoch
+    static ref EPOCH_KEYS: SharedKeys = SharedKeys::new();
+    static ref CHAIN_KEYS: Mutex<Announced> = Mutex::new(Announced::new());
+}
+
+fn server_id() -> usize {
+    match HASHMAP.get(&String::from("server_id")) {
+        // param was passed
+        Some(x) => x.parse::<usize>().unwrap(),
+        // no param!
+        None => panic!("No input provided for the server_id flag!"),
+    }
+}
+
+/// Make sure the chain's keys for the epoch of `open_round` and the one
+/// after it are known, so clients have them before either starts, and
+/// hand them out with `round_info`.
+pub async fn refresh_epoch_keys(open_round: u32, server_addr: String, port: u16) -> Result<()> {
+    let s_addr = socket_addr(&server_addr, port)?;
+    let identity_pks = epoch::identity_pks()?;
+    let first = epoch_of(open_round);
+    for e in first..first + 2 {
+        if CHAIN_KEYS.lock().unwrap().get(e).is_some() {
+            continue;
+        }
+        let own = EPOCH_KEYS.with(server_id(), |k| k.announce(e))?;
+        let rest = await!(NEXT_SERVER.call(s_addr, int_rpc::dial, |mut client| {
+            async move { await!(client.EpochKeys(context::current(), e)) }
+        }))?;
+        let mut keys = vec![own];
+        keys.extend(rest);
+        // a server that lost its keys or answers for another is caught here
+        epoch::chain_pks(&keys, &identity_pks, 0, e)?;
+        CHAIN_KEYS.lock().unwrap().insert(e, keys);
+    }
+    publish_epoch_keys(CHAIN_KEYS.lock().unwrap().since(first));
+    Ok(())
+}
+
+/// The messages an entry server handed over for a round.
+#[derive(Clone, Debug)]
+struct EntryBatch {
+    addr: SocketAddr,
+    offset: usize,
+}
+
+/// Close `round` on every entry server and add their messages after our
+/// own. An entry server that cannot be reached sits this round out, which
+/// only affects its own clients.
+pub async fn collect_entries(
+    round: u32,
+    mut m_vec: Vec<onion::Message>,
+    deadline: u64,
+    entries: Vec<SocketAddr>,
+) -> Result<Vec<onion::Message>> {
+    let now = Instant::now();
+    let collected: Vec<(SocketAddr, Result<Vec<onion::Message>>)> =
+        await!(stream::iter(entries)
+            .map(|addr| {
+                async move {
+                    let res = await!(close_entry_round(addr, round, deadline));
+                    (addr, res)
+                }
+            })
+            .buffer_unordered(ENTRY_WINDOW)
+            .collect());
+
+    let mut batches = vec![];
+    for (addr, res) in collected {
+        match res {
+            Ok(msgs) => {
+                batches.push(EntryBatch {
+                    addr,
+                    offset: m_vec.len(),
+                });
+                m_vec.extend(msgs);
+            }
+            Err(e) => eprintln!("Entry server {} left out of round {}: {}", addr, round, e),
+        }
+    }
+    ENTRY_BATCHES.lock().unwrap().insert(round, batches);
+    println!(
+        "ENTRY COLLECTION TIME ELAPSED (ms): {}",
+        now.elapsed().as_millis()
+    );
+
+    Ok(m_vec)
+}
+
+async fn close_entry_round(
+    addr: SocketAddr,
+    round: u32,
+    deadline: u64,
+) -> Result<Vec<onion::Message>> {
+    let keys = head_rpc::epoch_keys();
+    let msgs = await!(ENTRY_PEERS.call_within(addr, ENTRY_RETRY_LIMIT, entry_rpc::dial, |mut c| {
+        let keys = keys.clone();
+        async move { await!(c.CloseRound(context::current(), round, deadline, keys)) }
+    }))?;
+    // entry servers check messages on the way in too, but a faulty one
+    // should only cost its own clients the round
+    wire::check_messages(&msgs, Hop::head(sharedlib::NUM_SERVERS).onion_size())?;
+    Ok(msgs)
+}
+
+async fn deliver_entry_replies(
+    addr: SocketAddr,
+    round: u32,
+    replies: Vec<onion::Message>,
+) -> Result<()> {
+    let delivered = await!(ENTRY_PEERS.call_within(
+        addr,
+        ENTRY_RETRY_LIMIT,
+        entry_rpc::dial,
+        |mut client| {
+            let replies = replies.clone();
+            async move { await!(client.DeliverReplies(context::current(), round, replies)) }
+        },
+    ));
+    match delivered {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Error::Round(round, RoundError::Rejected)),
+        Err(e) => Err(Error::Transport(e)),
+    }
+}
+
 /*
  * This function is used to periodically end a round,
  * flush the messages to the next server in the chain,
@@ -26,9 +178,8 @@ use tokio_threadpool::blocking;
  */
 pub async fn round_status_check(
     m_vec: Vec<onion::Message>,
-    _server_addr: String,
-    _port: u16,
-) -> io::Result<(State, Vec<onion::Message>)> {
+    round: u32,
+) -> Result<(State, Batch)> {
     //println!("round_status_check");
 
     let micro: f64 = match HASHMAP.get(&String::from("micro")) {
@@ -48,41 +199,36 @@ pub async fn round_status_check(
     // permute the messages *before* proceeding further
     let n = Laplace::new(scale, micro);
     let transformed_noise = TransformedDistribution::new(n, |x| u32::max(0, f64::ceil(x) as u32));
-    // read in the next two server pub keys
-    let mut key_vec = vec![];
+    // the keys the next two servers announced for this round's epoch
+    let e = epoch_of(round);
+    let announced = CHAIN_KEYS
+        .lock()
+        .unwrap()
+        .get(e)
+        .ok_or(Error::Crypto("no keys announced for the round's epoch"))?;
+    let key_vec = epoch::chain_pks(&announced, &epoch::identity_pks()?, 1, e)?;
 
-    let k1 = match get(PartyType::Server.with_id(1)) {
-        Ok(k) => k,
-        Err(e) => panic!("Unable to read server public key 1: err: {}", e),
-    };
-    let k2 = match get(PartyType::Server.with_id(2)) {
-        Ok(k) => k,
-        Err(e) => panic!("Unable to read server public key 2: err: {}", e),
-    };
-    key_vec.push(k1);
-    key_vec.push(k2);
-
-    let server_id = match HASHMAP.get(&String::from("server_id")) {
-        // param was passed
-        Some(x) => x.parse::<usize>().unwrap(),
-        // no param!
-        None => panic!("No input provided for the server_id flag!"),
-    };
-
-    let (server_priv_key, _) = match get_keypair(PartyType::Server.with_id(server_id)) {
-        Ok(kp) => kp,
-        Err(e) => panic!("Unable to read server keys!!! err: {}", e),
-    };
+    // no round older than the previous epoch is still in flight
+    EPOCH_KEYS.with(server_id(), |k| {
+        k.retire(round);
+        Ok(())
+    })?;
+    CHAIN_KEYS.lock().unwrap().retire(round);
+    let server_priv_key = EPOCH_KEYS.with(server_id(), |k| k.sk(round))?;
 
     //println!("shuffling m_vec...");
+    let pool = NOISE_POOL.get_or_start(&key_vec, noise::target_for(micro, scale));
     let settings = Settings {
         other_pks: key_vec,
         sk: server_priv_key,
         noise: transformed_noise,
+        pool: Some(pool),
     };
 
     let now = Instant::now();
-    let (state, processed_m_vec): (State, Vec<onion::Message>) = forward(m_vec, &settings);
+    // copy the submissions into one buffer, our layer is peeled off in place
+    let input = Batch::from_messages(message::onion_size(sharedlib::NUM_SERVERS), &m_vec);
+    let (state, processed_m_vec): (State, Batch) = forward(input, &settings)?;
     println!("FORWARD TIME ELAPSED (ms): {}", now.elapsed().as_millis());
 
     Ok((state, processed_m_vec))
@@ -90,151 +236,162 @@ pub async fn round_status_check(
 
 pub async fn start_round(
     s: State,
-    m_vec: Vec<onion::Message>,
+    m_vec: Batch,
     server_addr: String,
     port: u16,
-) -> io::Result<(State, Vec<onion::Message>)> {
+) -> Result<(State, Batch)> {
     //println!("start_round");
-    let s_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
-    let transport = await!(connect(&s_addr)).unwrap();
-    let _client = await!(new_stub(client::Config::default(), transport)).unwrap();
-    // figure out if we need this call at all
-    //await!(client.StartNewRound(context::current())).unwrap();
+    let s_addr = socket_addr(&server_addr, port)?;
+    // make sure the intermediate server is up before we start streaming to it
+    await!(NEXT_SERVER.call(s_addr, int_rpc::dial, |_| future::ready(Ok(()))))?;
     Ok((s, m_vec))
 }
 
 pub async fn send_m_vec(
     s: State,
-    m_vec: Vec<onion::Message>,
+    m_vec: Batch,
+    round: u32,
     server_addr: String,
     port: u16,
-) -> io::Result<(State, Vec<onion::Message>)> {
+) -> Result<State> {
     //println!("send_m_vec");
-    let s_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
-    let transport = await!(connect(&s_addr)).unwrap();
-    let mut client = await!(new_stub(client::Config::default(), transport)).unwrap();
-    // compute how many chunks we can make
+    let s_addr = socket_addr(&server_addr, port)?;
+    // stream the m_vec in chunks, the intermediate server decrypts each
+    // one as it arrives so it is mostly done by the time we end the round
     let now = Instant::now();
-    let mut m_vec_clone = m_vec.clone();
-    while m_vec_clone.len() > 0 {
-        let chunk_size = min(1024, m_vec_clone.len());
-        let msgs: Vec<onion::Message> = m_vec_clone.drain(..chunk_size).collect();
-        match await!(client.SendMessages(context::current(), msgs.clone(), true)) {
-            Err(e) => {
-                println!("err: {}, retrying...", e);
-                // if we die again, that's it
-                await!(client.SendMessages(context::current(), msgs, true)).unwrap();
-                ()
-            }
-            Ok(_o) => (),
-        }
-    }
+    await!(stream_chunks(round, Kind::Forward, m_vec, |frame| {
+        NEXT_SERVER.call(s_addr, int_rpc::dial, move |mut client| {
+            let frame = Arc::clone(&frame);
+            async move { await!(client.SendMessages(context::current(), frame)) }
+        })
+    }))?;
 
     println!(
         "NETWORK FORWARD TIME ELAPSED (ms): {}",
         now.elapsed().as_millis()
     );
 
-    Ok((s, m_vec))
+    Ok(s)
 }
 
-pub async fn end_round(
-    s: State,
-    m_vec: Vec<onion::Message>,
-    server_addr: String,
-    port: u16,
-) -> io::Result<(State, Vec<onion::Message>)> {
+pub async fn end_round(s: State, round: u32, server_addr: String, port: u16) -> Result<State> {
     //println!("end_round");
 
-    let s_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
-    let transport = await!(connect(&s_addr)).unwrap();
-    let mut client = await!(new_stub(client::Config::default(), transport)).unwrap();
-    await!(client.EndRound(context::current())).unwrap();
-    Ok((s, m_vec))
+    let s_addr = socket_addr(&server_addr, port)?;
+    await!(NEXT_SERVER.call(s_addr, int_rpc::dial, |mut client| {
+        async move { await!(client.EndRound(context::current(), round)) }
+    }))?;
+    Ok(s)
 }
 
-pub async fn waiting_for_next(s: State) -> io::Result<State> {
+pub async fn waiting_for_next(s: State, round: u32) -> Result<State> {
     // after we end the round, we will begin receiving msg's from the int_server
     //println!("waiting for intermediate server to finish!");
 
-    blocking(|| {
-        // wait int_server signals it is done sending us messages
-        let &(ref b, ref cvar) = &*REMOTE_ROUND_ENDED.clone();
-        let mut flag = b.lock().unwrap();
-        while !*flag {
-            flag = cvar.wait(flag).unwrap();
+    let ended = blocking(|| {
+        // wait int_server signals it is done sending us messages, or gives up
+        REMOTE_ROUND_ENDED.wait(round, phase_timeout())
+    });
+    match ended {
+        Ok(Async::Ready(ended)) => ended?,
+        // no thread free to block on, or the threadpool is shutting down
+        _ => {
+            return Err(Error::Transport(io::Error::new(
+                io::ErrorKind::Other,
+                "unable to block",
+            )))
         }
-    })
-    .map_err(|_| panic!("the threadpool shut down"))
-    .unwrap();
+    }
 
     //println!("round ended by intermediate server!");
     Ok(s)
 }
 
-pub async fn cleanup(s: State) -> io::Result<()> {
-    /*blocking(|| {*/
+pub async fn cleanup(s: State, round: u32, retain: usize) -> Result<()> {
     // unshuffle the permutations
     let now = Instant::now();
-    let mut _returning_m_vec = vec![];
-    {
-        let m_vec = BACKWARDS_MESSAGES.lock();
-        match m_vec {
-            Err(e) => _returning_m_vec = backward(s, e.into_inner().clone()),
-            Ok(v) => _returning_m_vec = backward(s, v.clone()),
-        }
-    }
+    let returning = backward(s, BACKWARDS_MESSAGES.take(round))?;
     println!("BACKWARDS TIME ELAPSED (ms): {}", now.elapsed().as_millis());
 
-    let p_backwards_m_vec = PROCESSED_BACKWARDS_MESSAGES.lock();
-    let mut unwrapped_p_backwards_m_vec = match p_backwards_m_vec {
-        Err(e) => e.into_inner(),
-        Ok(v) => v,
-    };
-    unwrapped_p_backwards_m_vec.extend(_returning_m_vec);
-
-    // increment round count
-    let mut rn = ROUND_NUM.lock().unwrap();
-    *rn += 1;
-    // reset cond var flag for next round
-    {
-        let &(ref b, _) = &*REMOTE_ROUND_ENDED.clone();
-        let mut flag = b.lock().unwrap();
-        *flag = false;
+    let mut replies = returning.to_messages();
+    batch::POOL.give(returning);
+
+    // give the entry servers back the replies for their messages, they stay
+    // in ENTRY_BATCHES until then so an abort still reaches them
+    let batches = ENTRY_BATCHES
+        .lock()
+        .unwrap()
+        .get(&round)
+        .cloned()
+        .unwrap_or_default();
+    // entry batches follow each other, so each one runs to the start of the next
+    let mut theirs = vec![];
+    for b in batches.iter().rev() {
+        theirs.push((b.addr, replies.split_off(b.offset)));
     }
-    //("Round number incremented, now: {}", *rn);
 
-    // send all the messages back!
-    let tuple = LOCAL_ROUND_ENDED.clone();
-    let &(ref b, ref cvar) = &*tuple;
-    let mut flag = b.lock().unwrap();
-    *flag = true;
-    cvar.notify_all();
+    // hand the replies to anyone holding a ticket for this round
+    publish_results(round, replies, retain);
 
-    // we need to actually wait for all messages to be flushed!
-    //println!("waiting to finish replying to all msgs");
-    /*})
-    .map_err(|_| panic!("the threadpool shut down"))
-    .unwrap();*/
+    let deliveries = stream::iter(theirs)
+        .map(move |(addr, r)| {
+            async move {
+                let res = await!(deliver_entry_replies(addr, round, r));
+                (addr, res)
+            }
+        })
+        .buffer_unordered(ENTRY_WINDOW)
+        .collect::<Vec<(SocketAddr, Result<()>)>>();
+    let delivered = await!(within(round, phase_timeout(), deliveries.map(Ok)))?;
 
+    // aborting the round tells the entry servers we could not reach, the
+    // others keep the replies they already have
+    let mut failed = None;
+    for (addr, res) in delivered {
+        if let Err(e) = res {
+            eprintln!("Could not deliver round {} to entry server {}: {}", round, addr, e);
+            failed = Some(e);
+        }
+    }
+    if let Some(e) = failed {
+        return Err(e);
+    }
+    ENTRY_BATCHES.lock().unwrap().remove(&round);
     Ok(())
 }
 
-pub async fn block_on_replies() -> io::Result<()> {
-    let p_backwards_m_vec = PROCESSED_BACKWARDS_MESSAGES.lock();
-    let unwrapped_p_backwards_m_vec = match p_backwards_m_vec {
-        Err(e) => e.into_inner(),
-        Ok(v) => v,
-    };
-    blocking(|| {
-        let &(ref b, ref cvar) = &*REQUEST_RESPONSE_BLOCK.clone();
-        let mut flag = b.lock().unwrap();
-        println!("{:?}", *flag);
-        while *(flag.get_mut()) != unwrapped_p_backwards_m_vec.len() {
-            flag = cvar.wait(flag).unwrap();
+/// Give up on `round`: drop what we hold of it, tell its clients that it
+/// failed, and have the rest of the chain and the entry servers drop it too.
+pub async fn abort_round(
+    round: u32,
+    retain: usize,
+    err: Error,
+    server_addr: String,
+    port: u16,
+) -> Result<()> {
+    eprintln!("Aborting round {}: {}", round, err);
+    batch::POOL.give(BACKWARDS_MESSAGES.discard(round));
+    let batches = ENTRY_BATCHES
+        .lock()
+        .unwrap()
+        .remove(&round)
+        .unwrap_or_default();
+    publish_failure(round, retain);
+
+    let s_addr = socket_addr(&server_addr, port)?;
+    let next = NEXT_SERVER.call_within(s_addr, ABORT_RETRY_LIMIT, int_rpc::dial, |mut client| {
+        async move { await!(client.AbortRound(context::current(), round)) }
+    });
+    if let Err(e) = await!(next) {
+        eprintln!("Could not abort round {} on {}: {}", round, s_addr, e);
+    }
+    for b in batches {
+        let entry = ENTRY_PEERS.call_within(b.addr, ABORT_RETRY_LIMIT, entry_rpc::dial, |mut c| {
+            async move { await!(c.AbortRound(context::current(), round)) }
+        });
+        if let Err(e) = await!(entry) {
+            eprintln!("Could not abort round {} on entry server {}: {}", round, b.addr, e);
         }
-    })
-    .map_err(|_| panic!("the threadpool shut down"))
-    .unwrap();
+    }
     Ok(())
 }
@@ -0,0 +1,156 @@
+use sharedlib::head_rpc::{MESSAGES, MESSAGE_ARRIVED, ROUND_NUM};
+use sharedlib::onion;
+use std::mem;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Why a round was closed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Trigger {
+    Batch,
+    Deadline,
+}
+
+/// Decides when the open round closes: as soon as `batch` messages have
+/// arrived, or once `period` has passed since the last round closed.
+pub struct Scheduler {
+    period: Duration,
+    // 0 closes rounds on the deadline alone
+    batch: usize,
+    deadline: Instant,
+    overruns: u64,
+    // the open round's messages, signalled as they arrive, and its number
+    messages: &'static Mutex<Vec<onion::Message>>,
+    arrived: &'static Condvar,
+    round_num: &'static Mutex<u32>,
+}
+
+impl Scheduler {
+    pub fn new(period: Duration, batch: usize) -> Scheduler {
+        Scheduler::with_state(period, batch, &MESSAGES, &MESSAGE_ARRIVED, &ROUND_NUM)
+    }
+
+    // like `new`, for rounds held somewhere other than the head server's globals
+    fn with_state(
+        period: Duration,
+        batch: usize,
+        messages: &'static Mutex<Vec<onion::Message>>,
+        arrived: &'static Condvar,
+        round_num: &'static Mutex<u32>,
+    ) -> Scheduler {
+        Scheduler {
+            period,
+            batch,
+            deadline: Instant::now() + period,
+            overruns: 0,
+            messages,
+            arrived,
+            round_num,
+        }
+    }
+
+    /// Block until the open round should close, then close it: take its
+    /// messages and open the next round.
+    pub fn close_round(&mut self) -> (u32, Vec<onion::Message>, Trigger) {
+        let mut m_vec = self.messages.lock().unwrap();
+        let mut now = Instant::now();
+        while (self.batch == 0 || m_vec.len() < self.batch) && now < self.deadline {
+            let (m, _) = self
+                .arrived
+                .wait_timeout(m_vec, self.deadline - now)
+                .unwrap();
+            m_vec = m;
+            now = Instant::now();
+        }
+        let trigger = if now < self.deadline {
+            Trigger::Batch
+        } else {
+            Trigger::Deadline
+        };
+
+        let mut rn = self.round_num.lock().unwrap();
+        let closed = *rn;
+        *rn += 1;
+        self.deadline = now + self.period;
+        (closed, mem::replace(&mut *m_vec, vec![]), trigger)
+    }
+
+    /// Record that the round closed at `started` has finished processing.
+    /// If that took us past the next deadline, the next round is late and
+    /// the overrun is logged.
+    pub fn finished(&mut self, started: Instant) {
+        let now = Instant::now();
+        if now <= self.deadline {
+            return;
+        }
+        self.overruns += 1;
+        let late = now - self.deadline;
+        println!(
+            "ROUND OVERRUN (ms): {}, took {} ms against a {} ms period, {} overruns so far",
+            late.as_millis(),
+            (now - started).as_millis(),
+            self.period.as_millis(),
+            self.overruns
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    // a scheduler over state of its own, so tests can run side by side
+    fn scheduler(period: Duration, batch: usize) -> Scheduler {
+        Scheduler::with_state(
+            period,
+            batch,
+            Box::leak(Box::new(Mutex::new(vec![]))),
+            Box::leak(Box::new(Condvar::new())),
+            Box::leak(Box::new(Mutex::new(5))),
+        )
+    }
+
+    #[test]
+    fn closes_on_a_full_batch() {
+        let mut scheduler = scheduler(Duration::from_secs(60), 2);
+        let (messages, arrived) = (scheduler.messages, scheduler.arrived);
+
+        let sender = thread::spawn(move || {
+            for m in 0..3 {
+                messages.lock().unwrap().push(vec![m]);
+                arrived.notify_one();
+            }
+        });
+        let started = Instant::now();
+        let (round, messages, trigger) = scheduler.close_round();
+        sender.join().unwrap();
+        assert_eq!(trigger, Trigger::Batch);
+        assert_eq!(round, 5);
+        assert!(messages.len() >= 2);
+        assert!(started.elapsed() < Duration::from_secs(60));
+        assert_eq!(*scheduler.round_num.lock().unwrap(), 6);
+
+        scheduler.finished(started);
+        assert_eq!(scheduler.overruns, 0);
+    }
+
+    #[test]
+    fn closes_when_the_period_ends() {
+        let period = Duration::from_millis(50);
+        // before the scheduler, which sets its deadline as it is made
+        let started = Instant::now();
+        let mut scheduler = scheduler(period, 2);
+
+        scheduler.messages.lock().unwrap().push(vec![0]);
+        let (_, messages, trigger) = scheduler.close_round();
+        assert_eq!(trigger, Trigger::Deadline);
+        assert_eq!(messages, vec![vec![0]]);
+        assert!(started.elapsed() >= period);
+
+        // taking longer than a period to process the round makes the next one late
+        thread::sleep(period * 2);
+        scheduler.finished(started);
+        assert_eq!(scheduler.overruns, 1);
+    }
+}
@@ -20,12 +20,18 @@ use clap::{App, Arg};
 use tarpc::server::Handler;
 use tarpc_bincode_transport::listen;
 
+use sharedlib::conn::socket_addr;
 use sharedlib::int_rpc::serve;
 use sharedlib::int_rpc::IntermediateServer;
+use sharedlib::keys::{self, Encrypted, FileStore, PartyType};
+use sharedlib::onion::{configure_suite, CipherSuite};
+use sharedlib::pipeline::configure_timeout;
 
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::Ipv4Addr;
+use std::process;
 use std::thread;
+use std::time::Duration;
 use tarpc::server;
 use tokio::runtime::Builder;
 
@@ -87,6 +93,22 @@ lazy_static! {
                             .long("variance")
                             .help("Specifies the variance of the noise distribution, for differential privacy")
                             .takes_value(true))
+                        .arg(Arg::with_name("timeout")
+                            .long("timeout")
+                            .help("Specifies how many milliseconds each phase of a round may take before the round is aborted")
+                            .takes_value(true))
+                        .arg(Arg::with_name("suite")
+                            .long("suite")
+                            .help("Specifies the cipher suite of the chain, aes256gcm, chacha20poly1305 or (built with the hybrid feature) hybrid-mlkem768, the same on every server")
+                            .takes_value(true))
+                        .arg(Arg::with_name("keys")
+                            .long("keys")
+                            .help("Specifies the directory holding the key files")
+                            .takes_value(true))
+                        .arg(Arg::with_name("passphrase_file")
+                            .long("passphrase_file")
+                            .help("Specifies a file holding the passphrase of the key files, asked for on the terminal if unset")
+                            .takes_value(true))
                         .get_matches();
 
         let server_uid = String::from(matches.value_of("server_id").unwrap_or("1").clone());
@@ -98,8 +120,17 @@ lazy_static! {
         let prev_server_port = String::from(matches.value_of("prevport").unwrap_or("8080").clone());
         let micro = String::from(matches.value_of("micro").unwrap_or("10").clone());
         let b = String::from(matches.value_of("variance").unwrap_or("0").clone());
+        let timeout = String::from(matches.value_of("timeout").unwrap_or("30000").clone());
+        let suite = String::from(matches.value_of("suite").unwrap_or("aes256gcm").clone());
+
+        let keys_dir = String::from(matches.value_of("keys").unwrap_or("./keys").clone());
+        let passphrase_file = String::from(matches.value_of("passphrase_file").unwrap_or("").clone());
 
+        m.insert(String::from("keys"), keys_dir);
+        m.insert(String::from("passphrase_file"), passphrase_file);
         m.insert(String::from("variance"), b);
+        m.insert(String::from("timeout"), timeout);
+        m.insert(String::from("suite"), suite);
         m.insert(String::from("micro"), micro);
         m.insert(String::from("server_id"), server_uid);
         m.insert(String::from("server_ip"), server_ip);
@@ -113,7 +144,7 @@ lazy_static! {
 }
 
 async fn run_service(server_addr: &str, port: u16) -> io::Result<()> {
-    let parsed_server_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
+    let parsed_server_addr = socket_addr(server_addr, port)?;
     let transport = listen(&parsed_server_addr)?;
     let _addr = transport.local_addr();
 
@@ -124,6 +155,22 @@ async fn run_service(server_addr: &str, port: u16) -> io::Result<()> {
         None => panic!("No input provided for the server_id flag!"),
     };
 
+    let timeout: u64 = match HASHMAP.get(&String::from("timeout")) {
+        // param was passed
+        Some(x) => x.parse::<u64>().unwrap(),
+        // no param!
+        None => panic!("No input provided for the timeout flag!"),
+    };
+    configure_timeout(Duration::from_millis(timeout));
+
+    let suite = match HASHMAP.get(&String::from("suite")) {
+        // param was passed
+        Some(x) => CipherSuite::from_name(x).expect("Unknown cipher suite"),
+        // no param!
+        None => panic!("No input provided for the suite flag!"),
+    };
+    configure_suite(suite);
+
     let nextaddr: Ipv4Addr = HASHMAP
         .get(&String::from("next_server_ip"))
         .unwrap()
@@ -194,6 +241,25 @@ fn main() {
         .unwrap();
     tarpc::init(runtime.executor().compat());
 
+    // private keys are sealed on disk, make sure ours opens before we start,
+    // it is kept in memory from then on
+    let server_id = HASHMAP
+        .get(&String::from("server_id"))
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    let keys_dir = HASHMAP.get(&String::from("keys")).unwrap();
+    let passphrase_file = HASHMAP.get(&String::from("passphrase_file")).unwrap();
+    let unlocked = keys::read_passphrase(passphrase_file).and_then(|p| {
+        let own = PartyType::Server.with_id(server_id);
+        keys::configure_store(Encrypted::new(FileStore::new(keys_dir), p), Some(own.clone()))?;
+        keys::get_identity_private(own)
+    });
+    if let Err(e) = unlocked {
+        eprintln!("Unable to open our identity key: {}", e);
+        process::exit(1);
+    }
+
     let ip = HASHMAP.get(&String::from("server_ip")).unwrap();
     let port = HASHMAP
         .get(&String::from("server_port"))
@@ -0,0 +1,379 @@
+use crate::rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::{max, min};
+use std::ptr;
+use std::sync::Mutex;
+
+/// Buffers kept around between rounds.
+const POOL_LIMIT: usize = 8;
+
+lazy_static! {
+    /// Buffers freed by earlier rounds, reused by later ones.
+    pub static ref POOL: BatchPool = BatchPool::new();
+}
+
+/// A round's messages stored back to back in one buffer. Every message has
+/// the same length, the stride, so message `i` is `data[i * stride..][..stride]`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Batch {
+    stride: usize,
+    data: Vec<u8>,
+}
+
+impl Batch {
+    pub fn new(stride: usize) -> Batch {
+        Batch {
+            stride,
+            data: Vec::new(),
+        }
+    }
+
+    /// An empty batch backed by a buffer from the pool.
+    pub fn with_capacity(stride: usize, capacity: usize) -> Batch {
+        POOL.take(stride, capacity)
+    }
+
+    /// Copy messages into a batch. A message of the wrong length leaves its
+    /// slot zeroed, so it fails to decrypt like any other bad message.
+    pub fn from_messages(stride: usize, messages: &[Vec<u8>]) -> Batch {
+        let mut b = Batch::with_capacity(stride, messages.len());
+        for m in messages {
+            if m.len() == stride {
+                b.push(m);
+            } else {
+                b.push_zeroed();
+            }
+        }
+        b
+    }
+
+    /// Take over a buffer of messages of `stride` bytes each, back to back.
+    pub fn from_bytes(stride: usize, data: Vec<u8>) -> Batch {
+        assert!(
+            if stride == 0 { data.is_empty() } else { data.len() % stride == 0 },
+            "buffer is not a whole number of messages"
+        );
+        Batch { stride, data }
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn len(&self) -> usize {
+        if self.stride == 0 {
+            0
+        } else {
+            self.data.len() / self.stride
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn push(&mut self, m: &[u8]) {
+        assert_eq!(m.len(), self.stride, "message does not fit the stride");
+        self.data.extend_from_slice(m);
+    }
+
+    pub fn push_zeroed(&mut self) {
+        let end = self.data.len() + self.stride;
+        self.data.resize(end, 0);
+    }
+
+    pub fn get(&self, i: usize) -> &[u8] {
+        &self.data[i * self.stride..(i + 1) * self.stride]
+    }
+
+    pub fn get_mut(&mut self, i: usize) -> &mut [u8] {
+        &mut self.data[i * self.stride..(i + 1) * self.stride]
+    }
+
+    pub fn iter(&self) -> std::slice::Chunks<u8> {
+        self.data.chunks(self.stride)
+    }
+
+    pub fn par_iter(&self) -> rayon::slice::Chunks<u8> {
+        self.data.par_chunks(self.stride)
+    }
+
+    pub fn par_iter_mut(&mut self) -> rayon::slice::ChunksMut<u8> {
+        self.data.par_chunks_mut(self.stride)
+    }
+
+    /// The whole buffer, `len() * stride()` bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Swap messages `i` and `j`.
+    pub fn swap(&mut self, i: usize, j: usize) {
+        if i == j {
+            return;
+        }
+        let (lo, hi) = (min(i, j), max(i, j));
+        let (front, back) = self.data.split_at_mut(hi * self.stride);
+        front[lo * self.stride..(lo + 1) * self.stride].swap_with_slice(&mut back[..self.stride]);
+    }
+
+    /// Grow or shrink to `n` messages, new ones zeroed.
+    pub fn resize(&mut self, n: usize) {
+        self.data.resize(n * self.stride, 0);
+    }
+
+    /// Swap every pair of messages in `pairs` in parallel. No message may
+    /// be in more than one pair.
+    pub fn swap_disjoint(&mut self, pairs: &[(usize, usize)]) {
+        let n = self.len();
+        let mut seen = vec![false; n];
+        for &(i, j) in pairs {
+            assert!(i != j && !seen[i] && !seen[j], "overlapping swaps");
+            seen[i] = true;
+            seen[j] = true;
+        }
+
+        let stride = self.stride;
+        let base = Shared(self.data.as_mut_ptr());
+        pairs.par_iter().for_each(|&(i, j)| {
+            // every message is in at most one pair, checked above
+            unsafe {
+                ptr::swap_nonoverlapping(base.0.add(i * stride), base.0.add(j * stride), stride);
+            }
+        });
+    }
+
+    /// Keep only the first `n` messages.
+    pub fn truncate(&mut self, n: usize) {
+        self.data.truncate(n * self.stride);
+    }
+
+    /// Move another batch's messages onto the end of this one.
+    pub fn append(&mut self, other: Batch) {
+        if self.is_empty() {
+            self.stride = other.stride;
+        }
+        assert_eq!(self.stride, other.stride, "appending a batch of another stride");
+        self.data.extend_from_slice(&other.data);
+        POOL.give(other);
+    }
+
+    /// Split into batches of at most `size` messages each.
+    pub fn chunks(&self, size: usize) -> Vec<Batch> {
+        if self.is_empty() {
+            return vec![];
+        }
+        self.data
+            .chunks(size * self.stride)
+            .map(|c| {
+                let mut b = Batch::new(self.stride);
+                b.data.extend_from_slice(c);
+                b
+            })
+            .collect()
+    }
+
+    /// Keep only bytes `skip..skip + stride` of each message, in place. Used
+    /// after decrypting, when each message loses its header and tag.
+    pub fn shrink_stride(&mut self, skip: usize, stride: usize) {
+        assert!(skip + stride <= self.stride);
+        let n = self.len();
+        for i in 0..n {
+            let from = i * self.stride + skip;
+            self.data.copy_within(from..from + stride, i * stride);
+        }
+        self.data.truncate(n * stride);
+        self.stride = stride;
+    }
+
+    /// Grow each message to `stride` bytes, in place, leaving the new space
+    /// zeroed at the end of each message. Used before encrypting, to make
+    /// room for the tags.
+    pub fn grow_stride(&mut self, stride: usize) {
+        assert!(stride >= self.stride);
+        let n = self.len();
+        let old = self.stride;
+        self.data.resize(n * stride, 0);
+        // back to front, so no message is overwritten before it has moved
+        for i in (0..n).rev() {
+            self.data.copy_within(i * old..(i + 1) * old, i * stride);
+            for b in &mut self.data[i * stride + old..(i + 1) * stride] {
+                *b = 0;
+            }
+        }
+        self.stride = stride;
+    }
+
+    /// Copy the messages out, for callers that need them one by one.
+    pub fn to_messages(&self) -> Vec<Vec<u8>> {
+        self.par_iter().map(|m| m.to_vec()).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+    }
+}
+
+// a batch's buffer, written by several threads at disjoint positions
+struct Shared(*mut u8);
+
+unsafe impl Sync for Shared {}
+
+/// Free buffers, so a round reuses the allocations of earlier rounds instead
+/// of asking for hundreds of megabytes afresh.
+#[derive(Debug, Default)]
+pub struct BatchPool {
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BatchPool {
+    pub fn new() -> BatchPool {
+        BatchPool::default()
+    }
+
+    /// An empty batch with room for at least `capacity` messages.
+    pub fn take(&self, stride: usize, capacity: usize) -> Batch {
+        let want = stride * capacity;
+        let mut free = self.free.lock().unwrap();
+        // the smallest free buffer that is big enough, else the biggest one
+        let best = free
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.capacity() >= want)
+            .min_by_key(|(_, b)| b.capacity())
+            .or_else(|| free.iter().enumerate().max_by_key(|(_, b)| b.capacity()))
+            .map(|(i, _)| i);
+        let mut data = match best {
+            Some(i) => free.swap_remove(i),
+            None => Vec::new(),
+        };
+        data.reserve(want);
+        Batch { stride, data }
+    }
+
+    /// Hand a batch's buffer back for a later round.
+    pub fn give(&self, mut b: Batch) {
+        if b.data.capacity() == 0 {
+            return;
+        }
+        b.data.clear();
+        let mut free = self.free.lock().unwrap();
+        if free.len() < POOL_LIMIT {
+            free.push(b.data);
+        } else if let Some(smallest) = (0..free.len()).min_by_key(|&i| free[i].capacity()) {
+            if free[smallest].capacity() < b.data.capacity() {
+                free[smallest] = b.data;
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn free_buffers(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn numbered(n: usize, stride: usize) -> Batch {
+        let mut b = Batch::new(stride);
+        for i in 0..n {
+            b.push(&vec![i as u8; stride]);
+        }
+        b
+    }
+
+    #[test]
+    fn messages_roundtrip() {
+        let msgs = vec![vec![1, 2, 3], vec![4, 5, 6]];
+        let b = Batch::from_messages(3, &msgs);
+
+        assert_eq!(b.len(), 2);
+        assert_eq!(b.get(1), &[4, 5, 6]);
+        assert_eq!(b.to_messages(), msgs);
+    }
+
+    #[test]
+    fn wrong_length_is_zeroed() {
+        let b = Batch::from_messages(2, &vec![vec![1], vec![2, 3]]);
+
+        assert_eq!(b.to_messages(), vec![vec![0, 0], vec![2, 3]]);
+    }
+
+    #[test]
+    fn shrink_keeps_middle() {
+        let mut b = Batch::from_messages(4, &vec![vec![1, 2, 3, 4], vec![5, 6, 7, 8]]);
+        b.shrink_stride(1, 2);
+
+        assert_eq!(b.stride(), 2);
+        assert_eq!(b.to_messages(), vec![vec![2, 3], vec![6, 7]]);
+    }
+
+    #[test]
+    fn grow_pads_end() {
+        let mut b = Batch::from_messages(2, &vec![vec![1, 2], vec![3, 4]]);
+        b.grow_stride(3);
+
+        assert_eq!(b.to_messages(), vec![vec![1, 2, 0], vec![3, 4, 0]]);
+    }
+
+    #[test]
+    fn swap_and_truncate() {
+        let mut b = numbered(3, 2);
+        b.swap(2, 0);
+        assert_eq!(b.to_messages(), vec![vec![2, 2], vec![1, 1], vec![0, 0]]);
+
+        b.truncate(1);
+        assert_eq!(b.to_messages(), vec![vec![2, 2]]);
+    }
+
+    #[test]
+    fn swap_disjoint_pairs() {
+        let mut b = numbered(4, 2);
+        b.swap_disjoint(&[(0, 3), (2, 1)]);
+        assert_eq!(
+            b.to_messages(),
+            vec![vec![3, 3], vec![2, 2], vec![1, 1], vec![0, 0]]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn swap_overlapping_panics() {
+        let mut b = numbered(3, 1);
+        b.swap_disjoint(&[(0, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn chunks_and_append() {
+        let b = numbered(5, 3);
+        let chunks = b.chunks(2);
+        assert_eq!(chunks.iter().map(|c| c.len()).collect::<Vec<_>>(), vec![2, 2, 1]);
+
+        let mut joined = Batch::default();
+        for c in chunks {
+            joined.append(c);
+        }
+        assert_eq!(joined, b);
+    }
+
+    #[test]
+    fn pool_reuses_buffers() {
+        let pool = BatchPool::new();
+        let mut b = pool.take(4, 100);
+        b.push(&[0; 4]);
+        let ptr = b.as_bytes().as_ptr();
+        pool.give(b);
+        assert_eq!(pool.free_buffers(), 1);
+
+        let b = pool.take(2, 10);
+        assert!(b.is_empty());
+        assert_eq!(b.as_bytes().as_ptr(), ptr);
+    }
+}
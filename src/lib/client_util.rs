@@ -1,57 +1,104 @@
-use crate::byteorder::{BigEndian, ByteOrder};
-use crate::{message, onion};
+use crate::error::Result;
+use crate::onion::{EncryptionPurpose, PrivateKey, PublicKey};
+use crate::ratchet::{Ratchet, RoundKeys};
+use crate::wire::Payload;
+use crate::{keys, message, onion};
+
+// each message key seals one message, so a fixed nonce is never reused
+const NONCE: u32 = 0;
 
 /// For Alice to wrap a message to send to Bob over servers s1...sn.
 /// Put:
-///  round : the round number
-///  m : of length message::RAW_SIZE
-///  pk = &pk_bob
-///  dk = onion::derive(&sk_alice, &pk_bob)
+///  m : at most `text_size()` bytes
+///  keys : Alice's keys for the round, from her ratchet with Bob
 ///  server_pks : pks of s1...sn
 pub fn wrap(
-    round: u32,
-    mut m: Vec<u8>,
-    pk: &onion::PublicKey,
-    dk: &onion::DerivedKey,
+    m: Vec<u8>,
+    keys: &RoundKeys,
     server_pks: &Vec<onion::PublicKey>,
-) -> (Vec<onion::DerivedKey>, onion::Message) {
-    // resize
-    m.resize(message::RAW_SIZE, 0);
+) -> Result<(Vec<onion::DerivedKey>, onion::Message)> {
+    let w = seal(m, keys)?;
+
+    // onion encrypt
+    message::forward_onion_encrypt(server_pks, w)
+}
+
+/// Like `wrap`, with the ephemeral key of each onion layer given rather
+/// than fresh; see `message::forward_onion_encrypt_with`.
+pub fn wrap_with(
+    m: Vec<u8>,
+    keys: &RoundKeys,
+    server_pks: &Vec<onion::PublicKey>,
+    ephemeral_sks: &[onion::PrivateKey],
+) -> Result<(Vec<onion::DerivedKey>, onion::Message)> {
+    let w = seal(m, keys)?;
+    message::forward_onion_encrypt_with(server_pks, ephemeral_sks, w)
+}
+
+pub use crate::ratchet::text_size;
+
+// the packed message for Bob, before any onion layers
+fn seal(m: Vec<u8>, keys: &RoundKeys) -> Result<onion::Message> {
+    // our ephemeral key, the one of Bob's we sealed to, then the text
+    let m = Payload::new(Ratchet::seal(keys, &m)?)?;
 
     // encrypt for Bob
-    let pk_bytes = BigEndian::read_u32(&pk[..4]);
-    let p = onion::EncryptionPurpose::FromBytes(round ^ pk_bytes);
-    let e = onion::encrypt(&dk, m, p);
+    let e = onion::encrypt(&keys.to_peer, m.into_bytes(), EncryptionPurpose::FromBytes(NONCE))?;
 
     // pack with deaddrop
-    let mut round_bytes = [0; 4];
-    BigEndian::write_u32(&mut round_bytes, round);
-    let drop = message::Deaddrop::new(dk, &round_bytes);
-    let w = message::pack(&e, &drop);
-
-    // onion encrypt
-    message::forward_onion_encrypt(server_pks, w)
+    Ok(message::pack(&e, &keys.deaddrop))
 }
 
 /// For Alice to unwrap her message received from Bob via servers
 /// Put:
-///  round : the round number
 ///  c : response from server
-///  pk = &pk_alice
-///  dk = onion::derive(&sk_alice, &pk_bob)
+///  keys : the keys `wrap` was given
+///  ratchet : the ratchet `keys` came from
 ///  server_dks : output from wrap
+/// Gives Bob's padded text. The ratchet takes in his ephemeral key, so
+/// keep it afterwards.
 pub fn unwrap(
-    round: u32,
     c: onion::Message,
-    pk: &onion::PublicKey,
-    dk: &onion::DerivedKey,
+    keys: &RoundKeys,
+    ratchet: &mut Ratchet,
     server_dks: Vec<onion::DerivedKey>,
-) -> Result<Vec<u8>, ()> {
+) -> Result<Vec<u8>> {
     // onion decrypt
     let m = message::backward_onion_decrypt(&server_dks, c)?;
 
-    // decrypt using Alice/Bob shared key
-    let pk_bytes = BigEndian::read_u32(&pk[..4]);
-    let p = onion::EncryptionPurpose::FromBytes(round ^ pk_bytes);
-    onion::decrypt(&dk, m, p)
+    // decrypt using Bob's key for this round
+    let m = onion::decrypt(&keys.from_peer, m, EncryptionPurpose::FromBytes(NONCE))?;
+    let m = Payload::parse(m)?.into_bytes();
+    ratchet.open(keys, &m)
+}
+
+/// Client `uid`'s ratchet with `remote_uid` as it was last kept, or a new
+/// one if the two have not talked since either changed keys.
+pub fn load_ratchet(
+    uid: usize,
+    remote_uid: usize,
+    sk: &PrivateKey,
+    remote_pk: &PublicKey,
+) -> Result<Ratchet> {
+    let ratchet = match keys::get_conversation(uid, remote_uid)? {
+        Some(mut state) => {
+            let ratchet = Ratchet::from_bytes(&state);
+            onion::erase(&mut state);
+            ratchet?
+        }
+        None => return Ratchet::new(sk, remote_pk),
+    };
+    if !ratchet.is_between(&onion::public_key(sk)?, remote_pk) {
+        return Ratchet::new(sk, remote_pk);
+    }
+    Ok(ratchet)
+}
+
+/// Keep `ratchet` as client `uid`'s with `remote_uid`, to carry on from
+/// after a restart rather than from the long-term keys.
+pub fn keep_ratchet(uid: usize, remote_uid: usize, ratchet: &Ratchet) -> Result<()> {
+    let mut state = ratchet.to_bytes();
+    let res = keys::put_conversation(uid, remote_uid, &state);
+    onion::erase(&mut state);
+    res
 }
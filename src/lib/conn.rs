@@ -0,0 +1,260 @@
+use crate::error::{Error, Result, RoundError};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tarpc::futures::compat::Future01CompatExt;
+use tarpc::futures::{Future, FutureExt, TryFutureExt};
+use tokio::timer::{Delay, Timeout};
+
+/// First wait after a failed call, doubled on every further failure.
+pub const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Longest wait between two attempts.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// Give up once this much time has been spent waiting; long enough for the
+/// rest of the chain to be started after this server.
+pub const RETRY_LIMIT: Duration = Duration::from_secs(120);
+/// Telling a neighbour about an aborted round is best effort; one that is
+/// down has nothing to clean up.
+pub const ABORT_RETRY_LIMIT: Duration = Duration::from_secs(1);
+
+/// `dial` for a module made with `service!`, each RPC module has one.
+macro_rules! dial {
+    () => {
+        /// Connect to a server offering these RPCs.
+        pub async fn dial(addr: std::net::SocketAddr) -> std::io::Result<Client> {
+            let transport = await!(tarpc_bincode_transport::connect(&addr))?;
+            await!(new_stub(tarpc::client::Config::default(), transport))
+        }
+    };
+}
+
+/// Exponentially growing waits between attempts, until the limit is spent.
+#[derive(Debug)]
+pub struct Backoff {
+    next: Duration,
+    waited: Duration,
+    limit: Duration,
+}
+
+impl Backoff {
+    /// Back off until `limit` has been spent waiting.
+    pub fn within(limit: Duration) -> Backoff {
+        Backoff {
+            next: INITIAL_BACKOFF,
+            waited: Duration::from_secs(0),
+            limit,
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::within(RETRY_LIMIT)
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        if self.waited >= self.limit {
+            return None;
+        }
+        let wait = self.next;
+        self.waited += wait;
+        self.next = min(self.next * 2, MAX_BACKOFF);
+        Some(wait)
+    }
+}
+
+/// Wait without holding up the executor's thread.
+pub async fn sleep(wait: Duration) -> io::Result<()> {
+    await!(Delay::new(Instant::now() + wait).compat())
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+/// The address of a server given on the command line.
+pub fn socket_addr(ip: &str, port: u16) -> Result<SocketAddr> {
+    let ip: IpAddr = ip.parse().map_err(|_| {
+        Error::Transport(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("bad server address {}", ip),
+        ))
+    })?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Run a phase of `round`, failing it as timed out if it is not done
+/// within `limit`.
+pub async fn within<T, F>(round: u32, limit: Duration, fut: F) -> Result<T>
+where
+    F: Future<Output = Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    await!(Timeout::new(fut.boxed().compat(), limit).compat()).map_err(|e| {
+        if e.is_elapsed() {
+            Error::Round(round, RoundError::TimedOut)
+        } else {
+            match e.into_inner() {
+                Some(e) => e,
+                None => Error::Transport(io::Error::new(io::ErrorKind::Other, "timer failed")),
+            }
+        }
+    })
+}
+
+/// One connection per neighbouring server, made when first needed and kept
+/// across rounds. A failed call drops the connection and is retried on a
+/// fresh one with backoff, so neighbours can start in any order and a
+/// brief network drop does not fail the round.
+///
+/// Calls are retried whole, so they may reach the other side more than
+/// once and must be safe to repeat.
+#[derive(Debug)]
+pub struct Peers<C> {
+    // each connection with the number it was dialed as
+    conns: Mutex<HashMap<SocketAddr, (usize, C)>>,
+    dialed: AtomicUsize,
+}
+
+impl<C: Clone> Peers<C> {
+    pub fn new() -> Peers<C> {
+        Peers {
+            conns: Mutex::new(HashMap::new()),
+            dialed: AtomicUsize::new(0),
+        }
+    }
+
+    fn cached(&self, addr: SocketAddr) -> Option<(usize, C)> {
+        self.conns.lock().unwrap().get(&addr).cloned()
+    }
+
+    /// Forget connection `id` to `addr`, the next call dials it again. A
+    /// connection another call has dialed since is kept.
+    pub fn reset(&self, addr: SocketAddr, id: usize) {
+        let mut conns = self.conns.lock().unwrap();
+        if conns.get(&addr).map(|&(current, _)| current) == Some(id) {
+            conns.remove(&addr);
+        }
+    }
+
+    /// Run `f` with the connection to `addr`, dialing it with `dial` if
+    /// there is none, and retrying with backoff until it succeeds or the
+    /// retry limit is spent.
+    pub async fn call<T, D, DFut, F, FFut>(&self, addr: SocketAddr, dial: D, f: F) -> io::Result<T>
+    where
+        D: Fn(SocketAddr) -> DFut,
+        DFut: Future<Output = io::Result<C>>,
+        F: FnMut(C) -> FFut,
+        FFut: Future<Output = io::Result<T>>,
+    {
+        await!(self.call_within(addr, RETRY_LIMIT, dial, f))
+    }
+
+    /// Like `call`, but gives up once `limit` has been spent waiting.
+    pub async fn call_within<T, D, DFut, F, FFut>(
+        &self,
+        addr: SocketAddr,
+        limit: Duration,
+        dial: D,
+        mut f: F,
+    ) -> io::Result<T>
+    where
+        D: Fn(SocketAddr) -> DFut,
+        DFut: Future<Output = io::Result<C>>,
+        F: FnMut(C) -> FFut,
+        FFut: Future<Output = io::Result<T>>,
+    {
+        let mut backoff = Backoff::within(limit);
+        loop {
+            let (id, res) = match self.cached(addr) {
+                Some((id, c)) => (Some(id), await!(f(c))),
+                None => match await!(dial(addr)) {
+                    Ok(c) => {
+                        let id = self.dialed.fetch_add(1, Ordering::SeqCst);
+                        self.conns.lock().unwrap().insert(addr, (id, c.clone()));
+                        (Some(id), await!(f(c)))
+                    }
+                    Err(e) => (None, Err(e)),
+                },
+            };
+
+            match res {
+                Ok(t) => return Ok(t),
+                Err(e) => {
+                    if let Some(id) = id {
+                        self.reset(addr, id);
+                    }
+                    match backoff.next() {
+                        Some(wait) => {
+                            eprintln!(
+                                "Call to {} failed: {}, retrying in {} ms",
+                                addr,
+                                e,
+                                wait.as_millis()
+                            );
+                            await!(sleep(wait))?;
+                        }
+                        None => return Err(e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let waits: Vec<Duration> = Backoff::default().take(8).collect();
+        assert_eq!(waits[0], INITIAL_BACKOFF);
+        assert_eq!(waits[1], INITIAL_BACKOFF * 2);
+        assert_eq!(waits[2], INITIAL_BACKOFF * 4);
+        assert_eq!(*waits.last().unwrap(), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn backoff_gives_up() {
+        let total: Duration = Backoff::default().sum();
+        assert!(total >= RETRY_LIMIT);
+        assert!(total < RETRY_LIMIT + MAX_BACKOFF);
+    }
+
+    #[test]
+    fn backoff_within_short_limit() {
+        let limit = Duration::from_millis(250);
+        let waits: Vec<Duration> = Backoff::within(limit).collect();
+        assert_eq!(waits, vec![INITIAL_BACKOFF, INITIAL_BACKOFF * 2]);
+    }
+
+    #[test]
+    fn socket_addr_rejects_bad_ip() {
+        assert_eq!(
+            socket_addr("127.0.0.1", 8080).unwrap(),
+            "127.0.0.1:8080".parse().unwrap()
+        );
+        match socket_addr("not an ip", 8080) {
+            Err(Error::Transport(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            other => panic!("expected a bad address, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reset_keeps_a_newer_connection() {
+        let peers: Peers<u32> = Peers::new();
+        let addr = "127.0.0.1:8080".parse().unwrap();
+        peers.conns.lock().unwrap().insert(addr, (1, 7));
+        // a call on connection 0 failing after connection 1 was dialed
+        peers.reset(addr, 0);
+        assert_eq!(peers.cached(addr), Some((1, 7)));
+        peers.reset(addr, 1);
+        assert_eq!(peers.cached(addr), None);
+    }
+}
@@ -1,45 +1,77 @@
 #![allow(non_snake_case)]
 
-use crate::int_rpc::new_stub as prev_server_stub;
-use crate::keys::get_keypair;
-use crate::keys::PartyType;
+use crate::conn::{socket_addr, within, Peers, ABORT_RETRY_LIMIT};
+use crate::epoch::{EpochKey, SharedKeys};
+use crate::error::{Error, Result, RoundError};
+use crate::frame::{Frame, Kind};
+use crate::int_rpc;
 use crate::laplace::{Laplace, TransformedDistribution};
+use crate::noise::{self, SharedPool};
 use crate::onion;
+use crate::pipeline::{phase_timeout, RoundBuffers, RoundOnce};
+use crate::batch::{self, Batch};
+use crate::shard_rpc;
+use crate::transfer::stream_chunks;
 use crate::util::deaddrop;
-use crate::util::{backward, forward, Settings, State};
-use std::cmp::min;
-use std::net::{IpAddr, SocketAddr};
+use crate::util::{self, backward, mix, Settings, State};
+use crate::wire::Hop;
 use std::str;
-use std::sync::Mutex;
+use std::sync::Arc;
 use std::thread;
 use std::time::Instant;
 use tarpc::futures::future::Ready;
 use tarpc::futures::*;
-use tarpc::{client, context};
-use tarpc_bincode_transport::connect;
+use tarpc::context;
 
 lazy_static! {
-    pub static ref MESSAGES: Mutex<Vec<onion::Message>> = Mutex::new(vec![]);
-    pub static ref ROUND_NUM: Mutex<u32> = Mutex::new(0);
+    // messages received from the previous server, decrypted as they arrive
+    pub static ref MESSAGES: RoundBuffers<(Vec<onion::DerivedKey>, Batch)> =
+                        RoundBuffers::new();
+    // blank noise messages, made between rounds
+    pub static ref NOISE_POOL: SharedPool = SharedPool::new();
+    // our onion key for each epoch
+    static ref EPOCH_KEYS: SharedKeys = SharedKeys::new();
+    // rounds whose EndRound has been acted on, the previous server may repeat it
+    static ref STARTED: RoundOnce = RoundOnce::new();
+    // rounds given up on, by us or the previous server
+    static ref ABORTED: RoundOnce = RoundOnce::new();
+    // connection kept open to the previous server in the chain
+    static ref PREV_SERVER: Peers<int_rpc::Client> = Peers::new();
+}
+
+/// The deaddrop server is the last in the chain.
+const SERVER_ID: usize = crate::NUM_SERVERS - 1;
+
+fn hop() -> Hop {
+    Hop::new(crate::NUM_SERVERS, SERVER_ID)
+}
+
+// needed for every incoming chunk
+fn private_key(round: u32) -> Result<onion::PrivateKey> {
+    EPOCH_KEYS.with(SERVER_ID, |k| k.sk(round))
 }
 
 pub async fn send_m_vec(
-    m_vec: Vec<onion::Message>,
+    m_vec: Batch,
+    round: u32,
     server_addr: String,
     port: u16,
-) -> io::Result<()> {
+) -> Result<()> {
+    if ABORTED.contains(round) {
+        // the previous server has already dropped the round
+        batch::POOL.give(m_vec);
+        return Err(Error::Round(round, RoundError::Aborted));
+    }
     println!("respond with swapped m_vec");
-    let s_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
-    let transport = await!(connect(&s_addr)).unwrap();
-    let mut client = await!(prev_server_stub(client::Config::default(), transport)).unwrap();
-    // divide the m_vec into evenly sized chunks
-    let mut m_vec_clone = m_vec.clone();
+    let s_addr = socket_addr(&server_addr, port)?;
+    // stream the m_vec in evenly sized chunks
     let now = Instant::now();
-    while m_vec_clone.len() > 0 {
-        let chunk_size = min(1024, m_vec_clone.len());
-        let msgs = m_vec_clone.drain(..chunk_size).collect();
-        await!(client.SendMessages(context::current(), msgs, false)).unwrap();
-    }
+    await!(stream_chunks(round, Kind::Backward, m_vec, |frame| {
+        PREV_SERVER.call(s_addr, int_rpc::dial, move |mut client| {
+            let frame = Arc::clone(&frame);
+            async move { await!(client.SendMessages(context::current(), frame)) }
+        })
+    }))?;
     println!(
         "NETWORK RESPONSE TO INT TIME ELAPSED (ms): {}",
         now.elapsed().as_millis()
@@ -48,59 +80,82 @@ pub async fn send_m_vec(
     Ok(())
 }
 
-pub async fn end_round(server_addr: String, port: u16) -> io::Result<()> {
+pub async fn end_round(round: u32, server_addr: String, port: u16) -> Result<()> {
     println!("respond with swapped m_vec");
-    let s_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
-    let transport = await!(connect(&s_addr)).unwrap();
-    let mut client = await!(prev_server_stub(client::Config::default(), transport)).unwrap();
-    await!(client.EndRoundForward(context::current())).unwrap();
-    let mut m_vec = MESSAGES.lock().unwrap();
-    *m_vec = vec![];
+    let s_addr = socket_addr(&server_addr, port)?;
+    await!(PREV_SERVER.call(s_addr, int_rpc::dial, |mut client| {
+        async move { await!(client.EndRoundForward(context::current(), round)) }
+    }))?;
+    Ok(())
+}
+
+/// Give up on `round`: drop what we hold of it and have the previous server
+/// drop it too.
+pub async fn abort_round(
+    round: u32,
+    err: Error,
+    server_addr: String,
+    port: u16,
+) -> Result<()> {
+    if !ABORTED.first(round) {
+        return Ok(());
+    }
+    eprintln!("Aborting round {}: {}", round, err);
+    let (_, forward) = MESSAGES.discard(round);
+    batch::POOL.give(forward);
+
+    let s_addr = socket_addr(&server_addr, port)?;
+    let prev = PREV_SERVER.call_within(s_addr, ABORT_RETRY_LIMIT, int_rpc::dial, |mut client| {
+        async move { await!(client.AbortRoundForward(context::current(), round)) }
+    });
+    if let Err(e) = await!(prev) {
+        eprintln!("Could not abort round {} on {}: {}", round, s_addr, e);
+    }
     Ok(())
 }
 
 pub async fn forward_fn(
     scale: f64,
     micro: f64,
-    m_vec: Vec<onion::Message>,
-) -> io::Result<(State, Vec<onion::Message>)> {
+    decrypted: (Vec<onion::DerivedKey>, Batch),
+    round: u32,
+) -> Result<(State, Batch)> {
     println!("forwarding...");
     let n = Laplace::new(scale, micro);
     let transformed_noise = TransformedDistribution::new(n, |x| u32::max(0, f64::ceil(x) as u32));
     let key_vec = vec![];
-    let (server_priv_key, _) = match get_keypair(PartyType::Server.with_id(2)) {
-        Ok(kp) => kp,
-        Err(e) => panic!("Unable to read server keys!!! err: {}", e),
-    };
+    let server_priv_key = private_key(round)?;
+    let pool = NOISE_POOL.get_or_start(&key_vec, noise::target_for(micro, scale));
     let settings = Settings {
         other_pks: key_vec,
         sk: server_priv_key,
         noise: transformed_noise,
+        pool: Some(pool),
     };
     let now = Instant::now();
-    let fwd = forward(m_vec, &settings);
+    // our layer was already peeled off as the chunks arrived
+    let fwd = mix(decrypted, &settings)?;
     println!("FORWARD TIME ELAPSED (ms): {}", now.elapsed().as_millis());
     Ok(fwd)
 }
 
-pub async fn dead_drop_fn(
-    st: State,
-    m_vec: Vec<onion::Message>,
-) -> io::Result<(State, Vec<onion::Message>)> {
+pub async fn dead_drop_fn(st: State, m_vec: Batch, round: u32) -> Result<(State, Batch)> {
     println!("swapping deaddrops...");
     let now = Instant::now();
-    let dd = deaddrop(m_vec);
+    let shards = shard_rpc::SHARDS.lock().unwrap().clone();
+    let dd = if shards.is_empty() {
+        deaddrop(m_vec)
+    } else {
+        await!(shard_rpc::exchange(round, m_vec, shards))?
+    };
     println!("DEADDROP TIME ELAPSED (ms): {}", now.elapsed().as_millis());
     Ok((st, dd))
 }
 
-pub async fn backwards_fn(
-    st: State,
-    m_vec: Vec<onion::Message>,
-) -> io::Result<(Vec<onion::Message>)> {
+pub async fn backwards_fn(st: State, m_vec: Batch) -> Result<Batch> {
     println!("sending msgs backwards...");
     let now = Instant::now();
-    let bwd = backward(st, m_vec);
+    let bwd = backward(st, m_vec)?;
     println!("BACKWARDS TIME ELAPSED (ms): {}", now.elapsed().as_millis());
     Ok(bwd)
 }
@@ -116,12 +171,18 @@ service! {
     //  | Intermediate Server  | <--  | Dead Drop Server  |
     //  ------------------------      ---------------------
     //
-    // TODO
-    rpc EndRound() -> bool;
-    // Sends a batch of messages in a round
-    rpc SendMessages(v: Vec<onion::Message>) -> bool;
+    // the previous server is done sending us messages for this round
+    rpc EndRound(round: u32) -> bool;
+    // Sends a Forward frame holding a chunk of a round's messages
+    rpc SendMessages(frame: Arc<Vec<u8>>) -> bool;
+    // the previous server gave up on the round
+    rpc AbortRound(round: u32) -> bool;
+    // our signed onion key for `epoch`, made if it is the first time it is asked for
+    rpc EpochKeys(epoch: u32) -> Vec<EpochKey>;
 }
 
+dial!();
+
 #[derive(Clone, Copy, Debug)]
 pub struct DeadDropServer {
     pub micro: f64,
@@ -131,20 +192,35 @@ pub struct DeadDropServer {
 impl self::Service for DeadDropServer {
     type EndRoundFut = Ready<bool>;
     type SendMessagesFut = Ready<bool>;
+    type AbortRoundFut = Ready<bool>;
+    type EpochKeysFut = Ready<Vec<EpochKey>>;
 
-    fn EndRound(self, _: context::Context) -> Self::EndRoundFut {
+    fn EndRound(self, _: context::Context, round: u32) -> Self::EndRoundFut {
+        if !STARTED.first(round) || ABORTED.contains(round) {
+            // a retry of a call we already acted on, or a round given up on
+            return future::ready(true);
+        }
+        // no round still to come can use keys from before the previous epoch
+        if let Err(e) = EPOCH_KEYS.with(SERVER_ID, |k| {
+            k.retire(round);
+            Ok(())
+        }) {
+            eprintln!("Could not retire old epoch keys: {}", e);
+        }
         // when the round is ended, send everything backwards to the previous server
-        // in the chain
+        // in the chain, each round in flight gets its own thread
 
         let _rpc_service = thread::spawn(move || {
-            let m_vec = MESSAGES.lock().unwrap();
-            let m_vec_copy = m_vec.to_vec();
-            drop(m_vec);
-            let fwd = forward_fn(self.scale, self.micro, m_vec_copy);
-            let dd = fwd.and_then(|(s, m)| dead_drop_fn(s, m));
+            let decrypted = MESSAGES.take(round);
+            let fwd = forward_fn(self.scale, self.micro, decrypted, round);
+            let dd = fwd.and_then(move |(s, m)| dead_drop_fn(s, m, round));
             let bwd = dd.and_then(|(s, m)| backwards_fn(s, m));
-            let send = bwd.and_then(|v| send_m_vec(v, "127.0.0.1".to_string(), 8081));
-            let end = send.and_then(|_| end_round("127.0.0.1".to_string(), 8081));
+            let send = bwd.and_then(move |v| send_m_vec(v, round, "127.0.0.1".to_string(), 8081));
+            let end = send.and_then(move |_| end_round(round, "127.0.0.1".to_string(), 8081));
+            // failing or running late aborts the round along the chain
+            let end = within(round, phase_timeout(), end).or_else(move |e| {
+                abort_round(round, e, "127.0.0.1".to_string(), 8081)
+            });
             tokio::run(
                 (end)
                     .map_err(|e| eprintln!("RPC Error: {}", e))
@@ -156,12 +232,59 @@ impl self::Service for DeadDropServer {
         future::ready(true)
     }
 
-    fn SendMessages(self, _: context::Context, v: Vec<onion::Message>) -> Self::SendMessagesFut {
+    fn SendMessages(self, _: context::Context, frame: Arc<Vec<u8>>) -> Self::SendMessagesFut {
         //println!("messages arriving to the deaddrop!");
-        let mut m_vec = MESSAGES.lock().unwrap();
-        m_vec.extend(v.clone());
-        //println!("mvec size: {}!", m_vec.len());
+        let decoded = Frame::decode(&frame, hop()).and_then(|f| f.expect(Kind::Forward));
+        let Frame {
+            round,
+            offset,
+            items: mut v,
+            ..
+        } = match decoded {
+            Ok(f) => f,
+            Err(e) => {
+                // refusing the chunk makes the previous server abort the round
+                eprintln!("Bad chunk: {}", e);
+                return future::ready(false);
+            }
+        };
+        if ABORTED.contains(round) {
+            return future::ready(true);
+        }
+        // decrypt while later chunks are still in flight
+        let kept = private_key(round)
+            .and_then(|sk| util::decrypt(&mut v, &sk))
+            .and_then(|keys| MESSAGES.insert(round, offset, (keys, v)));
+        if let Err(e) = kept {
+            eprintln!("Could not take round {}: {}", round, e);
+            return future::ready(false);
+        }
 
         future::ready(true)
     }
+
+    fn AbortRound(self, _: context::Context, round: u32) -> Self::AbortRoundFut {
+        // abort in the background, the previous server should not wait on us
+        thread::spawn(move || {
+            let err = Error::Round(round, RoundError::Aborted);
+            tokio::run(
+                abort_round(round, err, "127.0.0.1".to_string(), 8081)
+                    .map_err(|e| eprintln!("RPC Error: {}", e))
+                    .boxed()
+                    .compat(),
+            );
+        });
+        future::ready(true)
+    }
+
+    fn EpochKeys(self, _: context::Context, epoch: u32) -> Self::EpochKeysFut {
+        match EPOCH_KEYS.with(SERVER_ID, |k| k.announce(epoch)) {
+            Ok(a) => future::ready(vec![a]),
+            Err(e) => {
+                // the previous server takes an empty answer as a failure
+                eprintln!("Could not announce a key for epoch {}: {}", epoch, e);
+                future::ready(vec![])
+            }
+        }
+    }
 }
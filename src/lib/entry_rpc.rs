@@ -0,0 +1,159 @@
+#![allow(non_snake_case)]
+
+use crate::conn::Peers;
+use crate::epoch::EpochKey;
+use crate::head_rpc::{
+    self, announce_round, configure_chain, publish_epoch_keys, publish_failure, publish_results,
+    MESSAGES, ROUND_NUM,
+};
+use crate::onion;
+use crate::wire::{self, Hop};
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use tarpc::futures::future::Ready;
+use tarpc::futures::*;
+use tarpc::context;
+
+lazy_static! {
+    // the last round handed over, kept in case the head server asks again
+    static ref HANDED_OVER: Mutex<Option<(u32, Vec<onion::Message>)>> = Mutex::new(None);
+}
+
+service! {
+    // RPC's for the entry servers, which take client connections off the head server
+    //
+    //  ----------------      ----------------
+    //  | Entry Server |  <-  | Head Server  |
+    //  ----------------      ----------------
+    //
+    // close `round` and hand over its messages; the next round closes at
+    // `deadline` (ms since the UNIX epoch) and its clients use `epoch_keys`
+    rpc CloseRound(round: u32, deadline: u64, epoch_keys: Vec<EpochKey>) -> Vec<onion::Message>;
+    // replies to the messages handed over for `round`, in the same order
+    rpc DeliverReplies(round: u32, replies: Vec<onion::Message>) -> bool;
+    // `round` was aborted, its messages were not delivered
+    rpc AbortRound(round: u32) -> bool;
+}
+
+dial!();
+
+/// Take over the head server's round numbering and chain parameters, so our
+/// clients wrap their messages for the same round as everyone else's.
+pub async fn join_chain(head: SocketAddr) -> io::Result<()> {
+    // the head server may not be up yet
+    let peers: Peers<head_rpc::Client> = Peers::new();
+    let info = await!(peers.call(head, head_rpc::dial, |mut client| {
+        async move { await!(client.round_info(context::current())) }
+    }))?;
+
+    onion::configure_suite(info.suite);
+    configure_chain(info.round_duration, info.server_pks);
+    publish_epoch_keys(info.epoch_keys);
+    *ROUND_NUM.lock().unwrap() = info.round;
+    announce_round(info.round, info.deadline);
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EntryServer {
+    // rounds of replies kept for fetch
+    pub retain: usize,
+    // the head server, whose parameters we take
+    pub head: SocketAddr,
+}
+
+impl EntryServer {
+    // take the chain's parameters again and wait for them, on a thread of
+    // its own since the RPC runtime is busy with us
+    fn rejoin(self) -> io::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            tokio::run(
+                async move {
+                    let _res = tx.send(await!(join_chain(self.head)));
+                    Ok(())
+                }
+                    .boxed()
+                    .compat(),
+            );
+        });
+        rx.recv()
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "rejoin stopped")))
+    }
+}
+
+impl self::Service for EntryServer {
+    type CloseRoundFut = Ready<Vec<onion::Message>>;
+    type DeliverRepliesFut = Ready<bool>;
+    type AbortRoundFut = Ready<bool>;
+
+    fn CloseRound(
+        self,
+        _: context::Context,
+        round: u32,
+        deadline: u64,
+        epoch_keys: Vec<EpochKey>,
+    ) -> Self::CloseRoundFut {
+        // before the next round opens, so its clients wrap for the right keys
+        publish_epoch_keys(epoch_keys);
+        let mut handed_over = HANDED_OVER.lock().unwrap();
+        if let Some((r, ref m_vec)) = *handed_over {
+            if r == round {
+                // the head server retried, hand over the same messages again
+                return future::ready(m_vec.clone());
+            }
+        }
+
+        let m_vec = {
+            // same lock order as submit, so every ticket names the round its message is in
+            let mut m_vec = MESSAGES.lock().unwrap();
+            let stale = *ROUND_NUM.lock().unwrap();
+            if stale != round {
+                // the head server restarted or we missed rounds, its
+                // parameters may have changed since we joined; our messages
+                // were wrapped for another round, so none of them go on
+                eprintln!(
+                    "Head server closed round {} while round {} was open here, rejoining",
+                    round, stale
+                );
+                m_vec.clear();
+                publish_failure(stale, self.retain);
+                // submits wait on MESSAGES until the parameters are in
+                if let Err(e) = self.rejoin() {
+                    eprintln!("Unable to rejoin the chain: {}", e);
+                }
+            }
+            *ROUND_NUM.lock().unwrap() = round + 1;
+            mem::replace(&mut *m_vec, vec![])
+        };
+        println!("Handing over {} messages for round {}", m_vec.len(), round);
+        announce_round(round + 1, deadline);
+        *handed_over = Some((round, m_vec.clone()));
+        future::ready(m_vec)
+    }
+
+    fn DeliverReplies(
+        self,
+        _: context::Context,
+        round: u32,
+        replies: Vec<onion::Message>,
+    ) -> Self::DeliverRepliesFut {
+        // replies come back with every server's layer added
+        let size = Hop::head(crate::NUM_SERVERS).reply_out_size();
+        if let Err(e) = wire::check_messages(&replies, size) {
+            eprintln!("Bad replies for round {}: {}", round, e);
+            publish_failure(round, self.retain);
+            return future::ready(false);
+        }
+        publish_results(round, replies, self.retain);
+        future::ready(true)
+    }
+
+    fn AbortRound(self, _: context::Context, round: u32) -> Self::AbortRoundFut {
+        publish_failure(round, self.retain);
+        future::ready(true)
+    }
+}
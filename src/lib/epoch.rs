@@ -0,0 +1,309 @@
+use crate::byteorder::{BigEndian, ByteOrder};
+use crate::error::{Error, Result};
+#[cfg(feature = "hybrid")]
+use crate::kem;
+use crate::keys::{self, PartyType};
+use crate::onion::{self, KeyPair, PrivateKey, PublicKey};
+use crate::ring::rand::SystemRandom;
+use crate::ring::signature::{self, Ed25519KeyPair, KeyPair as _};
+use serde::{Deserialize, Serialize};
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Rounds using the same onion keys.
+pub const EPOCH_ROUNDS: u32 = 100;
+
+// signed ahead of every announcement, so the signature means nothing else
+const CONTEXT: &[u8] = b"vuvuzela epoch key";
+
+pub fn epoch_of(round: u32) -> u32 {
+    round / EPOCH_ROUNDS
+}
+
+/// A long-term key pair signing a server's epoch keys: a PKCS#8 document
+/// and the Ed25519 public key.
+pub fn identity_keygen() -> Result<KeyPair> {
+    let doc = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())?;
+    let pair = identity(doc.as_ref())?;
+    let pk = pair.public_key().as_ref().to_vec();
+    Ok((PrivateKey::new(doc.as_ref().to_vec()), pk))
+}
+
+fn identity(sk: &[u8]) -> Result<Ed25519KeyPair> {
+    Ed25519KeyPair::from_pkcs8(sk).map_err(|_| Error::Crypto("malformed identity key"))
+}
+
+/// The identity keys of the servers, in chain order.
+pub fn identity_pks() -> Result<Vec<PublicKey>> {
+    (0..crate::NUM_SERVERS)
+        .map(|i| keys::get_identity(PartyType::Server.with_id(i)))
+        .collect()
+}
+
+/// A server's onion key for one epoch, signed with its identity key.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct EpochKey {
+    // the server's place in the chain
+    pub server: u32,
+    pub epoch: u32,
+    pub pk: PublicKey,
+    pub signature: Vec<u8>,
+}
+
+impl EpochKey {
+    pub fn sign(identity_sk: &[u8], server: u32, epoch: u32, pk: PublicKey) -> Result<EpochKey> {
+        let signature = identity(identity_sk)?
+            .sign(&signed_bytes(server, epoch, &pk))
+            .as_ref()
+            .to_vec();
+        Ok(EpochKey {
+            server,
+            epoch,
+            pk,
+            signature,
+        })
+    }
+
+    /// Check the announcement was signed by the holder of `identity_pk`.
+    pub fn verify(&self, identity_pk: &[u8]) -> Result<()> {
+        signature::UnparsedPublicKey::new(&signature::ED25519, identity_pk)
+            .verify(&signed_bytes(self.server, self.epoch, &self.pk), &self.signature)
+            .map_err(|_| Error::Crypto("bad signature on an epoch key"))
+    }
+}
+
+fn signed_bytes(server: u32, epoch: u32, pk: &[u8]) -> Vec<u8> {
+    let mut numbers = [0; 8];
+    BigEndian::write_u32(&mut numbers[..4], server);
+    BigEndian::write_u32(&mut numbers[4..], epoch);
+    [CONTEXT, &numbers, pk].concat()
+}
+
+/// The onion keys of servers `first..` for `epoch`, in chain order, each
+/// checked against that server's key in `identity_pks`.
+pub fn chain_pks(
+    keys: &[EpochKey],
+    identity_pks: &[PublicKey],
+    first: usize,
+    epoch: u32,
+) -> Result<Vec<PublicKey>> {
+    (first..identity_pks.len())
+        .map(|i| {
+            let k = keys
+                .iter()
+                .find(|k| k.server as usize == i && k.epoch == epoch)
+                .ok_or(Error::Crypto("no key announced for the epoch"))?;
+            k.verify(&identity_pks[i])?;
+            Ok(k.pk.clone())
+        })
+        .collect()
+}
+
+// an onion key pair, followed by a KEM key pair when built for the
+// hybrid suite
+fn keygen() -> Result<KeyPair> {
+    let (sk, pk) = onion::keygen()?;
+    #[cfg(feature = "hybrid")]
+    let (sk, pk) = {
+        let (kem_sk, kem_pk) = kem::keygen()?;
+        let sk = PrivateKey::new([&sk[..], &kem_sk[..]].concat());
+        (sk, [pk, kem_pk].concat())
+    };
+    Ok((sk, pk))
+}
+
+// epochs before this one may still have rounds in flight at `round`
+fn floor(round: u32) -> u32 {
+    epoch_of(round).saturating_sub(1)
+}
+
+/// A server's own onion keys, one pair per epoch. Each is made the first
+/// time it is announced and only ever held in memory.
+#[derive(Debug)]
+pub struct EpochKeys {
+    server: usize,
+    identity_sk: PrivateKey,
+    keys: BTreeMap<u32, (PrivateKey, EpochKey)>,
+    // epochs below this have been erased
+    floor: u32,
+}
+
+impl EpochKeys {
+    pub fn new(server: usize, identity_sk: PrivateKey) -> EpochKeys {
+        EpochKeys {
+            server,
+            identity_sk,
+            keys: BTreeMap::new(),
+            floor: 0,
+        }
+    }
+
+    /// Our signed key for `epoch`, made now if this is the first time it
+    /// is asked for.
+    pub fn announce(&mut self, epoch: u32) -> Result<EpochKey> {
+        if epoch < self.floor {
+            return Err(Error::Crypto("epoch key already erased"));
+        }
+        if let Some((_, a)) = self.keys.get(&epoch) {
+            return Ok(a.clone());
+        }
+        let (sk, pk) = keygen()?;
+        let a = EpochKey::sign(&self.identity_sk, self.server as u32, epoch, pk)?;
+        self.keys.insert(epoch, (sk, a.clone()));
+        Ok(a)
+    }
+
+    /// Our private key for `round`, which clients only have if it was
+    /// announced. The copy is cleared when dropped, like the one kept here.
+    pub fn sk(&self, round: u32) -> Result<PrivateKey> {
+        match self.keys.get(&epoch_of(round)) {
+            Some((sk, _)) => Ok(sk.clone()),
+            None => Err(Error::Crypto("no key for the round's epoch")),
+        }
+    }
+
+    /// Erase the keys of epochs no round in flight at `round` can be from.
+    pub fn retire(&mut self, round: u32) {
+        self.floor = max(self.floor, floor(round));
+        // the dropped keys clear themselves
+        self.keys = self.keys.split_off(&self.floor);
+    }
+}
+
+/// A server's `EpochKeys`, set up on first use with its identity key from
+/// disk and shared by its round threads and RPC handlers.
+#[derive(Debug, Default)]
+pub struct SharedKeys {
+    keys: Mutex<Option<EpochKeys>>,
+}
+
+impl SharedKeys {
+    pub fn new() -> SharedKeys {
+        SharedKeys::default()
+    }
+
+    pub fn with<T, F>(&self, server: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut EpochKeys) -> Result<T>,
+    {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.is_none() {
+            let identity_sk = keys::get_identity_private(PartyType::Server.with_id(server))?;
+            *keys = Some(EpochKeys::new(server, identity_sk));
+        }
+        f(keys.as_mut().unwrap())
+    }
+}
+
+/// The announced keys of the servers after us, by epoch, as the next
+/// server passed them on.
+#[derive(Debug, Default)]
+pub struct Announced {
+    epochs: BTreeMap<u32, Vec<EpochKey>>,
+}
+
+impl Announced {
+    pub fn new() -> Announced {
+        Announced::default()
+    }
+
+    pub fn get(&self, epoch: u32) -> Option<Vec<EpochKey>> {
+        self.epochs.get(&epoch).cloned()
+    }
+
+    pub fn insert(&mut self, epoch: u32, keys: Vec<EpochKey>) {
+        self.epochs.insert(epoch, keys);
+    }
+
+    /// Every announcement from `epoch` on, oldest first.
+    pub fn since(&self, epoch: u32) -> Vec<EpochKey> {
+        self.epochs.range(epoch..).flat_map(|(_, k)| k.clone()).collect()
+    }
+
+    /// Forget the epochs `EpochKeys::retire` would erase at `round`.
+    pub fn retire(&mut self, round: u32) {
+        let keep = self.epochs.split_off(&floor(round));
+        self.epochs = keep;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chain() -> (Vec<PrivateKey>, Vec<PublicKey>) {
+        (0..3).map(|_| identity_keygen().unwrap()).unzip()
+    }
+
+    #[test]
+    fn announcements_verify() {
+        let (sk, pk) = identity_keygen().unwrap();
+        let (_, other_pk) = identity_keygen().unwrap();
+        let a = EpochKey::sign(&sk, 1, 4, vec![7; 32]).unwrap();
+        assert!(a.verify(&pk).is_ok());
+        assert!(a.verify(&other_pk).is_err());
+
+        // the signature covers the server, the epoch and the key
+        let mut b = a.clone();
+        b.epoch = 5;
+        assert!(b.verify(&pk).is_err());
+        let mut b = a.clone();
+        b.server = 2;
+        assert!(b.verify(&pk).is_err());
+        let mut b = a;
+        b.pk[0] ^= 1;
+        assert!(b.verify(&pk).is_err());
+    }
+
+    #[test]
+    fn chain_pks_checked_and_ordered() {
+        let (sks, pks) = chain();
+        let mut keys: Vec<EpochKey> = (0..3)
+            .rev()
+            .map(|i| EpochKey::sign(&sks[i], i as u32, 2, vec![i as u8; 32]).unwrap())
+            .collect();
+        let got = chain_pks(&keys, &pks, 0, 2).unwrap();
+        assert_eq!(got, vec![vec![0; 32], vec![1; 32], vec![2; 32]]);
+        assert_eq!(chain_pks(&keys, &pks, 1, 2).unwrap().len(), 2);
+        // no keys announced for another epoch
+        assert!(chain_pks(&keys, &pks, 0, 3).is_err());
+
+        // server 2's key signed by server 1
+        keys[0] = EpochKey::sign(&sks[1], 2, 2, vec![2; 32]).unwrap();
+        assert!(chain_pks(&keys, &pks, 0, 2).is_err());
+    }
+
+    #[test]
+    fn keys_made_once_and_erased() {
+        let (sk, pk) = identity_keygen().unwrap();
+        let mut keys = EpochKeys::new(1, sk);
+        let a = keys.announce(0).unwrap();
+        assert_eq!(keys.announce(0).unwrap(), a);
+        assert!(a.verify(&pk).is_ok());
+        // the X25519 part, a KEM key may follow
+        let sk = keys.sk(EPOCH_ROUNDS - 1).unwrap()[..32].to_vec();
+        assert_eq!(onion::public_key(&sk).unwrap()[..], a.pk[..32]);
+        // never announced
+        assert!(keys.sk(EPOCH_ROUNDS).is_err());
+
+        keys.announce(1).unwrap();
+        keys.announce(2).unwrap();
+        // the previous epoch is kept for rounds still in flight
+        keys.retire(2 * EPOCH_ROUNDS);
+        assert!(keys.sk(0).is_err());
+        assert!(keys.sk(EPOCH_ROUNDS).is_ok());
+        assert!(keys.announce(0).is_err());
+    }
+
+    #[test]
+    fn announced_forgotten_with_keys() {
+        let mut a = Announced::new();
+        for e in 0..3 {
+            a.insert(e, vec![]);
+        }
+        a.retire(2 * EPOCH_ROUNDS);
+        assert!(a.get(0).is_none());
+        assert!(a.get(1).is_some());
+    }
+}
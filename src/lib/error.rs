@@ -0,0 +1,116 @@
+use crate::ring;
+use std::path::PathBuf;
+use std::{error, fmt, io, result};
+
+/// Why a round did not complete.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RoundError {
+    // a server in the chain gave up on it
+    Aborted,
+    // a phase ran past its deadline
+    TimedOut,
+    // a neighbouring server refused part of it
+    Rejected,
+    // too far from the rounds in flight for its chunks to be held
+    OutOfWindow,
+}
+
+/// Everything that can go wrong in the library and the servers.
+#[derive(Debug)]
+pub enum Error {
+    /// A key was malformed, or a key agreement, encryption or decryption failed.
+    Crypto(&'static str),
+    /// A key file could not be read or written.
+    KeyIo(PathBuf, io::Error),
+    /// A message did not have the size or layout expected.
+    Framing(String),
+    /// An onion layer was for another cipher suite than the chain's, by its code.
+    UnsupportedSuite(u8),
+    /// Another server could not be reached, or a call to it failed.
+    Transport(io::Error),
+    /// A round could not be completed.
+    Round(u32, RoundError),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Crypto(what) => write!(f, "crypto error: {}", what),
+            Error::KeyIo(path, e) => write!(f, "key file {}: {}", path.display(), e),
+            Error::Framing(what) => write!(f, "bad message: {}", what),
+            Error::UnsupportedSuite(code) => {
+                write!(f, "layer for cipher suite {}, not the chain's", code)
+            }
+            Error::Transport(e) => write!(f, "transport error: {}", e),
+            Error::Round(round, RoundError::Aborted) => write!(f, "round {} aborted", round),
+            Error::Round(round, RoundError::TimedOut) => write!(f, "round {} timed out", round),
+            Error::Round(round, RoundError::Rejected) => {
+                write!(f, "round {} rejected by a neighbouring server", round)
+            }
+            Error::Round(round, RoundError::OutOfWindow) => {
+                write!(f, "round {} is too far from the rounds in flight", round)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::KeyIo(_, e) | Error::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Transport(e)
+    }
+}
+
+impl From<ring::error::Unspecified> for Error {
+    fn from(_: ring::error::Unspecified) -> Error {
+        Error::Crypto("operation failed")
+    }
+}
+
+// the RPC and client code still speaks io::Result
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        let kind = match &e {
+            Error::Transport(e) => return io::Error::new(e.kind(), e.to_string()),
+            Error::KeyIo(_, e) => e.kind(),
+            Error::Crypto(_) | Error::Framing(_) | Error::UnsupportedSuite(_) => {
+                io::ErrorKind::InvalidData
+            }
+            Error::Round(_, RoundError::TimedOut) => io::ErrorKind::TimedOut,
+            Error::Round(_, _) => io::ErrorKind::Interrupted,
+        };
+        io::Error::new(kind, e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_errors_keep_their_kind() {
+        let e: io::Error = Error::Round(3, RoundError::TimedOut).into();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(e.to_string(), "round 3 timed out");
+
+        let e: io::Error = Error::Crypto("bad key").into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn transport_errors_round_trip() {
+        let e: Error = io::Error::new(io::ErrorKind::ConnectionRefused, "refused").into();
+        let e: io::Error = e.into();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
@@ -0,0 +1,307 @@
+use crate::batch::Batch;
+use crate::byteorder::{BigEndian, ByteOrder};
+use crate::error::{Error, Result};
+use crate::transfer::CHUNK_SIZE;
+use crate::wire::Hop;
+use std::io::{Read, Write};
+
+/// The version of the framing written by this implementation.
+pub const VERSION: u8 = 1;
+/// Versions this implementation can read, oldest first.
+pub const SUPPORTED_VERSIONS: &[u8] = &[VERSION];
+/// Bytes before the payload: version, type, round, offset and count.
+pub const HEADER_LEN: usize = 14;
+
+/// What a frame carries. The codes are part of the format, never reuse one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    // the versions the sender speaks, one byte each
+    Hello = 1,
+    // an onion from a client for the open round
+    Submit = 2,
+    // the slot a submitted onion got in the header's round
+    Ticket = 3,
+    // why a submitted onion was not taken, one byte
+    Refused = 4,
+    // the reply to a submitted onion
+    Reply = 5,
+    // the round was aborted, the onion was not delivered
+    Failed = 6,
+    // a chunk of a round on its way in, to the next server
+    Forward = 7,
+    // a chunk of a round's replies on its way out, to the previous server
+    Backward = 8,
+}
+
+impl Kind {
+    fn from_code(code: u8) -> Option<Kind> {
+        Some(match code {
+            1 => Kind::Hello,
+            2 => Kind::Submit,
+            3 => Kind::Ticket,
+            4 => Kind::Refused,
+            5 => Kind::Reply,
+            6 => Kind::Failed,
+            7 => Kind::Forward,
+            8 => Kind::Backward,
+            _ => return None,
+        })
+    }
+
+    /// Bytes per item in a frame of this kind received by (or, for
+    /// `Reply`, sent by) the server at `hop`. Client frames are sized for
+    /// the head server.
+    pub fn item_size(self, hop: Hop) -> usize {
+        match self {
+            Kind::Hello | Kind::Refused => 1,
+            Kind::Submit | Kind::Forward => hop.onion_size(),
+            Kind::Ticket => 4,
+            Kind::Reply => hop.reply_out_size(),
+            Kind::Failed => 0,
+            Kind::Backward => hop.reply_in_size(),
+        }
+    }
+
+    // the most items a frame may carry, so a bad count is caught before
+    // anything is allocated for it
+    fn max_count(self) -> usize {
+        match self {
+            Kind::Hello => u8::max_value() as usize,
+            Kind::Submit | Kind::Ticket | Kind::Refused | Kind::Reply => 1,
+            Kind::Failed => 0,
+            Kind::Forward | Kind::Backward => CHUNK_SIZE,
+        }
+    }
+}
+
+/// One framed message: a header followed by `count` items, all the size
+/// their kind has at the receiving hop. See the README for the layout.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Frame {
+    pub kind: Kind,
+    pub round: u32,
+    // position of the first item in the round, for chunks
+    pub offset: u32,
+    pub items: Batch,
+}
+
+impl Frame {
+    pub fn new(kind: Kind, round: u32, offset: u32, items: Batch) -> Frame {
+        Frame {
+            kind,
+            round,
+            offset,
+            items,
+        }
+    }
+
+    /// A frame carrying a single item.
+    pub fn single(kind: Kind, round: u32, item: &[u8]) -> Frame {
+        Frame::new(kind, round, 0, Batch::from_bytes(item.len(), item.to_vec()))
+    }
+
+    /// A frame carrying nothing but its kind and round.
+    pub fn empty(kind: Kind, round: u32) -> Frame {
+        Frame::new(kind, round, 0, Batch::new(0))
+    }
+
+    /// Offer `versions` to the other side.
+    pub fn hello(versions: &[u8]) -> Frame {
+        Frame::new(Kind::Hello, 0, 0, Batch::from_bytes(1, versions.to_vec()))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_as(VERSION)
+    }
+
+    /// Encode in `version`, one agreed with `negotiate`.
+    pub fn encode_as(&self, version: u8) -> Vec<u8> {
+        let payload = self.items.as_bytes();
+        let mut out = vec![0; HEADER_LEN + payload.len()];
+        out[0] = version;
+        out[1] = self.kind as u8;
+        BigEndian::write_u32(&mut out[2..6], self.round);
+        BigEndian::write_u32(&mut out[6..10], self.offset);
+        BigEndian::write_u32(&mut out[10..14], self.items.len() as u32);
+        out[HEADER_LEN..].copy_from_slice(payload);
+        out
+    }
+
+    /// Decode a whole frame received by the server at `hop`.
+    pub fn decode(bytes: &[u8], hop: Hop) -> Result<Frame> {
+        if bytes.len() < HEADER_LEN {
+            return Err(Error::Framing(format!("frame of {} bytes", bytes.len())));
+        }
+        let (header, payload) = bytes.split_at(HEADER_LEN);
+        let (mut frame, len) = parse_header(header, hop)?;
+        if payload.len() != len {
+            return Err(Error::Framing(format!(
+                "{:?} frame with {} bytes of payload, expected {}",
+                frame.kind,
+                payload.len(),
+                len
+            )));
+        }
+        frame.items = Batch::from_bytes(frame.items.stride(), payload.to_vec());
+        Ok(frame)
+    }
+
+    /// Read one frame for the server at `hop` off a stream.
+    pub fn read_from<R: Read>(r: &mut R, hop: Hop) -> Result<Frame> {
+        let mut header = [0; HEADER_LEN];
+        r.read_exact(&mut header)?;
+        Frame::read_payload(r, &header, hop)
+    }
+
+    /// Like `read_from`, but fail unless the frame is in `version`.
+    pub fn read_as<R: Read>(r: &mut R, hop: Hop, version: u8) -> Result<Frame> {
+        let mut header = [0; HEADER_LEN];
+        r.read_exact(&mut header)?;
+        if header[0] != version {
+            return Err(Error::Framing(format!(
+                "version {} frame, agreed on {}",
+                header[0], version
+            )));
+        }
+        Frame::read_payload(r, &header, hop)
+    }
+
+    // the rest of the frame `header` starts
+    fn read_payload<R: Read>(r: &mut R, header: &[u8], hop: Hop) -> Result<Frame> {
+        let (mut frame, len) = parse_header(header, hop)?;
+        let mut payload = vec![0; len];
+        r.read_exact(&mut payload)?;
+        frame.items = Batch::from_bytes(frame.items.stride(), payload);
+        Ok(frame)
+    }
+
+    /// Fail unless this is a `kind` frame.
+    pub fn expect(self, kind: Kind) -> Result<Frame> {
+        if self.kind != kind {
+            return Err(Error::Framing(format!("{:?} frame, expected {:?}", self.kind, kind)));
+        }
+        Ok(self)
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<()> {
+        self.write_as(w, VERSION)
+    }
+
+    /// Write in `version`, one agreed with `negotiate`.
+    pub fn write_as<W: Write>(&self, w: &mut W, version: u8) -> Result<()> {
+        w.write_all(&self.encode_as(version))?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+// the frame a header announces, with no items yet, and its payload length
+fn parse_header(header: &[u8], hop: Hop) -> Result<(Frame, usize)> {
+    let kind = Kind::from_code(header[1])
+        .ok_or_else(|| Error::Framing(format!("unknown frame type {}", header[1])))?;
+    // anyone may say hello, that is how versions are agreed
+    if kind != Kind::Hello && !SUPPORTED_VERSIONS.contains(&header[0]) {
+        return Err(Error::Framing(format!("unsupported version {}", header[0])));
+    }
+    let count = BigEndian::read_u32(&header[10..14]) as usize;
+    if count > kind.max_count() {
+        return Err(Error::Framing(format!("{:?} frame of {} items", kind, count)));
+    }
+    let size = kind.item_size(hop);
+    let frame = Frame::new(
+        kind,
+        BigEndian::read_u32(&header[2..6]),
+        BigEndian::read_u32(&header[6..10]),
+        Batch::new(size),
+    );
+    Ok((frame, count * size))
+}
+
+/// The newest version both sides speak, given the other side's hello.
+pub fn negotiate(hello: &Frame) -> Option<u8> {
+    if hello.kind != Kind::Hello {
+        return None;
+    }
+    hello
+        .items
+        .as_bytes()
+        .iter()
+        .filter(|v| SUPPORTED_VERSIONS.contains(v))
+        .max()
+        .cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message;
+
+    #[test]
+    fn chunks_round_trip() {
+        let hop = Hop::new(3, 1);
+        let items = Batch::from_messages(hop.onion_size(), &vec![vec![7; hop.onion_size()]; 3]);
+        let frame = Frame::new(Kind::Forward, 9, 2048, items);
+
+        let bytes = frame.encode();
+        assert_eq!(bytes.len(), HEADER_LEN + 3 * hop.onion_size());
+        assert_eq!(&bytes[..HEADER_LEN], &[1, 7, 0, 0, 0, 9, 0, 0, 8, 0, 0, 0, 0, 3]);
+        assert_eq!(Frame::decode(&bytes, hop).unwrap(), frame);
+        assert_eq!(Frame::read_from(&mut &bytes[..], hop).unwrap(), frame);
+    }
+
+    #[test]
+    fn sizes_follow_the_hop() {
+        let head = Hop::head(3);
+        let submit = Frame::single(Kind::Submit, 1, &vec![0; message::onion_size(3)]);
+        assert!(Frame::decode(&submit.encode(), head).is_ok());
+        // sized for another hop
+        assert!(Frame::decode(&submit.encode(), Hop::new(3, 1)).is_err());
+
+        let mut short = submit.encode();
+        short.pop();
+        assert!(Frame::decode(&short, head).is_err());
+        assert!(Frame::read_from(&mut &short[..], head).is_err());
+    }
+
+    #[test]
+    fn bad_headers_rejected() {
+        let hop = Hop::head(3);
+        let mut bytes = Frame::empty(Kind::Failed, 4).encode();
+        assert!(Frame::decode(&bytes, hop).is_ok());
+
+        bytes[0] = 99;
+        assert!(Frame::decode(&bytes, hop).is_err());
+        bytes[0] = VERSION;
+        bytes[1] = 0;
+        assert!(Frame::decode(&bytes, hop).is_err());
+        assert!(Frame::decode(&bytes[..5], hop).is_err());
+        assert!(Frame::empty(Kind::Failed, 4).expect(Kind::Forward).is_err());
+
+        // a count past the limit is refused before reading the payload
+        let mut bytes = Frame::empty(Kind::Submit, 4).encode();
+        BigEndian::write_u32(&mut bytes[10..14], u32::max_value());
+        assert!(Frame::read_from(&mut &bytes[..], hop).is_err());
+    }
+
+    #[test]
+    fn hello_picks_newest_shared_version() {
+        let hop = Hop::head(3);
+        let mut theirs = Frame::hello(&[VERSION, 200]).encode();
+        // a newer peer still gets its hello read
+        theirs[0] = 200;
+        let theirs = Frame::decode(&theirs, hop).unwrap();
+        assert_eq!(negotiate(&theirs), Some(VERSION));
+
+        assert_eq!(negotiate(&Frame::hello(&[200])), None);
+        assert_eq!(negotiate(&Frame::empty(Kind::Failed, 0)), None);
+    }
+
+    #[test]
+    fn frames_kept_to_the_agreed_version() {
+        let hop = Hop::head(3);
+        let failed = Frame::empty(Kind::Failed, 4);
+        let bytes = failed.encode_as(VERSION);
+        assert_eq!(Frame::read_as(&mut &bytes[..], hop, VERSION).unwrap(), failed);
+        assert!(Frame::read_as(&mut &bytes[..], hop, VERSION + 1).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use crate::byteorder::{BigEndian, ByteOrder};
+use crate::error::{Error, Result};
+use crate::frame::{self, Frame, Kind, SUPPORTED_VERSIONS};
+use crate::head_rpc::{self, FetchResult, Refused, Ticket};
+use crate::onion;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Framed clients served at once, each holds a thread.
+pub const MAX_CLIENTS: usize = 1024;
+/// A client that sends nothing this long between messages, or does not
+/// take what we write, is dropped.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// reasons carried by a Refused frame
+const MALFORMED: u8 = 1;
+const UNKNOWN_SESSION: u8 = 2;
+const UNSUPPORTED_SUITE: u8 = 3;
+
+fn reason(r: Refused) -> u8 {
+    match r {
+        Refused::Malformed => MALFORMED,
+        Refused::UnknownSession => UNKNOWN_SESSION,
+        Refused::UnsupportedSuite => UNSUPPORTED_SUITE,
+    }
+}
+
+/// Serve clients speaking the frame format directly over TCP, one thread
+/// per connection, next to the head server's RPCs.
+pub fn serve(listener: TcpListener) {
+    serve_up_to(listener, MAX_CLIENTS)
+}
+
+// like serve, turning away clients past the first `limit`
+fn serve_up_to(listener: TcpListener, limit: usize) {
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(s) => {
+                if open.fetch_add(1, Ordering::SeqCst) >= limit {
+                    open.fetch_sub(1, Ordering::SeqCst);
+                    eprintln!("Turned away a framed client, {} are connected", limit);
+                    continue;
+                }
+                let open = Arc::clone(&open);
+                thread::spawn(move || {
+                    if let Err(e) = handle(s) {
+                        eprintln!("Framed client: {}", e);
+                    }
+                    open.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(e) => eprintln!("Could not accept a framed client: {}", e),
+        }
+    }
+}
+
+// agree on a version, then answer each Submit with a Ticket and, once its
+// round is over, a Reply or Failed frame
+fn handle(mut s: TcpStream) -> Result<()> {
+    s.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    s.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let hop = head_rpc::hop();
+    let hello = Frame::read_from(&mut s, hop)?;
+    Frame::hello(SUPPORTED_VERSIONS).write_to(&mut s)?;
+    let version = match frame::negotiate(&hello) {
+        Some(v) => v,
+        None => return Err(Error::Framing("no version in common".to_string())),
+    };
+    loop {
+        let submit = match Frame::read_as(&mut s, hop, version) {
+            Ok(f) => f.expect(Kind::Submit)?,
+            // the client hung up between messages
+            Err(Error::Transport(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let t = match head_rpc::admit(submit.items.as_bytes().to_vec()) {
+            Ok(t) => t,
+            Err(r) => {
+                let refused = Frame::single(Kind::Refused, submit.round, &[reason(r)]);
+                refused.write_as(&mut s, version)?;
+                continue;
+            }
+        };
+        let mut slot = [0; 4];
+        BigEndian::write_u32(&mut slot, t.slot);
+        Frame::single(Kind::Ticket, t.round, &slot).write_as(&mut s, version)?;
+
+        let done = match head_rpc::wait_for(&t) {
+            FetchResult::Reply(ref m) if m.len() == hop.reply_out_size() => {
+                Frame::single(Kind::Reply, t.round, m)
+            }
+            _ => Frame::empty(Kind::Failed, t.round),
+        };
+        done.write_as(&mut s, version)?;
+    }
+}
+
+/// A client of the framed endpoint, one message in flight at a time.
+#[derive(Debug)]
+pub struct FramedClient {
+    stream: TcpStream,
+    // agreed with the server
+    version: u8,
+}
+
+impl FramedClient {
+    pub fn connect(addr: SocketAddr) -> Result<FramedClient> {
+        let mut stream = TcpStream::connect(addr)?;
+        Frame::hello(SUPPORTED_VERSIONS).write_to(&mut stream)?;
+        let hello = Frame::read_from(&mut stream, head_rpc::hop())?;
+        match frame::negotiate(&hello) {
+            Some(version) => Ok(FramedClient { stream, version }),
+            None => Err(Error::Framing("no version in common".to_string())),
+        }
+    }
+
+    /// Submit an onion for the open round, like the `put` RPC.
+    pub fn put(&mut self, m: &onion::Message) -> Result<std::result::Result<Ticket, Refused>> {
+        // the head server reads a Submit by its size, it cannot spot a bad one
+        if m.len() != head_rpc::hop().onion_size() {
+            return Ok(Err(Refused::Malformed));
+        }
+        Frame::single(Kind::Submit, 0, m).write_as(&mut self.stream, self.version)?;
+        let answer = Frame::read_as(&mut self.stream, head_rpc::hop(), self.version)?;
+        match answer.kind {
+            Kind::Ticket => Ok(Ok(Ticket {
+                round: answer.round,
+                slot: BigEndian::read_u32(answer.items.as_bytes()),
+            })),
+            Kind::Refused => Ok(Err(match answer.items.as_bytes() {
+                [UNKNOWN_SESSION] => Refused::UnknownSession,
+                [UNSUPPORTED_SUITE] => Refused::UnsupportedSuite,
+                _ => Refused::Malformed,
+            })),
+            kind => Err(Error::Framing(format!("unexpected {:?} frame", kind))),
+        }
+    }
+
+    /// Wait for the reply to the last ticket, like the `fetch` RPC.
+    pub fn fetch(&mut self) -> Result<FetchResult> {
+        let answer = Frame::read_as(&mut self.stream, head_rpc::hop(), self.version)?;
+        match answer.kind {
+            Kind::Reply => Ok(FetchResult::Reply(answer.items.as_bytes().to_vec())),
+            Kind::Failed => Ok(FetchResult::Failed),
+            kind => Err(Error::Framing(format!("unexpected {:?} frame", kind))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn endpoint() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener));
+        addr
+    }
+
+    #[test]
+    fn malformed_onion_refused() {
+        let mut c = FramedClient::connect(endpoint()).unwrap();
+        assert_eq!(c.put(&vec![0; 3]).unwrap(), Err(Refused::Malformed));
+    }
+
+    #[test]
+    fn bad_frames_end_the_connection() {
+        let mut c = FramedClient::connect(endpoint()).unwrap();
+        // two onions in one Submit frame
+        let mut bytes = Frame::empty(Kind::Submit, 0).encode();
+        BigEndian::write_u32(&mut bytes[10..14], 2);
+        c.stream.write_all(&bytes).unwrap();
+        assert!(c.fetch().is_err());
+    }
+
+    #[test]
+    fn clients_past_the_limit_turned_away() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_up_to(listener, 1));
+        let _first = FramedClient::connect(addr).unwrap();
+        assert!(FramedClient::connect(addr).is_err());
+    }
+
+    #[test]
+    fn unknown_versions_turned_away() {
+        let mut s = TcpStream::connect(endpoint()).unwrap();
+        Frame::hello(&[200]).write_to(&mut s).unwrap();
+        let hello = Frame::read_from(&mut s, head_rpc::hop()).unwrap();
+        assert_eq!(hello.items.as_bytes(), SUPPORTED_VERSIONS);
+        // the server gives up on the connection
+        assert!(Frame::read_from(&mut s, head_rpc::hop()).is_err());
+    }
+}
@@ -1,128 +1,550 @@
 #![allow(non_snake_case)]
 
-use crate::onion;
+use crate::epoch::EpochKey;
+use crate::error::Error;
+use crate::message;
+use crate::frame::{Frame, Kind};
+use crate::onion::{self, CipherSuite};
+use crate::pipeline::{phase_timeout, RoundBuffers, RoundSignals};
+use crate::session::{unix_millis, Event, SessionId, Sessions};
+use crate::wire::{Hop, Onion};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::str;
-use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Condvar, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use tarpc::context;
 use tarpc::futures::future::Ready;
 use tarpc::futures::*;
+use tokio::prelude::Async;
 use tokio_threadpool::blocking;
 
 const ROUND_ENDED_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+// phase timeouts `fetch` waits past the round's deadline before it gives up,
+// by then the round has either ended or been aborted
+const FETCH_PHASES: u32 = 2;
 
 lazy_static! {
     // a list of messages, protected by a global lock
     pub static ref MESSAGES: Mutex<Vec<onion::Message>> = Mutex::new(vec![]);
-    // buffer for messages received
-    pub static ref BACKWARDS_MESSAGES: Mutex<Vec<onion::Message>> = Mutex::new(vec![]);
-    // buffer for messages *after* we process them
-    pub static ref PROCESSED_BACKWARDS_MESSAGES: Mutex<Vec<onion::Message>> =
-                            Mutex::new(vec![]);
-    pub static ref REMOTE_ROUND_ENDED: Arc<(Mutex<bool>, Condvar)> =
-                        Arc::new((Mutex::new(false), Condvar::new()));
-    // used to block until the round ends
-    pub static ref LOCAL_ROUND_ENDED: Arc<(Mutex<bool>, Condvar)> =
-                        Arc::new((Mutex::new(false), Condvar::new()));
-    // block until we finish replying to everyone
-    pub static ref REQUEST_RESPONSE_BLOCK: Arc<(Mutex<AtomicUsize>, Condvar)> =
-                        Arc::new((Mutex::new(AtomicUsize::new(0)), Condvar::new()));
+    // signalled with MESSAGES held whenever a message is added to it
+    pub static ref MESSAGE_ARRIVED: Condvar = Condvar::new();
+    // buffer for messages received, per round in flight
+    pub static ref BACKWARDS_MESSAGES: RoundBuffers = RoundBuffers::new();
+    // replies for recently completed rounds, waited on by fetch
+    pub static ref RESULTS: Arc<(Mutex<RoundResults>, Condvar)> =
+                        Arc::new((Mutex::new(RoundResults::default()), Condvar::new()));
+    pub static ref REMOTE_ROUND_ENDED: RoundSignals = RoundSignals::new();
+    // the round currently accepting messages
     pub static ref ROUND_NUM: Mutex<u32> = Mutex::new(0);
+    // schedule and chain parameters handed out by round_info
+    pub static ref ROUND_INFO: Mutex<RoundInfo> = Mutex::new(RoundInfo::default());
+    // long-lived client connections, waited on by next_events
+    pub static ref SESSIONS: Arc<(Mutex<Sessions>, Condvar)> =
+                        Arc::new((Mutex::new(Sessions::default()), Condvar::new()));
+}
+
+/// Handed back by `put`; redeem it with `fetch` once the round has finished.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct Ticket {
+    pub round: u32,
+    pub slot: u32,
+}
+
+/// Why `put` or `session_put` did not take a message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Refused {
+    // not an onion of the size the chain expects
+    Malformed,
+    // the session has expired, open a new one
+    UnknownSession,
+    // the onion's layer is for another cipher suite than the chain's
+    UnsupportedSuite,
+}
+
+/// What `fetch` hands back for a ticket.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum FetchResult {
+    // the reply to the message
+    Reply(onion::Message),
+    // the round was aborted somewhere in the chain, the message was not
+    // delivered and can be sent again in a later round
+    Failed,
+    // the round's replies are no longer kept
+    Expired,
+}
+
+/// What a client needs to know to take part in the current round.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RoundInfo {
+    // the round currently accepting messages
+    pub round: u32,
+    // when that round closes, in ms since the UNIX epoch
+    pub deadline: u64,
+    // time between rounds in ms
+    pub round_duration: u64,
+    // plaintext bytes per message, see message::RAW_SIZE
+    pub message_size: usize,
+    // identity keys of the servers in chain order, which sign their epoch keys
+    pub server_pks: Vec<onion::PublicKey>,
+    // the cipher suite every layer must use
+    pub suite: CipherSuite,
+    // the servers' onion keys for the open round's epoch and the next one
+    pub epoch_keys: Vec<EpochKey>,
+}
+
+/// Set the parameters that stay fixed for the lifetime of the chain.
+pub fn configure_chain(round_duration: u64, server_pks: Vec<onion::PublicKey>) {
+    let mut info = ROUND_INFO.lock().unwrap();
+    info.round_duration = round_duration;
+    info.message_size = message::RAW_SIZE;
+    info.server_pks = server_pks;
+    info.suite = onion::suite();
+}
+
+/// Hand out a new set of epoch keys with `round_info`.
+pub fn publish_epoch_keys(keys: Vec<EpochKey>) {
+    ROUND_INFO.lock().unwrap().epoch_keys = keys;
+}
+
+/// The epoch keys handed out with `round_info`.
+pub fn epoch_keys() -> Vec<EpochKey> {
+    ROUND_INFO.lock().unwrap().epoch_keys.clone()
+}
+
+/// Replies of the last few completed rounds, indexed by ticket slot.
+#[derive(Debug, Default)]
+pub struct RoundResults {
+    // None for a round that was aborted
+    replies: BTreeMap<u32, Option<Vec<onion::Message>>>,
+    // rounds below this have been discarded
+    floor: u32,
+}
+
+impl RoundResults {
+    /// Publish the replies of a finished round, keeping at most `retain` rounds.
+    pub fn insert(&mut self, round: u32, replies: Vec<onion::Message>, retain: usize) {
+        self.record(round, Some(replies), retain);
+    }
+
+    /// Record that a round was aborted, keeping at most `retain` rounds.
+    pub fn fail(&mut self, round: u32, retain: usize) {
+        self.record(round, None, retain);
+    }
+
+    fn record(&mut self, round: u32, replies: Option<Vec<onion::Message>>, retain: usize) {
+        // an abort arriving after the replies does not take them back
+        self.replies.entry(round).or_insert(replies);
+        while self.replies.len() > retain {
+            let oldest = *self.replies.keys().next().unwrap();
+            self.replies.remove(&oldest);
+            self.floor = oldest + 1;
+        }
+    }
+
+    // a ticket for a round after `open_round` was never handed out, and
+    // does not wait for it
+    fn is_pending(&self, t: &Ticket, open_round: u32) -> bool {
+        t.round >= self.floor && t.round <= open_round && !self.replies.contains_key(&t.round)
+    }
+
+    fn get(&self, t: &Ticket) -> FetchResult {
+        match self.replies.get(&t.round) {
+            None => FetchResult::Expired,
+            Some(None) => FetchResult::Failed,
+            Some(Some(r)) => match r.get(t.slot as usize) {
+                Some(m) => FetchResult::Reply(m.clone()),
+                None => FetchResult::Failed,
+            },
+        }
+    }
+}
+
+/// Record the replies for `round` and wake up any client waiting in `fetch`
+/// or subscribed to the round over a session.
+pub fn publish_results(round: u32, replies: Vec<onion::Message>, retain: usize) {
+    {
+        let &(ref b, ref cvar) = &*SESSIONS.clone();
+        let mut sessions = match b.lock() {
+            Err(e) => e.into_inner(),
+            Ok(o) => o,
+        };
+        sessions.deliver(round, &replies);
+        cvar.notify_all();
+    }
+
+    let &(ref b, ref cvar) = &*RESULTS.clone();
+    let mut results = match b.lock() {
+        Err(e) => e.into_inner(),
+        Ok(o) => o,
+    };
+    results.insert(round, replies, retain);
+    cvar.notify_all();
+}
+
+/// Tell any client waiting on `round` that it was aborted, so it can send
+/// its message again.
+pub fn publish_failure(round: u32, retain: usize) {
+    {
+        let &(ref b, ref cvar) = &*SESSIONS.clone();
+        let mut sessions = match b.lock() {
+            Err(e) => e.into_inner(),
+            Ok(o) => o,
+        };
+        sessions.fail(round);
+        cvar.notify_all();
+    }
+
+    let &(ref b, ref cvar) = &*RESULTS.clone();
+    let mut results = match b.lock() {
+        Err(e) => e.into_inner(),
+        Ok(o) => o,
+    };
+    results.fail(round, retain);
+    cvar.notify_all();
+}
+
+/// Tell every session that `round` is open until `deadline` (ms since the UNIX epoch).
+pub fn announce_round(round: u32, deadline: u64) {
+    {
+        let mut info = ROUND_INFO.lock().unwrap();
+        info.round = round;
+        info.deadline = deadline;
+    }
+
+    let &(ref b, ref cvar) = &*SESSIONS.clone();
+    let mut sessions = match b.lock() {
+        Err(e) => e.into_inner(),
+        Ok(o) => o,
+    };
+    sessions.expire();
+    sessions.announce(round, deadline);
+    cvar.notify_all();
+}
+
+/// Where clients' messages enter the chain.
+pub fn hop() -> Hop {
+    Hop::head(crate::NUM_SERVERS)
+}
+
+fn submit(s: Onion) -> Ticket {
+    // the round thread swaps MESSAGES out and bumps ROUND_NUM under this lock,
+    // so the ticket always names the round the message ends up in
+    let mut m_vec = MESSAGES.lock().unwrap();
+    let ticket = Ticket {
+        round: *ROUND_NUM.lock().unwrap(),
+        slot: m_vec.len() as u32,
+    };
+    m_vec.push(s.into_bytes());
+    MESSAGE_ARRIVED.notify_one();
+    ticket
+}
+
+/// Take a client's onion for the open round, as `put` does.
+pub fn admit(s: onion::Message) -> Result<Ticket, Refused> {
+    Ok(submit(check_onion(s)?))
+}
+
+// a client's onion for the chain, or why it is refused
+fn check_onion(s: onion::Message) -> Result<Onion, Refused> {
+    match Onion::parse(s, hop()) {
+        Ok(s) => Ok(s),
+        Err(Error::UnsupportedSuite(_)) => Err(Refused::UnsupportedSuite),
+        Err(_) => Err(Refused::Malformed),
+    }
+}
+
+// when to stop waiting for the replies of the ticket's round
+fn give_up_at(t: &Ticket) -> Instant {
+    let info = ROUND_INFO.lock().unwrap();
+    let open_for = if t.round == info.round {
+        info.deadline.saturating_sub(unix_millis(SystemTime::now()))
+    } else {
+        0
+    };
+    Instant::now() + Duration::from_millis(open_for) + phase_timeout() * FETCH_PHASES
+}
+
+/// Block until the ticket's round ends or is aborted, as `fetch` does. A
+/// ticket for a round that has not opened yet is `Expired`, and a round
+/// that takes too long to end counts as `Failed`.
+pub fn wait_for(t: &Ticket) -> FetchResult {
+    let open_round = *ROUND_NUM.lock().unwrap();
+    let give_up = give_up_at(t);
+    let &(ref b, ref cvar) = &*RESULTS.clone();
+    let mut results = match b.lock() {
+        Err(e) => e.into_inner(),
+        Ok(o) => o,
+    };
+    while results.is_pending(t, open_round) {
+        let now = Instant::now();
+        if now >= give_up {
+            return FetchResult::Failed;
+        }
+        let wait = ROUND_ENDED_CHECK_INTERVAL.min(give_up - now);
+        let (r, _) = cvar.wait_timeout(results, wait).unwrap();
+        results = r;
+    }
+    results.get(t)
 }
 
 service! {
     // RPC's for the head server
-    rpc put(message: onion::Message) -> onion::Message;
+    // submit a message for the current round, returns immediately
+    rpc put(message: onion::Message) -> Result<Ticket, Refused>;
+    // blocks until the ticket's round ends or is aborted
+    rpc fetch(ticket: Ticket) -> FetchResult;
+    // start a long-lived session, round events and replies are pushed over it
+    rpc open_session() -> SessionId;
+    // like put, but the reply is also pushed to the session
+    rpc session_put(session: SessionId, message: onion::Message) -> Result<Ticket, Refused>;
+    // blocks until the session has events, None if the session has expired
+    rpc next_events(session: SessionId) -> Option<Vec<Event>>;
     // this RPC should only be called by the next server in the chain
-    rpc SendMessages(v: Vec<onion::Message>) -> bool;
+    // with a Backward frame holding a chunk of a round's replies
+    rpc SendMessages(frame: Arc<Vec<u8>>) -> bool;
     // this RPC should also only be called by the next server in the chain
-    // to signify when it is done sending backwards messages
-    rpc EndRound() -> bool;
+    // to signify when it is done sending backwards messages for a round
+    rpc EndRound(round: u32) -> bool;
+    // the next server gave up on the round
+    rpc AbortRound(round: u32) -> bool;
+    // the open round, its deadline and the chain's parameters
+    rpc round_info() -> RoundInfo;
     // for debugging
     rpc getrn() -> u32;
 }
 
+dial!();
+
 #[derive(Clone, Copy, Debug)]
 pub struct HeadServer;
 
 impl self::Service for HeadServer {
     type GetrnFut = Ready<u32>;
-    type PutFut = Ready<onion::Message>;
+    type RoundInfoFut = Ready<RoundInfo>;
+    type PutFut = Ready<Result<Ticket, Refused>>;
+    type FetchFut = Ready<FetchResult>;
+    type OpenSessionFut = Ready<SessionId>;
+    type SessionPutFut = Ready<Result<Ticket, Refused>>;
+    type NextEventsFut = Ready<Option<Vec<Event>>>;
     type SendMessagesFut = Ready<bool>;
     type EndRoundFut = Ready<bool>;
+    type AbortRoundFut = Ready<bool>;
 
     fn put(self, _: context::Context, s: onion::Message) -> Self::PutFut {
-        let msg_count;
-        {
-            let mut m_vec = MESSAGES.lock().unwrap();
-            msg_count = m_vec.len();
-            m_vec.push(s.clone());
-        }
-        //println!("DEBUG: incoming msg len: {:?}", s.clone().len());
-
-        // block until the current round ends, send back round reply
+        future::ready(admit(s))
+    }
 
-        blocking(|| {
-            let &(ref b, ref cvar) = &*LOCAL_ROUND_ENDED.clone();
-            let mut flag = b.lock().unwrap();
-            while !*flag {
-                let (f, _) = cvar.wait_timeout(flag, ROUND_ENDED_CHECK_INTERVAL).unwrap();
-                flag = f;
-                //println!("waiting for end of round: count: {:?}", flag);
+    fn fetch(self, _: context::Context, t: Ticket) -> Self::FetchFut {
+        // block until the ticket's round ends, send back round reply
+        let reply = match blocking(|| wait_for(&t)) {
+            Ok(Async::Ready(reply)) => reply,
+            // no thread free to block on, or the threadpool is shutting down
+            _ => {
+                eprintln!("unable to block!");
+                FetchResult::Failed
             }
-        })
-        .map_err(|_| {
-            println!("unable to block!");
-            panic!("the threadpool shut down")
-        })
-        .unwrap();
-
-        let tuple = REQUEST_RESPONSE_BLOCK.clone();
-        let &(ref b, ref cvar) = &*tuple;
-        let mut flag = match b.lock() {
-            Err(e) => e.into_inner(),
-            Ok(o) => o,
         };
-        *flag.get_mut() += 1;
-        cvar.notify_one();
 
-        let temp = PROCESSED_BACKWARDS_MESSAGES.lock();
-        let msg_vec = match temp {
-            Err(e) => e.into_inner(),
-            Ok(o) => o,
-        };
+        future::ready(reply)
+    }
 
-        //println!("DEBUG: msg len: {:?}", msg_vec[msg_count].clone().len());
-        future::ready(msg_vec[msg_count].clone())
+    fn open_session(self, _: context::Context) -> Self::OpenSessionFut {
+        let &(ref b, _) = &*SESSIONS.clone();
+        let id = b.lock().unwrap().open();
+        future::ready(id)
     }
 
-    fn SendMessages(self, _: context::Context, v: Vec<onion::Message>) -> Self::SendMessagesFut {
-        /*blocking(|| {*/
-        let m_vec = BACKWARDS_MESSAGES.lock();
-        let mut b_msgs = match m_vec {
-            Err(e) => e.into_inner(),
-            Ok(a) => a,
+    fn session_put(
+        self,
+        _: context::Context,
+        id: SessionId,
+        s: onion::Message,
+    ) -> Self::SessionPutFut {
+        let s = match check_onion(s) {
+            Ok(s) => s,
+            Err(e) => return future::ready(Err(e)),
+        };
+        let &(ref b, _) = &*SESSIONS.clone();
+        // hold the session lock so the round cannot be delivered before we watch it
+        let mut sessions = b.lock().unwrap();
+        if !sessions.contains(id) {
+            return future::ready(Err(Refused::UnknownSession));
+        }
+        let ticket = submit(s);
+        sessions.watch(id, ticket);
+        future::ready(Ok(ticket))
+    }
+
+    fn next_events(self, _: context::Context, id: SessionId) -> Self::NextEventsFut {
+        // long poll: hold the request open until there is something to push
+        let events = match blocking(|| {
+            let &(ref b, ref cvar) = &*SESSIONS.clone();
+            let mut sessions = match b.lock() {
+                Err(e) => e.into_inner(),
+                Ok(o) => o,
+            };
+            while !sessions.has_events(id) {
+                let (s, _) = cvar
+                    .wait_timeout(sessions, ROUND_ENDED_CHECK_INTERVAL)
+                    .unwrap();
+                sessions = s;
+            }
+            sessions.poll(id)
+        }) {
+            Ok(Async::Ready(events)) => events,
+            // no thread free to block on, the client opens a new session
+            _ => {
+                eprintln!("unable to block!");
+                None
+            }
         };
-        b_msgs.extend(v.clone());
-        //println!("received messages from next server# = {}", b_msgs.len());
-        /*})
-        .map_err(|_| panic!("the threadpool shut down"))
-        .unwrap();*/
+
+        future::ready(events)
+    }
+
+    fn SendMessages(self, _: context::Context, frame: Arc<Vec<u8>>) -> Self::SendMessagesFut {
+        let kept = Frame::decode(&frame, hop())
+            .and_then(|f| f.expect(Kind::Backward))
+            .and_then(|f| BACKWARDS_MESSAGES.insert(f.round, f.offset, f.items));
+        if let Err(e) = kept {
+            // refusing the chunk makes the next server abort the round
+            eprintln!("Bad replies: {}", e);
+            return future::ready(false);
+        }
         future::ready(true)
     }
 
-    fn EndRound(self, _: context::Context) -> Self::EndRoundFut {
+    fn EndRound(self, _: context::Context, round: u32) -> Self::EndRoundFut {
         //println!("round end called by next server");
-        let tuple = REMOTE_ROUND_ENDED.clone();
-        let &(ref b, ref cvar) = &*tuple;
-        let mut flag = b.lock().unwrap();
-        *flag = true;
-        cvar.notify_one();
+        REMOTE_ROUND_ENDED.signal(round);
+        future::ready(true)
+    }
+
+    fn AbortRound(self, _: context::Context, round: u32) -> Self::AbortRoundFut {
+        // the round thread waiting on it does the cleanup
+        REMOTE_ROUND_ENDED.abort(round);
         future::ready(true)
     }
 
+    fn round_info(self, _: context::Context) -> Self::RoundInfoFut {
+        future::ready(ROUND_INFO.lock().unwrap().clone())
+    }
+
     fn getrn(self, _: context::Context) -> Self::GetrnFut {
         future::ready(*ROUND_NUM.lock().unwrap())
     }
 }
+
+/// The head server's RPCs as an entry server offers them to its clients:
+/// the ones only the next server in the chain may call are refused.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientsOnly;
+
+impl self::Service for ClientsOnly {
+    type GetrnFut = Ready<u32>;
+    type RoundInfoFut = Ready<RoundInfo>;
+    type PutFut = Ready<Result<Ticket, Refused>>;
+    type FetchFut = Ready<FetchResult>;
+    type OpenSessionFut = Ready<SessionId>;
+    type SessionPutFut = Ready<Result<Ticket, Refused>>;
+    type NextEventsFut = Ready<Option<Vec<Event>>>;
+    type SendMessagesFut = Ready<bool>;
+    type EndRoundFut = Ready<bool>;
+    type AbortRoundFut = Ready<bool>;
+
+    fn put(self, c: context::Context, s: onion::Message) -> Self::PutFut {
+        HeadServer.put(c, s)
+    }
+
+    fn fetch(self, c: context::Context, t: Ticket) -> Self::FetchFut {
+        HeadServer.fetch(c, t)
+    }
+
+    fn open_session(self, c: context::Context) -> Self::OpenSessionFut {
+        HeadServer.open_session(c)
+    }
+
+    fn session_put(
+        self,
+        c: context::Context,
+        id: SessionId,
+        s: onion::Message,
+    ) -> Self::SessionPutFut {
+        HeadServer.session_put(c, id, s)
+    }
+
+    fn next_events(self, c: context::Context, id: SessionId) -> Self::NextEventsFut {
+        HeadServer.next_events(c, id)
+    }
+
+    fn SendMessages(self, _: context::Context, _: Arc<Vec<u8>>) -> Self::SendMessagesFut {
+        future::ready(false)
+    }
+
+    fn EndRound(self, _: context::Context, _: u32) -> Self::EndRoundFut {
+        future::ready(false)
+    }
+
+    fn AbortRound(self, _: context::Context, _: u32) -> Self::AbortRoundFut {
+        future::ready(false)
+    }
+
+    fn round_info(self, c: context::Context) -> Self::RoundInfoFut {
+        HeadServer.round_info(c)
+    }
+
+    fn getrn(self, c: context::Context) -> Self::GetrnFut {
+        HeadServer.getrn(c)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn results_pending_until_published() {
+        let mut results = RoundResults::default();
+        let t = Ticket { round: 0, slot: 1 };
+        assert!(results.is_pending(&t, 0));
+
+        results.insert(0, vec![vec![0], vec![1]], 2);
+        assert!(!results.is_pending(&t, 0));
+        assert_eq!(results.get(&t), FetchResult::Reply(vec![1]));
+    }
+
+    #[test]
+    fn results_expire() {
+        let mut results = RoundResults::default();
+        results.insert(0, vec![vec![0]], 2);
+        results.insert(1, vec![vec![1]], 2);
+        results.insert(2, vec![vec![2]], 2);
+
+        let expired = Ticket { round: 0, slot: 0 };
+        assert!(!results.is_pending(&expired, 3));
+        assert_eq!(results.get(&expired), FetchResult::Expired);
+        assert_eq!(
+            results.get(&Ticket { round: 2, slot: 0 }),
+            FetchResult::Reply(vec![2])
+        );
+    }
+
+    #[test]
+    fn results_of_aborted_round_fail() {
+        let mut results = RoundResults::default();
+        let t = Ticket { round: 5, slot: 0 };
+        results.fail(5, 2);
+        assert!(!results.is_pending(&t, 5));
+        assert_eq!(results.get(&t), FetchResult::Failed);
+    }
+
+    #[test]
+    fn future_rounds_not_waited_for() {
+        let results = RoundResults::default();
+        let t = Ticket { round: u32::max_value(), slot: 0 };
+        assert!(!results.is_pending(&t, 3));
+        assert_eq!(results.get(&t), FetchResult::Expired);
+        assert!(results.is_pending(&Ticket { round: 3, slot: 0 }, 3));
+    }
+}
@@ -1,38 +1,50 @@
 #![allow(non_snake_case)]
 
-use crate::keys::get_keypair;
-use crate::keys::{get, PartyType};
+use crate::epoch::{self, epoch_of, Announced, EpochKey, SharedKeys};
 use crate::laplace::{Laplace, TransformedDistribution};
-use crate::util::{backward, forward, Settings, State};
-use std::cmp::min;
-use std::io;
+use crate::noise::{self, SharedPool};
+use crate::batch::{self, Batch};
+use crate::error::{Error, Result, RoundError};
+use crate::frame::{Frame, Kind};
+use crate::transfer::stream_chunks;
+use crate::util::{self, backward, mix, Settings, State};
+use crate::wire::Hop;
+use std::future::Future;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::{Arc, Condvar, Mutex};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Instant;
 use tarpc::futures::future::Ready;
 use tarpc::futures::*;
-use tarpc::{client, context};
-use tarpc_bincode_transport::connect;
-
-// TODO: need to find a way to dynamically switch which new_stub we use
-// depending on flags passed in. Probably by passing down a flag and conditionally
-// creating the new_stub.
-use crate::deaddrop_rpc::new_stub as next_server_new_stub;
-use crate::head_rpc::new_stub as prev_server_new_stub;
-
-// we want to make sure we connect to the intermediate server in our rounds
+use tarpc::context;
 
+use crate::conn::{socket_addr, within, Peers, ABORT_RETRY_LIMIT};
+use crate::deaddrop_rpc;
+use crate::head_rpc;
 use crate::onion;
+use crate::pipeline::{phase_timeout, RoundBuffers, RoundOnce, RoundSignals};
 
 lazy_static! {
-    // a list of messages, protected by a global lock
-    pub static ref MESSAGES: Mutex<Vec<onion::Message>> = Mutex::new(vec![]);
-    // buffer for messages received
-    pub static ref BACKWARDS_MESSAGES: Mutex<Vec<onion::Message>> = Mutex::new(vec![]);
-    pub static ref REMOTE_ROUND_ENDED: Arc<(Mutex<bool>, Condvar)> =
-                        Arc::new((Mutex::new(false), Condvar::new()));
-    pub static ref ROUND_NUM: Mutex<u32> = Mutex::new(0);
+    // messages received from the previous server, decrypted as they arrive
+    pub static ref MESSAGES: RoundBuffers<(Vec<onion::DerivedKey>, Batch)> =
+                        RoundBuffers::new();
+    // messages received from the next server, per round in flight
+    pub static ref BACKWARDS_MESSAGES: RoundBuffers = RoundBuffers::new();
+    pub static ref REMOTE_ROUND_ENDED: RoundSignals = RoundSignals::new();
+    // noise onions for the rest of the chain, made between rounds
+    pub static ref NOISE_POOL: SharedPool = SharedPool::new();
+    // our onion key for each epoch
+    static ref EPOCH_KEYS: SharedKeys = SharedKeys::new();
+    // the keys of the servers after us, as the next server announced them
+    static ref NEXT_KEYS: Mutex<Announced> = Mutex::new(Announced::new());
+    // rounds whose EndRound has been acted on, the head server may repeat it
+    static ref STARTED: RoundOnce = RoundOnce::new();
+    // rounds given up on, by us or a neighbour
+    static ref ABORTED: RoundOnce = RoundOnce::new();
+    // connections kept open to our neighbours in the chain
+    static ref NEXT_SERVER: Peers<deaddrop_rpc::Client> = Peers::new();
+    static ref PREV_SERVER: Peers<head_rpc::Client> = Peers::new();
 }
 
 service! {
@@ -48,18 +60,27 @@ service! {
     //
 
     // Head Server ->  Intermediate Server calls
-    // tells the server we are done with the curent round
-    rpc EndRound() -> bool;
-    // Sends a batch of messages in a round
-    rpc SendMessages(v: Vec<onion::Message>, is_forward: bool) -> bool;
+    // tells the server we are done with the given round
+    rpc EndRound(round: u32) -> bool;
+    // Sends a Forward or Backward frame holding a chunk of a round's messages
+    rpc SendMessages(frame: Arc<Vec<u8>>) -> bool;
+    // the head server gave up on the round
+    rpc AbortRound(round: u32) -> bool;
 
     // Intermediate Server <- Deaddrop server (or next server in chain)
     // the *next* server in the chain calls this RPC to begin the stage
     // where we send the messages backwards to the previous server in the chain
-    rpc EndRoundForward() -> bool;
+    rpc EndRoundForward(round: u32) -> bool;
+    // the next server gave up on the round
+    rpc AbortRoundForward(round: u32) -> bool;
 
+    // Head Server -> Intermediate Server
+    // the signed onion keys of this server and every one after it for `epoch`
+    rpc EpochKeys(epoch: u32) -> Vec<EpochKey>;
 }
 
+dial!();
+
 #[derive(Clone, Copy, Debug)]
 pub struct IntermediateServer {
     pub server_id_arg: usize,
@@ -72,6 +93,37 @@ pub struct IntermediateServer {
     pub scale: f64,
 }
 
+impl IntermediateServer {
+    // needed for every incoming chunk
+    fn private_key(&self, round: u32) -> Result<onion::PrivateKey> {
+        EPOCH_KEYS.with(self.server_id_arg, |k| k.sk(round))
+    }
+
+    fn hop(&self) -> Hop {
+        Hop::new(crate::NUM_SERVERS, self.server_id_arg)
+    }
+
+    fn next_addr(&self) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(self.next_server_ip), self.next_server_port)
+    }
+
+    fn prev_addr(&self) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(self.prev_server_ip), self.prev_server_port)
+    }
+
+    // abort in the background, the caller should not wait on our neighbours
+    fn spawn_abort(self, round: u32, err: Error) {
+        thread::spawn(move || {
+            tokio::run(
+                abort_round(round, err, self.next_addr(), self.prev_addr())
+                    .map_err(|e| eprintln!("RPC Error: {}", e))
+                    .boxed()
+                    .compat(),
+            );
+        });
+    }
+}
+
 /*
  * We have to put the async fn calls here, because they are callbacks in response to RPCs
  * as opposed to the head server.
@@ -84,184 +136,215 @@ pub struct IntermediateServer {
  */
 pub async fn round_status_check(
     is: IntermediateServer,
-    m_vec: Vec<onion::Message>,
-    _server_addr: String,
-    _port: u16,
-) -> io::Result<(State, Vec<onion::Message>)> {
+    decrypted: (Vec<onion::DerivedKey>, Batch),
+    round: u32,
+) -> Result<(State, Batch)> {
     println!("round_status_check");
     // permute the messages *before* proceeding further
     let n = Laplace::new(is.scale, is.micro);
     let transformed_noise = TransformedDistribution::new(n, |x| u32::max(0, f64::ceil(x) as u32));
-    // read in the next server pub keys
-    let mut key_vec = vec![];
+    // the next servers' keys for the round, to make noise with
+    let epoch = epoch_of(round);
+    let next = await!(next_keys(is, epoch))?;
+    let key_vec = epoch::chain_pks(&next, &epoch::identity_pks()?, is.server_id_arg + 1, epoch)?;
 
-    // TODO: make this dynamics for n>3 vuvuzela setups
-    let k2 = match get(PartyType::Server.with_id(2)) {
-        Ok(k) => k,
-        Err(e) => panic!("Unable to read server public key 2: err: {}", e),
-    };
-
-    key_vec.push(k2);
-
-    let (server_priv_key, _) = match get_keypair(PartyType::Server.with_id(is.server_id_arg)) {
-        Ok(kp) => kp,
-        Err(e) => panic!("Unable to read server keys!!! err: {}", e),
-    };
+    let server_priv_key = is.private_key(round)?;
 
     println!("shuffling m_vec...");
+    let pool = NOISE_POOL.get_or_start(&key_vec, noise::target_for(is.micro, is.scale));
     let settings = Settings {
         other_pks: key_vec,
         sk: server_priv_key,
         noise: transformed_noise,
+        pool: Some(pool),
     };
 
     let now = Instant::now();
-    let (state, processed_m_vec): (State, Vec<onion::Message>) = forward(m_vec, &settings);
+    // our layer was already peeled off as the chunks arrived
+    let (state, processed_m_vec): (State, Batch) = mix(decrypted, &settings)?;
     println!("FORWARD TIME ELAPSED (ms): {}", now.elapsed().as_millis());
 
     Ok((state, processed_m_vec))
 }
 
+/// The keys of the servers after us for `epoch`, asked of the next server
+/// the first time and checked before they are used or passed on.
+pub async fn next_keys(is: IntermediateServer, epoch: u32) -> Result<Vec<EpochKey>> {
+    let known = NEXT_KEYS.lock().unwrap().get(epoch);
+    if let Some(keys) = known {
+        return Ok(keys);
+    }
+    let keys = await!(NEXT_SERVER.call(is.next_addr(), deaddrop_rpc::dial, |mut client| {
+        async move { await!(client.EpochKeys(context::current(), epoch)) }
+    }))?;
+    epoch::chain_pks(&keys, &epoch::identity_pks()?, is.server_id_arg + 1, epoch)?;
+    NEXT_KEYS.lock().unwrap().insert(epoch, keys.clone());
+    Ok(keys)
+}
+
+/// Our key for `epoch` followed by those of the servers after us.
+pub async fn chain_keys(is: IntermediateServer, epoch: u32) -> Result<Vec<EpochKey>> {
+    let own = EPOCH_KEYS.with(is.server_id_arg, |k| k.announce(epoch))?;
+    let next = await!(next_keys(is, epoch))?;
+    Ok([vec![own], next].concat())
+}
+
+// the head server takes an empty answer as a failure
+async fn announce_chain(is: IntermediateServer, epoch: u32) -> Vec<EpochKey> {
+    match await!(chain_keys(is, epoch)) {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("Could not announce keys for epoch {}: {}", epoch, e);
+            vec![]
+        }
+    }
+}
+
 pub async fn start_round(
     s: State,
-    m_vec: Vec<onion::Message>,
+    m_vec: Batch,
     server_addr: String,
     port: u16,
-) -> io::Result<(State, Vec<onion::Message>)> {
+) -> Result<(State, Batch)> {
     println!("start_round");
-    let s_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
-    let transport = await!(connect(&s_addr)).unwrap();
-    let _client = await!(next_server_new_stub(client::Config::default(), transport)).unwrap();
-    // TODO: figure out if we need this at all
-    //await!(client.StartNewRound(context::current())).unwrap();
+    let s_addr = socket_addr(&server_addr, port)?;
+    // make sure the next server is up before we start streaming to it
+    await!(NEXT_SERVER.call(s_addr, deaddrop_rpc::dial, |_| future::ready(Ok(()))))?;
     Ok((s, m_vec))
 }
 
 pub async fn send_m_vec(
     s: State,
-    m_vec: Vec<onion::Message>,
+    m_vec: Batch,
+    round: u32,
     server_addr: String,
     port: u16,
-) -> io::Result<(State, Vec<onion::Message>)> {
+) -> Result<State> {
     println!("forward m_vec");
-    let s_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
-    let transport = await!(connect(&s_addr)).unwrap();
-    let mut client = await!(next_server_new_stub(client::Config::default(), transport)).unwrap();
+    let s_addr = socket_addr(&server_addr, port)?;
 
-    // divide the m_vec into evenly sized chunks
+    // stream the m_vec in evenly sized chunks
     let now = Instant::now();
-    let mut m_vec_clone = m_vec.clone();
-    while m_vec_clone.len() > 0 {
-        let chunk_size = min(1024, m_vec_clone.len());
-        let msgs = m_vec_clone.drain(..chunk_size).collect();
-        await!(client.SendMessages(context::current(), msgs)).unwrap();
-    }
+    await!(stream_chunks(round, Kind::Forward, m_vec, |frame| {
+        NEXT_SERVER.call(s_addr, deaddrop_rpc::dial, move |mut client| {
+            let frame = Arc::clone(&frame);
+            async move { await!(client.SendMessages(context::current(), frame)) }
+        })
+    }))?;
     println!(
         "NETWORK FORWARD TIME ELAPSED (ms): {}",
         now.elapsed().as_millis()
     );
 
-    Ok((s, m_vec))
+    Ok(s)
 }
 
 pub async fn end_round(
     s: State,
-    m_vec: Vec<onion::Message>,
+    round: u32,
     server_addr: String,
     port: u16,
-) -> io::Result<(State, Vec<onion::Message>)> {
+) -> Result<State> {
     println!("end_round");
 
-    let s_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
-    let transport = await!(connect(&s_addr)).unwrap();
-    let mut client = await!(next_server_new_stub(client::Config::default(), transport)).unwrap();
-    await!(client.EndRound(context::current())).unwrap();
-    Ok((s, m_vec))
+    let s_addr = socket_addr(&server_addr, port)?;
+    await!(NEXT_SERVER.call(s_addr, deaddrop_rpc::dial, |mut client| {
+        async move { await!(client.EndRound(context::current(), round)) }
+    }))?;
+    Ok(s)
 }
 
 pub async fn cleanup(
     s: State,
-    m_vec: Vec<onion::Message>,
+    m_vec: Batch,
     _server_addr: String,
     _port: u16,
-) -> io::Result<Vec<onion::Message>> {
+) -> Result<Batch> {
     // unshuffle the permutations
     let now = Instant::now();
-    let back = backward(s, m_vec);
+    let back = backward(s, m_vec)?;
     println!("BACKWARDS TIME ELAPSED (ms): {}", now.elapsed().as_millis());
     Ok(back)
 }
 
 // send messages to previous server finally & finish cleanup
-pub async fn wait_for_reply(s: State) -> io::Result<(State, Vec<onion::Message>)> {
+pub async fn wait_for_reply(s: State, round: u32) -> Result<(State, Batch)> {
     // wait int_server signals it is done sending us messages
     println!("waiting on the next server to finish sending msgs");
-    let &(ref b, ref cvar) = &*REMOTE_ROUND_ENDED.clone();
-    let mut flag = b.lock().unwrap();
-    while !*flag {
-        flag = cvar.wait(flag).unwrap();
-    }
-    // reset cond var flag for next round
-    *flag = false;
-    println!("round ended by the next server!");
+    REMOTE_ROUND_ENDED.wait(round, phase_timeout())?;
+    println!("round {} ended by the next server!", round);
 
-    Ok((s, BACKWARDS_MESSAGES.lock().unwrap().to_vec()))
+    Ok((s, BACKWARDS_MESSAGES.take(round)))
 }
 
 // send messages to previous server finally & finish cleanup
 pub async fn backwards_send_msg(
-    m_vec: Vec<onion::Message>,
+    m_vec: Batch,
+    round: u32,
     server_addr: String,
     port: u16,
-) -> io::Result<()> {
+) -> Result<()> {
     println!("backwards_send_msg");
 
-    let s_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
-    let transport = await!(connect(&s_addr)).unwrap();
-    let mut client = await!(prev_server_new_stub(client::Config::default(), transport)).unwrap();
+    let s_addr = socket_addr(&server_addr, port)?;
 
     // send all the messages
     let now = Instant::now();
-    let mut m_vec_clone = m_vec.clone();
-    while m_vec_clone.len() > 0 {
-        let chunk_size = min(1024, m_vec_clone.len());
-        let msgs: Vec<onion::Message> = m_vec_clone.drain(..chunk_size).collect();
-        match await!(client.SendMessages(context::current(), msgs.clone())) {
-            Err(e) => {
-                println!("err: {}, retrying...", e);
-                // if we die again, that's it
-                await!(client.SendMessages(context::current(), msgs)).unwrap();
-                ()
-            }
-            Ok(_o) => (),
-        }
-    }
+    await!(stream_chunks(round, Kind::Backward, m_vec, |frame| {
+        PREV_SERVER.call(s_addr, head_rpc::dial, move |mut client| {
+            let frame = Arc::clone(&frame);
+            async move { await!(client.SendMessages(context::current(), frame)) }
+        })
+    }))?;
     println!(
         "NETWORK FORWARD TO HEAD TIME ELAPSED (ms): {}",
         now.elapsed().as_millis()
     );
 
-    // increment round count
-    let mut rn = ROUND_NUM.lock().unwrap();
-    *rn += 1;
-    println!("Round number incremented, now: {}", *rn);
-    // empty MESSAGES
-    let mut msgs = MESSAGES.lock().unwrap();
-    *msgs = vec![];
-    let mut back_msgs = BACKWARDS_MESSAGES.lock().unwrap();
-    *back_msgs = vec![];
-
     Ok(())
 }
 
-pub async fn backwards_end_round(server_addr: String, port: u16) -> io::Result<()> {
+pub async fn backwards_end_round(round: u32, server_addr: String, port: u16) -> Result<()> {
     println!("ending round on previous server");
 
-    let s_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
-    let transport = await!(connect(&s_addr)).unwrap();
-    let mut client = await!(prev_server_new_stub(client::Config::default(), transport)).unwrap();
-    await!(client.EndRound(context::current())).unwrap();
+    let s_addr = socket_addr(&server_addr, port)?;
+    await!(PREV_SERVER.call(s_addr, head_rpc::dial, |mut client| {
+        async move { await!(client.EndRound(context::current(), round)) }
+    }))?;
+
+    Ok(())
+}
 
+/// Give up on `round`: drop what we hold of it and have both neighbours
+/// drop it too. Acting once per round keeps the abort from bouncing back.
+pub async fn abort_round(
+    round: u32,
+    err: Error,
+    next: SocketAddr,
+    prev: SocketAddr,
+) -> Result<()> {
+    if !ABORTED.first(round) {
+        return Ok(());
+    }
+    eprintln!("Aborting round {}: {}", round, err);
+    let (_, forward) = MESSAGES.discard(round);
+    batch::POOL.give(forward);
+    batch::POOL.give(BACKWARDS_MESSAGES.discard(round));
+    // wake our own round thread if it is waiting on the next server
+    REMOTE_ROUND_ENDED.abort(round);
+
+    let to_next = NEXT_SERVER.call_within(next, ABORT_RETRY_LIMIT, deaddrop_rpc::dial, |mut c| {
+        async move { await!(c.AbortRound(context::current(), round)) }
+    });
+    if let Err(e) = await!(to_next) {
+        eprintln!("Could not abort round {} on {}: {}", round, next, e);
+    }
+    let to_prev = PREV_SERVER.call_within(prev, ABORT_RETRY_LIMIT, head_rpc::dial, |mut c| {
+        async move { await!(c.AbortRound(context::current(), round)) }
+    });
+    if let Err(e) = await!(to_prev) {
+        eprintln!("Could not abort round {} on {}: {}", round, prev, e);
+    }
     Ok(())
 }
 
@@ -269,50 +352,78 @@ impl self::Service for IntermediateServer {
     type EndRoundFut = Ready<bool>;
     type EndRoundForwardFut = Ready<bool>;
     type SendMessagesFut = Ready<bool>;
+    type AbortRoundFut = Ready<bool>;
+    type AbortRoundForwardFut = Ready<bool>;
+    // asks the next server, so it cannot answer right away
+    type EpochKeysFut = Pin<Box<dyn Future<Output = Vec<EpochKey>> + Send>>;
 
     // next server calls this to end the round and begin sending backwards
-    fn EndRoundForward(self, _: context::Context) -> Self::EndRoundForwardFut {
-        let tuple = REMOTE_ROUND_ENDED.clone();
-        let &(ref b, ref cvar) = &*tuple;
-        let mut flag = b.lock().unwrap();
-        *flag = true;
-        cvar.notify_one();
+    fn EndRoundForward(self, _: context::Context, round: u32) -> Self::EndRoundForwardFut {
+        REMOTE_ROUND_ENDED.signal(round);
+        future::ready(true)
+    }
+
+    fn AbortRound(self, _: context::Context, round: u32) -> Self::AbortRoundFut {
+        self.spawn_abort(round, Error::Round(round, RoundError::Aborted));
+        future::ready(true)
+    }
 
+    fn AbortRoundForward(self, _: context::Context, round: u32) -> Self::AbortRoundForwardFut {
+        self.spawn_abort(round, Error::Round(round, RoundError::Aborted));
         future::ready(true)
     }
 
     // head server calls this to signify when it is done
-    fn EndRound(self, _: context::Context) -> Self::EndRoundFut {
+    fn EndRound(self, _: context::Context, round: u32) -> Self::EndRoundFut {
+        if !STARTED.first(round) || ABORTED.contains(round) {
+            // a retry of a call we already acted on, or a round given up on
+            return future::ready(true);
+        }
+        // no round still to come can use keys from before the previous epoch
+        if let Err(e) = EPOCH_KEYS.with(self.server_id_arg, |k| {
+            k.retire(round);
+            Ok(())
+        }) {
+            eprintln!("Could not retire old epoch keys: {}", e);
+        }
+        NEXT_KEYS.lock().unwrap().retire(round);
         // this is the trigger to spin off a thread to forward all messages
-        // to the next server
+        // to the next server, each round in flight gets its own thread
         let _rpc_service = thread::spawn(move || {
-            let m_vec = MESSAGES.lock().unwrap();
-            let copy_m_vec = m_vec.to_vec();
-            drop(m_vec);
+            let decrypted = MESSAGES.take(round);
             let next_ip = self.next_server_ip;
             let next_port = self.next_server_port.clone();
             let prev_port = self.prev_server_port.clone();
 
-            let shuffle = round_status_check(self, copy_m_vec, next_ip.to_string(), next_port);
+            let shuffle = round_status_check(self, decrypted, round);
             // signal int_server to start round
             let start_new_round = shuffle
                 .and_then(move |(s, v)| start_round(s, v, next_ip.to_string(), next_port.clone()));
             // begin sending messages in batches
-            let send_msgs = start_new_round
-                .and_then(move |(s, v)| send_m_vec(s, v, next_ip.to_string(), next_port.clone()));
+            let send_msgs = start_new_round.and_then(move |(s, v)| {
+                send_m_vec(s, v, round, next_ip.to_string(), next_port.clone())
+            });
             // signal end of round
             let end_round = send_msgs
-                .and_then(move |(s, v)| end_round(s, v, next_ip.to_string(), next_port.clone()));
-            let wait = end_round.and_then(|(s, _)| wait_for_reply(s));
-            let backwards_permute = wait.and_then(move |(s, v)| {
-                cleanup(s, v, self.prev_server_ip.to_string(), prev_port.clone())
-            });
-            // only after the next server is done, can we start sending msgs back
-            let respond = backwards_permute.and_then(move |v| {
-                backwards_send_msg(v, self.prev_server_ip.to_string(), prev_port)
+                .and_then(move |s| end_round(s, round, next_ip.to_string(), next_port.clone()));
+            // each phase gets its own deadline, the wait has one built in
+            let wait = within(round, phase_timeout(), end_round)
+                .and_then(move |s| wait_for_reply(s, round));
+            let end_previous = wait.and_then(move |(s, v)| {
+                let prev_ip = self.prev_server_ip.to_string();
+                let backwards_permute = cleanup(s, v, prev_ip.clone(), prev_port.clone());
+                // only after the next server is done, can we start sending msgs back
+                let respond = backwards_permute.and_then(move |v| {
+                    backwards_send_msg(v, round, self.prev_server_ip.to_string(), prev_port)
+                });
+                let end_previous = respond.and_then(move |_| {
+                    backwards_end_round(round, prev_ip, prev_port)
+                });
+                within(round, phase_timeout(), end_previous)
             });
-            let end_previous = respond
-                .and_then(move |_| backwards_end_round(self.prev_server_ip.to_string(), prev_port));
+            // any phase failing or running late aborts the round along the chain
+            let end_previous = end_previous
+                .or_else(move |e| abort_round(round, e, self.next_addr(), self.prev_addr()));
 
             tokio::run(
                 (end_previous)
@@ -324,22 +435,55 @@ impl self::Service for IntermediateServer {
         future::ready(true)
     }
 
-    // only the head server calls this RPC
-    fn SendMessages(
-        self,
-        _: context::Context,
-        v: Vec<onion::Message>,
-        is_forward: bool,
-    ) -> Self::SendMessagesFut {
-        if is_forward {
-            let mut m_vec = MESSAGES.lock().unwrap();
-            m_vec.extend(v.clone());
-        //println!("# messages received from prev = {}", m_vec.len());
-        } else {
-            let mut m_vec = BACKWARDS_MESSAGES.lock().unwrap();
-            m_vec.extend(v.clone());
-            //println!("# messages received from next = {}", m_vec.len());
+    // the head server sends forward chunks, the next server backward ones
+    fn SendMessages(self, _: context::Context, frame: Arc<Vec<u8>>) -> Self::SendMessagesFut {
+        let Frame {
+            kind,
+            round,
+            offset,
+            items: mut v,
+        } = match Frame::decode(&frame, self.hop()) {
+            Ok(f) => f,
+            Err(e) => {
+                // refusing the chunk makes the sender abort the round
+                eprintln!("Bad chunk: {}", e);
+                return future::ready(false);
+            }
+        };
+        if ABORTED.contains(round) {
+            // nothing more is kept for the round
+            return future::ready(true);
+        }
+        match kind {
+            Kind::Forward => {
+                // decrypt while later chunks are still in flight, only acking
+                // once done keeps the sender from running too far ahead
+                let kept = self
+                    .private_key(round)
+                    .and_then(|sk| util::decrypt(&mut v, &sk))
+                    .and_then(|keys| MESSAGES.insert(round, offset, (keys, v)));
+                if let Err(e) = kept {
+                    // refusing the chunk makes the head server abort the round
+                    eprintln!("Could not take round {}: {}", round, e);
+                    return future::ready(false);
+                }
+            }
+            Kind::Backward => {
+                if let Err(e) = BACKWARDS_MESSAGES.insert(round, offset, v) {
+                    // refusing the chunk makes the deaddrop server abort the round
+                    eprintln!("Could not take replies: {}", e);
+                    return future::ready(false);
+                }
+            }
+            _ => {
+                eprintln!("Unexpected {:?} frame for round {}", kind, round);
+                return future::ready(false);
+            }
         }
         future::ready(true)
     }
+
+    fn EpochKeys(self, _: context::Context, epoch: u32) -> Self::EpochKeysFut {
+        Box::pin(announce_chain(self, epoch))
+    }
 }
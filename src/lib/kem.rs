@@ -0,0 +1,74 @@
+use crate::error::{Error, Result};
+use crate::onion::{KeyPair, PrivateKey};
+use pqcrypto_mlkem::mlkem768;
+use pqcrypto_traits::kem::{Ciphertext as _, PublicKey as _, SecretKey as _, SharedSecret as _};
+
+/// Bytes of an ML-KEM-768 encapsulation key, published next to a server's
+/// X25519 key.
+pub const PK_LEN: usize = 1184;
+/// Bytes of an ML-KEM-768 decapsulation key.
+pub const SK_LEN: usize = 2400;
+/// Bytes of an ML-KEM-768 ciphertext, carried in every hybrid layer header.
+pub const CT_LEN: usize = 1088;
+
+// where the encapsulation key sits in a FIPS 203 decapsulation key, after
+// the K-PKE private key
+const PK_OFFSET: usize = 1152;
+
+pub fn keygen() -> Result<KeyPair> {
+    let (pk, sk) = mlkem768::keypair();
+    Ok((PrivateKey::new(sk.as_bytes().to_vec()), pk.as_bytes().to_vec()))
+}
+
+/// A fresh shared secret for the holder of `pk`, and the ciphertext that
+/// lets them recover it.
+pub fn encapsulate(pk: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let pk = mlkem768::PublicKey::from_bytes(pk)
+        .map_err(|_| Error::Crypto("malformed KEM public key"))?;
+    let (secret, ct) = mlkem768::encapsulate(&pk);
+    Ok((secret.as_bytes().to_vec(), ct.as_bytes().to_vec()))
+}
+
+/// Recover the shared secret of a ciphertext. A tampered ciphertext gives a
+/// secret unrelated to the sender's, so the layer fails to open.
+pub fn decapsulate(sk: &[u8], ct: &[u8]) -> Result<Vec<u8>> {
+    let sk = mlkem768::SecretKey::from_bytes(sk)
+        .map_err(|_| Error::Crypto("malformed KEM private key"))?;
+    let ct = mlkem768::Ciphertext::from_bytes(ct)
+        .map_err(|_| Error::Crypto("malformed KEM ciphertext"))?;
+    Ok(mlkem768::decapsulate(&ct, &sk).as_bytes().to_vec())
+}
+
+/// The encapsulation key inside a decapsulation key, which FIPS 203 keeps
+/// there for the re-encryption check.
+pub fn public_key(sk: &[u8]) -> Result<&[u8]> {
+    if sk.len() != SK_LEN {
+        return Err(Error::Crypto("malformed KEM private key"));
+    }
+    Ok(&sk[PK_OFFSET..PK_OFFSET + PK_LEN])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sizes_match() {
+        let (sk, pk) = keygen().unwrap();
+        assert_eq!((sk.len(), pk.len()), (SK_LEN, PK_LEN));
+        assert_eq!(public_key(&sk).unwrap(), &pk[..]);
+        let (_secret, ct) = encapsulate(&pk).unwrap();
+        assert_eq!(ct.len(), CT_LEN);
+    }
+
+    #[test]
+    fn decapsulate_recovers_secret() {
+        let (sk, pk) = keygen().unwrap();
+        let (secret, mut ct) = encapsulate(&pk).unwrap();
+        assert_eq!(decapsulate(&sk, &ct).unwrap(), secret);
+
+        ct[0] ^= 1;
+        assert_ne!(decapsulate(&sk, &ct).unwrap(), secret);
+        assert!(decapsulate(&sk, &ct[1..]).is_err());
+    }
+}
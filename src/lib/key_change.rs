@@ -0,0 +1,117 @@
+use crate::error::{Error, Result};
+use crate::onion::{self, PrivateKey, PublicKey};
+use crate::ring::hmac;
+
+// starts every notice, a text message never begins with a NUL byte
+const MARKER: &[u8] = b"\0key change\0";
+// HKDF info of the key the notice is authenticated with
+const MAC_INFO: &[u8] = b"key change";
+const MAC_LEN: usize = 32;
+
+/// A client's notice to a contact that its identity key changed, sent as
+/// an ordinary conversation message. Client keys are X25519 and cannot
+/// sign, so the new key is bound to the old one with an HMAC-SHA256 under
+/// a key from derive(old_sk, contact_pk), which only the sender and the
+/// contact can compute. The key's HKDF info names the sender's old key and
+/// then the contact's, so a notice reflected back at its sender does not
+/// verify. Unlike the conversation keys, this one does not
+/// come from the ratchet, so a leaked ratchet state cannot forge a notice.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyChange {
+    pub new_pk: PublicKey,
+    tag: Vec<u8>,
+}
+
+impl KeyChange {
+    /// Announce `new_pk` to the holder of `contact_pk`, as the holder of
+    /// `old_sk`.
+    pub fn new(
+        old_sk: &PrivateKey,
+        contact_pk: &PublicKey,
+        new_pk: PublicKey,
+    ) -> Result<KeyChange> {
+        let old_pk = onion::public_key(old_sk)?;
+        let key = mac_key(old_sk, contact_pk, &old_pk, contact_pk)?;
+        let tag = hmac::sign(&key, &new_pk).as_ref().to_vec();
+        Ok(KeyChange { new_pk, tag })
+    }
+
+    /// Check the notice came from the holder of `old_pk`, as seen by the
+    /// holder of `sk`.
+    pub fn verify(&self, sk: &PrivateKey, old_pk: &PublicKey) -> Result<()> {
+        let key = mac_key(sk, old_pk, old_pk, &onion::public_key(sk)?)?;
+        hmac::verify(&key, &self.new_pk, &self.tag)
+            .map_err(|_| Error::Crypto("key change not made with the old key"))
+    }
+
+    /// The notice as a message payload.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [MARKER, &self.new_pk, &self.tag].concat()
+    }
+
+    /// Read a notice from a decrypted payload, `None` if it holds a text
+    /// message instead.
+    pub fn parse(m: &[u8]) -> Option<KeyChange> {
+        if !m.starts_with(MARKER) {
+            return None;
+        }
+        let rest = &m[MARKER.len()..];
+        let pk_len = *onion::PK_LEN;
+        if rest.len() < pk_len + MAC_LEN {
+            return None;
+        }
+        Some(KeyChange {
+            new_pk: rest[..pk_len].to_vec(),
+            tag: rest[pk_len..][..MAC_LEN].to_vec(),
+        })
+    }
+}
+
+// the key of a notice from the holder of `from_pk` to the holder of `to_pk`,
+// one of whom holds `sk` and the other `pk`
+fn mac_key(sk: &PrivateKey, pk: &[u8], from_pk: &[u8], to_pk: &[u8]) -> Result<hmac::Key> {
+    let mut secret = vec![0; MAC_LEN];
+    let info = [MAC_INFO, from_pk, to_pk].concat();
+    onion::derive(sk, pk)?.extract_and_expand(&info, &mut secret)?;
+    let key = hmac::Key::new(hmac::HMAC_SHA256, &secret);
+    onion::erase(&mut secret);
+    Ok(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::Payload;
+
+    #[test]
+    fn notice_checked_against_old_key() {
+        let (old_sk, old_pk) = onion::keygen().unwrap();
+        let (bob_sk, bob_pk) = onion::keygen().unwrap();
+        let (_, new_pk) = onion::keygen().unwrap();
+        let notice = KeyChange::new(&old_sk, &bob_pk, new_pk.clone()).unwrap();
+
+        // sent padded like any other message
+        let payload = Payload::new(notice.to_bytes()).unwrap().into_bytes();
+        let got = KeyChange::parse(&payload).unwrap();
+        assert_eq!(got.new_pk, new_pk);
+        assert!(got.verify(&bob_sk, &old_pk).is_ok());
+
+        // made with another key, or changed on the way
+        let (mallory_sk, _) = onion::keygen().unwrap();
+        let forged = KeyChange::new(&mallory_sk, &bob_pk, new_pk.clone()).unwrap();
+        assert!(forged.verify(&bob_sk, &old_pk).is_err());
+        let mut swapped = got;
+        swapped.new_pk[0] ^= 1;
+        assert!(swapped.verify(&bob_sk, &old_pk).is_err());
+
+        // nor does it pass for one from Bob, reflected back at its sender
+        assert!(notice.verify(&old_sk, &bob_pk).is_err());
+    }
+
+    #[test]
+    fn text_is_not_a_notice() {
+        let payload = Payload::new(b"hello\n".to_vec()).unwrap().into_bytes();
+        assert!(KeyChange::parse(&payload).is_none());
+        assert!(KeyChange::parse(MARKER).is_none());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::byteorder::{BigEndian, ByteOrder};
+use crate::error::{Error, Result};
+use crate::onion;
+use crate::ring::aead;
+use crate::ring::rand::{SecureRandom, SystemRandom};
+use crate::scrypt::{scrypt, ScryptParams};
+
+/// Private key files are sealed with AES-256-GCM under a key derived from a
+/// passphrase with scrypt:
+///  magic | version | kind | log_n | r | p | salt | nonce | sealed key | tag
+/// where r and p are big-endian u32s and everything before the sealed key
+/// is authenticated with it.
+const MAGIC: &[u8] = b"vzkey";
+pub const VERSION: u8 = 1;
+
+/// scrypt cost, 2^15 blocks of 1 KiB with r = 8, so 32 MiB per attempt
+pub const LOG_N: u8 = 15;
+const R: u32 = 8;
+const P: u32 = 1;
+// the most a file may ask for, 1 GiB with r = 8
+const MAX_LOG_N: u8 = 20;
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+// the header up to the scrypt parameters and salt, then the nonce
+const PARAMS_END: usize = 5 + 3 + 8 + SALT_LEN;
+const HEADER_LEN: usize = PARAMS_END + aead::NONCE_LEN;
+
+/// What a private key file holds, so one cannot be read in place of another.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyKind {
+    // a client's X25519 key
+    Onion,
+    // a server's Ed25519 key, see epoch
+    Identity,
+    // a client's ratchet with one contact, see ratchet
+    Conversation,
+}
+
+impl KeyKind {
+    fn code(self) -> u8 {
+        match self {
+            KeyKind::Onion => 1,
+            KeyKind::Identity => 2,
+            KeyKind::Conversation => 3,
+        }
+    }
+}
+
+/// Seal private key `sk` under `passphrase`.
+pub fn seal(kind: KeyKind, sk: &[u8], passphrase: &[u8]) -> Result<Vec<u8>> {
+    seal_with(kind, sk, passphrase, LOG_N)
+}
+
+/// Like `seal`, at a cost of 2^`log_n` scrypt blocks. Only lower it to
+/// keep tests fast.
+pub fn seal_with(kind: KeyKind, sk: &[u8], passphrase: &[u8], log_n: u8) -> Result<Vec<u8>> {
+    seal_under(kind, sk, &FileKey::new(passphrase, log_n)?)
+}
+
+/// Seal `sk` under a key already derived, with a fresh nonce, so a process
+/// that writes often pays for scrypt once.
+pub fn seal_under(kind: KeyKind, sk: &[u8], file_key: &FileKey) -> Result<Vec<u8>> {
+    let mut header = vec![0; HEADER_LEN];
+    header[..5].copy_from_slice(MAGIC);
+    header[5] = VERSION;
+    header[6] = kind.code();
+    header[7..PARAMS_END].copy_from_slice(&file_key.params);
+    SystemRandom::new().fill(&mut header[PARAMS_END..])?;
+
+    let sealing_key = aead::SealingKey::new(&aead::AES_256_GCM, &file_key.key)?;
+    let tag_len = aead::AES_256_GCM.tag_len();
+    let mut in_out = [sk, &vec![0; tag_len]].concat();
+    let aad = aead::Aad::from(&header[..]);
+    aead::seal_in_place(&sealing_key, nonce(&header)?, aad, &mut in_out, tag_len)
+        .map_err(|_| Error::Crypto("could not seal the key file"))?;
+    Ok([header, in_out].concat())
+}
+
+/// Open a file made by `seal`, failing if it holds another kind of key or
+/// the passphrase is wrong.
+pub fn open(kind: KeyKind, file: &[u8], passphrase: &[u8]) -> Result<Vec<u8>> {
+    open_with(kind, file, passphrase, &mut None)
+}
+
+/// Like `open`, using `known` if the file was sealed under it rather than
+/// running scrypt. Otherwise the file's own key is derived, and kept in
+/// `known` once it opens the file.
+pub fn open_with(
+    kind: KeyKind,
+    file: &[u8],
+    passphrase: &[u8],
+    known: &mut Option<FileKey>,
+) -> Result<Vec<u8>> {
+    if file.len() < HEADER_LEN || !file.starts_with(MAGIC) {
+        return Err(Error::Crypto("not a sealed key file"));
+    }
+    let (header, sealed) = file.split_at(HEADER_LEN);
+    if header[5] != VERSION {
+        return Err(Error::Crypto("key file of an unknown version"));
+    }
+    if header[6] != kind.code() {
+        return Err(Error::Crypto("key file holds another kind of key"));
+    }
+    let params = &header[7..PARAMS_END];
+    let derived = match known {
+        Some(k) if k.params[..] == *params => None,
+        _ => Some(FileKey::derive(passphrase, params)?),
+    };
+    let file_key = derived.as_ref().or(known.as_ref()).unwrap();
+    let opening_key = aead::OpeningKey::new(&aead::AES_256_GCM, &file_key.key)?;
+    let mut in_out = sealed.to_vec();
+    let aad = aead::Aad::from(header);
+    let len = aead::open_in_place(&opening_key, nonce(header)?, aad, 0, &mut in_out)
+        .map_err(|_| Error::Crypto("wrong passphrase or damaged key file"))?
+        .len();
+    in_out.truncate(len);
+    if derived.is_some() {
+        *known = derived;
+    }
+    Ok(in_out)
+}
+
+/// The AEAD key of key files with one set of scrypt parameters and salt,
+/// cleared when dropped.
+pub struct FileKey {
+    // log_n | r | p | salt, as in the header
+    params: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl FileKey {
+    /// Derive a key from `passphrase` with a fresh salt, at a cost of
+    /// 2^`log_n` scrypt blocks.
+    pub fn new(passphrase: &[u8], log_n: u8) -> Result<FileKey> {
+        let mut params = vec![0; PARAMS_END - 7];
+        params[0] = log_n;
+        BigEndian::write_u32(&mut params[1..5], R);
+        BigEndian::write_u32(&mut params[5..9], P);
+        SystemRandom::new().fill(&mut params[9..])?;
+        FileKey::derive(passphrase, &params)
+    }
+
+    pub fn log_n(&self) -> u8 {
+        self.params[0]
+    }
+
+    // the key for files with `params`, checked first so a file cannot make
+    // us spend more than we would ourselves
+    fn derive(passphrase: &[u8], params: &[u8]) -> Result<FileKey> {
+        let log_n = params[0];
+        if log_n > MAX_LOG_N {
+            return Err(Error::Crypto("key file asks for too costly a KDF"));
+        }
+        let r = BigEndian::read_u32(&params[1..5]);
+        let p = BigEndian::read_u32(&params[5..9]);
+        // r and p scale the cost as much as n does, and are never anything else
+        if (r, p) != (R, P) {
+            return Err(Error::Crypto("unsupported KDF parameters in key file"));
+        }
+        let scrypt_params = ScryptParams::new(log_n, r, p)
+            .map_err(|_| Error::Crypto("bad KDF parameters in key file"))?;
+        let mut key = FileKey {
+            params: params.to_vec(),
+            key: vec![0; KEY_LEN],
+        };
+        if scrypt(passphrase, &params[9..], &scrypt_params, &mut key.key).is_err() {
+            return Err(Error::Crypto("could not derive the key file's key"));
+        }
+        Ok(key)
+    }
+}
+
+impl Drop for FileKey {
+    fn drop(&mut self) {
+        onion::erase(&mut self.key);
+    }
+}
+
+fn nonce(header: &[u8]) -> Result<aead::Nonce> {
+    aead::Nonce::try_assume_unique_for_key(&header[PARAMS_END..])
+        .map_err(|_| Error::Crypto("bad nonce in key file"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // cheap enough for tests, a real file records its own cost
+    const TEST_LOG_N: u8 = 4;
+
+    #[test]
+    fn sealed_key_opens() {
+        let sk = vec![7; 32];
+        let file = seal_with(KeyKind::Onion, &sk, b"hunter2", TEST_LOG_N).unwrap();
+        assert!(!file.windows(sk.len()).any(|w| w == &sk[..]));
+        assert_eq!(open(KeyKind::Onion, &file, b"hunter2").unwrap(), sk);
+        // salted, so the same key seals differently each time
+        assert_ne!(seal_with(KeyKind::Onion, &sk, b"hunter2", TEST_LOG_N).unwrap(), file);
+    }
+
+    #[test]
+    fn wrong_passphrase_or_kind_refused() {
+        let file = seal_with(KeyKind::Identity, &[1; 83], b"hunter2", TEST_LOG_N).unwrap();
+        assert!(open(KeyKind::Identity, &file, b"hunter3").is_err());
+        assert!(open(KeyKind::Onion, &file, b"hunter2").is_err());
+
+        // the header is authenticated, as well as the key
+        let mut changed = file.clone();
+        changed[HEADER_LEN - 1] ^= 1;
+        assert!(open(KeyKind::Identity, &changed, b"hunter2").is_err());
+        let mut changed = file.clone();
+        changed[5] = VERSION + 1;
+        assert!(open(KeyKind::Identity, &changed, b"hunter2").is_err());
+        // nor any r or p but ours, which would make the KDF unbounded
+        let mut changed = file;
+        BigEndian::write_u32(&mut changed[8..12], u32::max_value());
+        assert!(open(KeyKind::Identity, &changed, b"hunter2").is_err());
+        // a raw key from before the format
+        assert!(open(KeyKind::Onion, &[7; 32], b"hunter2").is_err());
+    }
+
+    #[test]
+    fn derived_key_reused() {
+        let file_key = FileKey::new(b"hunter2", TEST_LOG_N).unwrap();
+        let a = seal_under(KeyKind::Conversation, &[1; 40], &file_key).unwrap();
+        let b = seal_under(KeyKind::Conversation, &[1; 40], &file_key).unwrap();
+        // same salt, fresh nonce
+        assert_eq!(a[..PARAMS_END], b[..PARAMS_END]);
+        assert_ne!(a, b);
+        // the key held is used, the passphrase is not asked again
+        let mut known = Some(file_key);
+        let opened = open_with(KeyKind::Conversation, &a, b"", &mut known).unwrap();
+        assert_eq!(opened, vec![1; 40]);
+
+        // a file under another salt opens with its own key, which is kept
+        let c = seal_with(KeyKind::Conversation, &[2; 40], b"hunter2", TEST_LOG_N).unwrap();
+        assert!(open_with(KeyKind::Conversation, &c, b"hunter3", &mut known).is_err());
+        assert_eq!(known.as_ref().unwrap().params[..], a[7..PARAMS_END]);
+        let opened = open_with(KeyKind::Conversation, &c, b"hunter2", &mut known).unwrap();
+        assert_eq!(opened, vec![2; 40]);
+        assert_eq!(known.unwrap().params[..], c[7..PARAMS_END]);
+    }
+}
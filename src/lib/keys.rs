@@ -1,72 +1,588 @@
+use crate::error::{Error, Result};
+use crate::keyfile::{self, KeyKind};
 use crate::onion;
 
-use std::{fs, io, path::PathBuf};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
-/// Read and write keys to hard-coded locations
+/// Read and write keys through a `Keys` handle on a `KeyStore`, this
+/// process's set once with `configure_store`.
 
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum PartyType {
     Client,
     Server,
 }
 
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct Party {
     party_type: PartyType,
-    id: usize,
+    id: String,
 }
 
 impl PartyType {
     pub fn with_id(self, id: usize) -> Party {
+        self.named(&id.to_string())
+    }
+
+    /// A party known by name rather than number.
+    pub fn named(self, name: &str) -> Party {
         Party {
             party_type: self,
-            id,
+            id: name.to_string(),
         }
     }
 }
 
-enum KeyType {
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum KeyType {
     Public,
     Private,
+    // a server's long-term signing keys, see epoch
+    IdentityPublic,
+    IdentityPrivate,
+    // a client's ratchet with one contact, see ratchet
+    Conversation,
 }
 
-fn parent(t: &PartyType) -> PathBuf {
-    let mut path = PathBuf::new();
-    path.push("./keys");
-    path.push(match t {
-        PartyType::Client => "client",
-        PartyType::Server => "server",
-    });
-    path
+impl KeyType {
+    // what a private key is, `None` for a public one
+    fn private_kind(self) -> Option<KeyKind> {
+        match self {
+            KeyType::Private => Some(KeyKind::Onion),
+            KeyType::IdentityPrivate => Some(KeyKind::Identity),
+            KeyType::Conversation => Some(KeyKind::Conversation),
+            KeyType::Public | KeyType::IdentityPublic => None,
+        }
+    }
 }
 
-fn path(p: &Party, k: KeyType) -> PathBuf {
-    let mut path = parent(&p.party_type);
-    path.push(p.id.to_string());
-    let e = match k {
-        KeyType::Public => "pk",
-        KeyType::Private => "sk",
-    };
-    path.set_extension(e);
-    path
+/// Somewhere to keep the keys of every party.
+pub trait KeyStore: Send + Sync {
+    fn read(&self, p: &Party, k: KeyType) -> Result<Vec<u8>>;
+    fn write(&self, p: &Party, k: KeyType, key: &[u8]) -> Result<()>;
 }
 
-pub fn makedirs() -> io::Result<()> {
-    fs::create_dir_all(parent(&PartyType::Client))?;
-    fs::create_dir_all(parent(&PartyType::Server))?;
-    Ok(())
+// numbers the files a write goes through, so no two writes share one
+static TMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Keys in files under `root`, as `<root>/<client|server>/<id>.<pk|sk|id.pk|id.sk>`,
+/// and a client's conversations as `<root>/client/<id>-<contact>.conv`.
+/// Private key files are readable by their owner alone. A key is written
+/// to a file beside it first and renamed over it, so it is never left half
+/// written.
+#[derive(Debug)]
+pub struct FileStore {
+    root: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> FileStore {
+        FileStore {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, p: &Party, k: KeyType) -> Result<PathBuf> {
+        let mut path = self.root.clone();
+        path.push(match p.party_type {
+            PartyType::Client => "client",
+            PartyType::Server => "server",
+        });
+        // a name must not lead out of the directory
+        let separator = |c: char| c == '/' || c == '\\';
+        if p.id.is_empty() || p.id.starts_with('.') || p.id.contains(separator) {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, "not a valid party name");
+            return Err(Error::KeyIo(path.join(&p.id), e));
+        }
+        path.push(&p.id);
+        let e = match k {
+            KeyType::Public => "pk",
+            KeyType::Private => "sk",
+            KeyType::IdentityPublic => "id.pk",
+            KeyType::IdentityPrivate => "id.sk",
+            KeyType::Conversation => "conv",
+        };
+        path.set_extension(e);
+        Ok(path)
+    }
+}
+
+impl KeyStore for FileStore {
+    fn read(&self, p: &Party, k: KeyType) -> Result<Vec<u8>> {
+        let path = self.path(p, k)?;
+        fs::read(&path).map_err(|e| Error::KeyIo(path, e))
+    }
+
+    fn write(&self, p: &Party, k: KeyType, key: &[u8]) -> Result<()> {
+        let path = self.path(p, k)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| Error::KeyIo(dir.to_path_buf(), e))?;
+        }
+        let mode = match k.private_kind() {
+            Some(_) => 0o600,
+            None => 0o644,
+        };
+        // a crash leaves either the old key or the new one
+        let mut tmp = path.clone().into_os_string();
+        let n = TMP_FILES.fetch_add(1, Ordering::SeqCst);
+        tmp.push(format!(".tmp{}.{}", process::id(), n));
+        let tmp = PathBuf::from(tmp);
+        let _res = fs::remove_file(&tmp);
+        let res = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)
+            .and_then(|mut f| {
+                f.write_all(key)?;
+                f.set_permissions(fs::Permissions::from_mode(mode))?;
+                f.sync_all()
+            })
+            .and_then(|()| fs::rename(&tmp, &path));
+        if let Err(e) = res {
+            let _res = fs::remove_file(&tmp);
+            return Err(Error::KeyIo(path, e));
+        }
+        // and the rename itself survives a crash
+        match path.parent() {
+            Some(dir) => File::open(dir)
+                .and_then(|d| d.sync_all())
+                .map_err(|e| Error::KeyIo(dir.to_path_buf(), e)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Keys held in memory only, for tests and for keys made at runtime.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    keys: Mutex<HashMap<(Party, KeyType), Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl KeyStore for MemoryStore {
+    fn read(&self, p: &Party, k: KeyType) -> Result<Vec<u8>> {
+        match self.keys.lock().unwrap().get(&(p.clone(), k)) {
+            Some(key) => Ok(key.clone()),
+            None => {
+                let e = io::Error::new(io::ErrorKind::NotFound, format!("no {:?} key", k));
+                Err(Error::KeyIo(PathBuf::from(&p.id), e))
+            }
+        }
+    }
+
+    fn write(&self, p: &Party, k: KeyType, key: &[u8]) -> Result<()> {
+        let old = self.keys.lock().unwrap().insert((p.clone(), k), key.to_vec());
+        // a replaced key, like a rotated private one, is cleared
+        if let Some(mut old) = old {
+            onion::erase(&mut old);
+        }
+        Ok(())
+    }
+}
+
+/// Private keys sealed under a passphrase before they reach `inner`, see
+/// keyfile. Public keys pass through as they are. The key derived from the
+/// passphrase is held after the first file, so conversations written every
+/// round do not run scrypt each time.
+pub struct Encrypted<S> {
+    inner: S,
+    passphrase: Vec<u8>,
+    log_n: u8,
+    // the last file key derived, cleared when dropped
+    file_key: Mutex<Option<keyfile::FileKey>>,
+}
+
+impl<S: KeyStore> Encrypted<S> {
+    pub fn new(inner: S, passphrase: Vec<u8>) -> Encrypted<S> {
+        Encrypted {
+            inner,
+            passphrase,
+            log_n: keyfile::LOG_N,
+            file_key: Mutex::new(None),
+        }
+    }
+}
+
+impl<S: KeyStore> KeyStore for Encrypted<S> {
+    fn read(&self, p: &Party, k: KeyType) -> Result<Vec<u8>> {
+        let key = self.inner.read(p, k)?;
+        match k.private_kind() {
+            Some(kind) => {
+                let mut file_key = self.file_key.lock().unwrap();
+                keyfile::open_with(kind, &key, &self.passphrase, &mut *file_key)
+            }
+            None => Ok(key),
+        }
+    }
+
+    fn write(&self, p: &Party, k: KeyType, key: &[u8]) -> Result<()> {
+        match k.private_kind() {
+            Some(kind) => {
+                let mut file_key = self.file_key.lock().unwrap();
+                // a key read from a file made at another cost is not written with
+                if file_key.as_ref().map(|k| k.log_n()) != Some(self.log_n) {
+                    *file_key = Some(keyfile::FileKey::new(&self.passphrase, self.log_n)?);
+                }
+                let sealed = keyfile::seal_under(kind, key, file_key.as_ref().unwrap())?;
+                drop(file_key);
+                self.inner.write(p, k, &sealed)
+            }
+            None => self.inner.write(p, k, key),
+        }
+    }
+}
+
+impl<S> Drop for Encrypted<S> {
+    fn drop(&mut self) {
+        onion::erase(&mut self.passphrase);
+    }
+}
+
+/// A key store and the keys read from it so far: every public key, and the
+/// private keys of `own`, the party the process acts for, held so they are
+/// cleared when dropped. Other private keys and conversations are opened
+/// from the store each time they are asked for, and nothing is kept on a
+/// write. Each process has one, set with `configure_store`; tests make
+/// their own.
+pub struct Keys {
+    store: Box<dyn KeyStore>,
+    own: Option<Party>,
+    public: MemoryStore,
+    private: Mutex<HashMap<KeyType, onion::PrivateKey>>,
+}
+
+impl Keys {
+    pub fn new<S: KeyStore + 'static>(store: S, own: Option<Party>) -> Keys {
+        Keys {
+            store: Box::new(store),
+            own,
+            public: MemoryStore::new(),
+            private: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn put(&self, s: &Party, (sk, pk): onion::KeyPair) -> Result<()> {
+        self.save(s, KeyType::Public, &pk)?;
+        self.save(s, KeyType::Private, &sk)
+    }
+
+    /// Replace a contact's public key, after it announced a new one.
+    pub fn put_public(&self, s: &Party, pk: onion::PublicKey) -> Result<()> {
+        self.save(s, KeyType::Public, &pk)
+    }
+
+    pub fn get(&self, s: &Party) -> Result<onion::PublicKey> {
+        self.load_public(s, KeyType::Public)
+    }
+
+    pub fn get_keypair(&self, s: &Party) -> Result<onion::KeyPair> {
+        let pk = self.load_public(s, KeyType::Public)?;
+        let sk = self.load_private(s, KeyType::Private)?;
+        Ok((sk, pk))
+    }
+
+    /// Store a server's identity key pair, which signs its epoch keys.
+    pub fn put_identity(&self, s: &Party, (sk, pk): onion::KeyPair) -> Result<()> {
+        self.save(s, KeyType::IdentityPublic, &pk)?;
+        self.save(s, KeyType::IdentityPrivate, &sk)
+    }
+
+    pub fn get_identity(&self, s: &Party) -> Result<onion::PublicKey> {
+        self.load_public(s, KeyType::IdentityPublic)
+    }
+
+    pub fn get_identity_private(&self, s: &Party) -> Result<onion::PrivateKey> {
+        self.load_private(s, KeyType::IdentityPrivate)
+    }
+
+    /// Keep the ratchet state of client `uid`'s conversation with
+    /// `remote_uid`, sealed like a private key.
+    pub fn put_conversation(&self, uid: usize, remote_uid: usize, state: &[u8]) -> Result<()> {
+        self.store.write(&conversation(uid, remote_uid), KeyType::Conversation, state)
+    }
+
+    /// The state kept by `put_conversation`, `None` if the two have not
+    /// talked.
+    pub fn get_conversation(&self, uid: usize, remote_uid: usize) -> Result<Option<Vec<u8>>> {
+        match self.store.read(&conversation(uid, remote_uid), KeyType::Conversation) {
+            Ok(state) => Ok(Some(state)),
+            Err(Error::KeyIo(_, ref e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn load_public(&self, p: &Party, k: KeyType) -> Result<Vec<u8>> {
+        if let Ok(key) = self.public.read(p, k) {
+            return Ok(key);
+        }
+        let key = self.store.read(p, k)?;
+        self.public.write(p, k, &key)?;
+        Ok(key)
+    }
+
+    fn load_private(&self, p: &Party, k: KeyType) -> Result<onion::PrivateKey> {
+        let own = self.own.as_ref() == Some(p);
+        if own {
+            if let Some(sk) = self.private.lock().unwrap().get(&k) {
+                return Ok(sk.clone());
+            }
+        }
+        let sk = onion::PrivateKey::new(self.store.read(p, k)?);
+        if own {
+            self.private.lock().unwrap().insert(k, sk.clone());
+        }
+        Ok(sk)
+    }
+
+    // write through, dropping any copy read before so the next read sees
+    // the new key
+    fn save(&self, p: &Party, k: KeyType, key: &[u8]) -> Result<()> {
+        self.store.write(p, k, key)?;
+        match k.private_kind() {
+            Some(_) => {
+                if self.own.as_ref() == Some(p) {
+                    self.private.lock().unwrap().remove(&k);
+                }
+            }
+            None => {
+                self.public.keys.lock().unwrap().remove(&(p.clone(), k));
+            }
+        }
+        Ok(())
+    }
 }
 
-pub fn put(s: Party, (sk, pk): onion::KeyPair) -> io::Result<()> {
-    fs::write(path(&s, KeyType::Public), pk)?;
-    fs::write(path(&s, KeyType::Private), sk)?;
+lazy_static! {
+    // this process's keys, see `configure_store`
+    static ref KEYS: RwLock<Option<Arc<Keys>>> = RwLock::new(None);
+}
+
+/// Keep this process's keys in `store`, holding on to the private keys of
+/// `own` alone. Set once, before any key is read or written; a second
+/// store is refused rather than replacing the first.
+pub fn configure_store<S: KeyStore + 'static>(store: S, own: Option<Party>) -> Result<()> {
+    let mut keys = KEYS.write().unwrap();
+    if keys.is_some() {
+        return Err(Error::Crypto("key store already configured"));
+    }
+    *keys = Some(Arc::new(Keys::new(store, own)));
     Ok(())
 }
 
-pub fn get(s: Party) -> io::Result<onion::PublicKey> {
-    fs::read(path(&s, KeyType::Public))
+/// This process's keys, for the functions below and for code that would
+/// rather hold on to them.
+pub fn process_keys() -> Result<Arc<Keys>> {
+    match &*KEYS.read().unwrap() {
+        Some(keys) => Ok(keys.clone()),
+        None => Err(Error::Crypto("no key store configured")),
+    }
+}
+
+/// Read the passphrase from the first line of `file`, or ask for it on the
+/// terminal if `file` is empty, without echoing it. Every other copy read
+/// along the way is cleared.
+pub fn read_passphrase(file: &str) -> Result<Vec<u8>> {
+    let mut text = if file.is_empty() {
+        eprint!("Passphrase for the key files: ");
+        let line = read_hidden_line().map_err(|e| Error::KeyIo(PathBuf::from("<stdin>"), e));
+        eprintln!();
+        line?
+    } else {
+        let path = PathBuf::from(file);
+        fs::read(&path).map_err(|e| Error::KeyIo(path, e))?
+    };
+    let line = text.split(|&b| b == b'\n').next().unwrap_or(&[]);
+    let passphrase = match line.last() {
+        Some(b'\r') => line[..line.len() - 1].to_vec(),
+        _ => line.to_vec(),
+    };
+    onion::erase(&mut text);
+    Ok(passphrase)
+}
+
+// the longest passphrase typed on the terminal
+const MAX_PASSPHRASE: usize = 1024;
+
+// a line from stdin, with echo off if it is a terminal; read a byte at a time
+// rather than through `io::Stdin`, whose buffer would keep a copy
+fn read_hidden_line() -> io::Result<Vec<u8>> {
+    let fd = libc::STDIN_FILENO;
+    let mut term: libc::termios = unsafe { mem::zeroed() };
+    let tty = unsafe { libc::tcgetattr(fd, &mut term) } == 0;
+    if tty {
+        let mut quiet = term;
+        quiet.c_lflag &= !libc::ECHO;
+        if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &quiet) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    // never grown, so no copy is left behind in freed memory
+    let mut line = Vec::with_capacity(MAX_PASSPHRASE);
+    let res = loop {
+        let mut b = 0u8;
+        match unsafe { libc::read(fd, &mut b as *mut u8 as *mut libc::c_void, 1) } {
+            1 if b == b'\n' => break Ok(()),
+            1 if line.len() < MAX_PASSPHRASE => line.push(b),
+            1 => {
+                break Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "passphrase too long",
+                ))
+            }
+            0 => break Ok(()),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    break Err(e);
+                }
+            }
+        }
+    };
+    if tty {
+        unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &term) };
+    }
+    match res {
+        Ok(()) => Ok(line),
+        Err(e) => {
+            onion::erase(&mut line);
+            Err(e)
+        }
+    }
+}
+
+// where client `uid` keeps its conversation with `remote_uid`
+fn conversation(uid: usize, remote_uid: usize) -> Party {
+    PartyType::Client.named(&format!("{}-{}", uid, remote_uid))
+}
+
+// The process's keys, see `Keys`.
+
+pub fn put(s: Party, pair: onion::KeyPair) -> Result<()> {
+    process_keys()?.put(&s, pair)
+}
+
+pub fn put_public(s: Party, pk: onion::PublicKey) -> Result<()> {
+    process_keys()?.put_public(&s, pk)
+}
+
+pub fn get(s: Party) -> Result<onion::PublicKey> {
+    process_keys()?.get(&s)
+}
+
+pub fn get_keypair(s: Party) -> Result<onion::KeyPair> {
+    process_keys()?.get_keypair(&s)
+}
+
+pub fn put_identity(s: Party, pair: onion::KeyPair) -> Result<()> {
+    process_keys()?.put_identity(&s, pair)
+}
+
+pub fn get_identity(s: Party) -> Result<onion::PublicKey> {
+    process_keys()?.get_identity(&s)
+}
+
+pub fn get_identity_private(s: Party) -> Result<onion::PrivateKey> {
+    process_keys()?.get_identity_private(&s)
+}
+
+pub fn put_conversation(uid: usize, remote_uid: usize, state: &[u8]) -> Result<()> {
+    process_keys()?.put_conversation(uid, remote_uid, state)
+}
+
+pub fn get_conversation(uid: usize, remote_uid: usize) -> Result<Option<Vec<u8>>> {
+    process_keys()?.get_conversation(uid, remote_uid)
 }
 
-pub fn get_keypair(s: Party) -> io::Result<onion::KeyPair> {
-    let pk = fs::read(path(&s, KeyType::Public))?;
-    let sk = fs::read(path(&s, KeyType::Private))?;
-    Ok((sk, pk))
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn cheap<S: KeyStore>(inner: S) -> Encrypted<S> {
+        Encrypted {
+            inner,
+            passphrase: b"hunter2".to_vec(),
+            log_n: 4,
+            file_key: Mutex::new(None),
+        }
+    }
+
+    #[test]
+    fn files_under_their_root() {
+        let root = env::temp_dir().join(format!("vuvuzela-keys-{}", process::id()));
+        let store = FileStore::new(&root);
+        let alice = PartyType::Client.named("alice");
+        store.write(&alice, KeyType::Public, &[1; 32]).unwrap();
+        store.write(&alice, KeyType::Private, &[2; 32]).unwrap();
+        assert_eq!(store.read(&alice, KeyType::Public).unwrap(), vec![1; 32]);
+        assert_eq!(fs::read(root.join("client/alice.sk")).unwrap(), vec![2; 32]);
+        let mode = fs::metadata(root.join("client/alice.sk")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // replaced whole, leaving nothing beside it
+        store.write(&alice, KeyType::Private, &[3; 16]).unwrap();
+        assert_eq!(store.read(&alice, KeyType::Private).unwrap(), vec![3; 16]);
+        assert_eq!(fs::read_dir(root.join("client")).unwrap().count(), 2);
+        assert!(store.read(&PartyType::Server.with_id(0), KeyType::Public).is_err());
+        // names cannot leave the root
+        assert!(store.write(&PartyType::Client.named("../x"), KeyType::Public, &[1]).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn private_keys_sealed() {
+        let store = cheap(MemoryStore::new());
+        let bob = PartyType::Client.with_id(1);
+        store.write(&bob, KeyType::Public, &[1; 32]).unwrap();
+        store.write(&bob, KeyType::Private, &[2; 32]).unwrap();
+        assert_eq!(store.inner.read(&bob, KeyType::Public).unwrap(), vec![1; 32]);
+        assert_ne!(store.inner.read(&bob, KeyType::Private).unwrap(), vec![2; 32]);
+        assert_eq!(store.read(&bob, KeyType::Private).unwrap(), vec![2; 32]);
+
+        let wrong = Encrypted::new(MemoryStore::new(), b"hunter3".to_vec());
+        let sealed = store.inner.read(&bob, KeyType::Private).unwrap();
+        wrong.inner.write(&bob, KeyType::Private, &sealed).unwrap();
+        assert!(wrong.read(&bob, KeyType::Private).is_err());
+    }
+
+    #[test]
+    fn stores_side_by_side() {
+        let alice = PartyType::Client.named("alice");
+        let a = Keys::new(MemoryStore::new(), None);
+        let b = Keys::new(MemoryStore::new(), None);
+        a.put(&alice, (onion::PrivateKey::new(vec![1; 32]), vec![2; 32])).unwrap();
+        b.put(&alice, (onion::PrivateKey::new(vec![3; 32]), vec![4; 32])).unwrap();
+        assert_eq!(a.get(&alice).unwrap(), vec![2; 32]);
+        assert_eq!(b.get(&alice).unwrap(), vec![4; 32]);
+        assert!(Keys::new(MemoryStore::new(), None).get(&alice).is_err());
+    }
+
+    #[test]
+    fn written_keys_read_afresh() {
+        let alice = PartyType::Client.named("alice");
+        let keys = Keys::new(MemoryStore::new(), Some(alice.clone()));
+        keys.put(&alice, (onion::PrivateKey::new(vec![1; 32]), vec![2; 32])).unwrap();
+        assert_eq!(&keys.get_keypair(&alice).unwrap().0[..], &[1; 32][..]);
+        // a rotated key replaces the one held
+        keys.put(&alice, (onion::PrivateKey::new(vec![3; 32]), vec![4; 32])).unwrap();
+        let (sk, pk) = keys.get_keypair(&alice).unwrap();
+        assert_eq!((&sk[..], pk), (&[3; 32][..], vec![4; 32]));
+        assert_eq!(keys.get_conversation(1, 2).unwrap(), None);
+        keys.put_conversation(1, 2, &[5; 40]).unwrap();
+        assert_eq!(keys.get_conversation(1, 2).unwrap(), Some(vec![5; 40]));
+    }
 }
@@ -11,20 +11,42 @@ extern crate byteorder;
 extern crate tarpc;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 extern crate rand;
 extern crate rayon;
 extern crate ring;
+extern crate scrypt;
 
+pub mod batch;
 pub mod client_util;
+#[macro_use]
+pub mod conn;
 pub mod deaddrop_rpc;
+pub mod entry_rpc;
+pub mod epoch;
+pub mod error;
+pub mod frame;
+pub mod framed;
 pub mod head_rpc;
 pub mod int_rpc;
+#[cfg(feature = "hybrid")]
+pub mod kem;
+pub mod key_change;
+pub mod keyfile;
 pub mod keys;
 pub mod laplace;
 pub mod message;
+pub mod noise;
 pub mod onion;
 pub mod permute;
+pub mod pipeline;
+pub mod ratchet;
+pub mod session;
+pub mod shard_rpc;
+pub mod transfer;
 pub mod util;
+pub mod vectors;
+pub mod wire;
 
 pub const NUM_CLIENTS: usize = 1000;
 pub const NUM_SERVERS: usize = 3;
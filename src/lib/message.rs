@@ -1,5 +1,7 @@
 use crate::byteorder::{BigEndian, ByteOrder};
+use crate::error::{Error, Result};
 use crate::onion::{self, DerivedKey, EncryptionPurpose, Message, PublicKey};
+use crate::wire::Layer;
 use crate::rand::Rng;
 
 pub const RAW_SIZE: usize = 256;
@@ -8,34 +10,89 @@ lazy_static! {
     pub static ref CONTENT_SIZE: usize = RAW_SIZE + *onion::TAG_LEN;
 }
 
+/// Bytes in front of each layer's sealed part: the cipher suite code, the
+/// public key and, in a hybrid suite, the KEM ciphertext.
+pub fn header_size() -> usize {
+    1 + *onion::PK_LEN + onion::suite().kem_ct_len()
+}
+
+/// Bytes each server's layer adds to a message: its header and tag.
+pub fn layer_size() -> usize {
+    header_size() + *onion::TAG_LEN
+}
+
+/// Length of a packed message wrapped for `hops` servers.
+pub fn onion_size(hops: usize) -> usize {
+    *CONTENT_SIZE + 4 + hops * layer_size()
+}
+
 pub fn wrap(k: &PublicKey, m: &Message) -> Message {
-    let mut w = Vec::with_capacity(*onion::PK_LEN + m.len());
+    let mut w = Vec::with_capacity(header_size() + m.len());
+    w.push(onion::suite().code());
     w.extend(k);
     w.extend(m);
     w
 }
 
-pub fn unwrap(w: &Message) -> (PublicKey, Message) {
-    (w[..*onion::PK_LEN].to_vec(), w[*onion::PK_LEN..].to_vec())
+pub fn unwrap(w: &Message) -> Result<(PublicKey, Message)> {
+    let layer = Layer::split(w)?;
+    Ok((layer.pk.to_vec(), layer.sealed.to_vec()))
 }
 
-pub fn forward_onion_encrypt(pks: &Vec<PublicKey>, mut m: Message) -> (Vec<DerivedKey>, Message) {
+pub fn forward_onion_encrypt(
+    pks: &Vec<PublicKey>,
+    m: Message,
+) -> Result<(Vec<DerivedKey>, Message)> {
+    let mut sks = Vec::with_capacity(pks.len());
+    for _ in pks {
+        let (sk, _) = onion::keygen()?;
+        sks.push(sk);
+    }
+    forward_onion_encrypt_with(pks, &sks, m)
+}
+
+/// Like `forward_onion_encrypt`, with the ephemeral private key of each
+/// layer given rather than fresh. Only for reproducible output, such as the
+/// known-answer vectors; a real client must never reuse these keys.
+pub fn forward_onion_encrypt_with(
+    pks: &Vec<PublicKey>,
+    ephemeral_sks: &[onion::PrivateKey],
+    m: Message,
+) -> Result<(Vec<DerivedKey>, Message)> {
+    if ephemeral_sks.len() != pks.len() {
+        return Err(Error::Crypto("need one ephemeral key per server"));
+    }
     let mut dks = Vec::with_capacity(pks.len());
 
-    for pk_server in pks.iter().rev() {
-        let (sk, pk) = onion::keygen();
-        let dk = onion::derive(&sk, &pk_server);
-        let c = onion::encrypt(&dk, m, EncryptionPurpose::Forward);
-        m = wrap(&pk, &c);
+    // every layer is sealed in place in one buffer, innermost first:
+    // suite | pk_1 | ct_1 | (suite | pk_2 | ct_2 | (... m ...) tag_2) tag_1
+    // where the KEM ciphertexts ct_i are empty unless the suite is hybrid
+    let hops = pks.len();
+    let mut w = vec![0; m.len() + hops * layer_size()];
+    w[hops * header_size()..][..m.len()].copy_from_slice(&m);
+
+    let suite = onion::suite();
+    let mut inner = m.len();
+    for (i, pk_server) in pks.iter().enumerate().rev() {
+        let sk = &ephemeral_sks[i];
+        let pk = onion::public_key(sk)?;
+        let (dk, ct) = onion::layer_key_for(suite, sk, &pk_server)?;
+        let header = &mut w[i * header_size()..][..header_size()];
+        header[0] = suite.code();
+        header[1..][..*onion::PK_LEN].copy_from_slice(&pk);
+        header[1 + *onion::PK_LEN..].copy_from_slice(&ct);
+        let sealed = &mut w[(i + 1) * header_size()..][..inner + *onion::TAG_LEN];
+        onion::seal_in_place(&dk, sealed, EncryptionPurpose::Forward)?;
+        inner += layer_size();
         dks.push(dk);
     }
 
     dks.reverse();
 
-    (dks, m)
+    Ok((dks, w))
 }
 
-pub fn backward_onion_decrypt(dks: &Vec<DerivedKey>, mut c: Message) -> Result<Message, ()> {
+pub fn backward_onion_decrypt(dks: &Vec<DerivedKey>, mut c: Message) -> Result<Message> {
     for dk in dks.iter() {
         c = onion::decrypt(&dk, c, EncryptionPurpose::Backward)?;
     }
@@ -48,10 +105,10 @@ pub struct Deaddrop {
 }
 
 impl Deaddrop {
-    pub fn new(dk: &DerivedKey, info: &[u8]) -> Deaddrop {
+    pub fn new(dk: &DerivedKey, info: &[u8]) -> Result<Deaddrop> {
         let mut bytes: Vec<u8> = vec![0; 4];
-        dk.extract_and_expand(info, &mut bytes);
-        Deaddrop::from_bytes(&bytes)
+        dk.extract_and_expand(info, &mut bytes)?;
+        Ok(Deaddrop::from_bytes(&bytes))
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Deaddrop {
@@ -86,10 +143,38 @@ pub fn pack(m: &Vec<u8>, d: &Deaddrop) -> Message {
     p
 }
 
-pub fn unpack(w: Message) -> (Vec<u8>, Deaddrop) {
+/// Overwrite a message in place with a blank one for `d`. A buffer longer
+/// than a packed message is zeroed, and gets replaced by the next server
+/// when it fails to decrypt there.
+pub fn blank_into(buf: &mut [u8], d: &Deaddrop) {
+    for b in buf.iter_mut() {
+        *b = 0;
+    }
+    if buf.len() == *CONTENT_SIZE + 4 {
+        buf[*CONTENT_SIZE..].copy_from_slice(&d.bytes());
+    }
+}
+
+/// The deaddrop of a packed message, without copying it. Anything but a
+/// packed message gets a random deaddrop, so it is not exchanged.
+pub fn location_of(w: &[u8]) -> Deaddrop {
+    if w.len() != *CONTENT_SIZE + 4 {
+        return Deaddrop::sample();
+    }
+    Deaddrop::from_bytes(&w[*CONTENT_SIZE..])
+}
+
+pub fn unpack(w: Message) -> Result<(Vec<u8>, Deaddrop)> {
+    if w.len() != *CONTENT_SIZE + 4 {
+        return Err(Error::Framing(format!(
+            "packed message of {} bytes, expected {}",
+            w.len(),
+            *CONTENT_SIZE + 4
+        )));
+    }
     let m = w[..*CONTENT_SIZE].to_vec();
     let d = Deaddrop::from_bytes(&w[*CONTENT_SIZE..]);
-    (m, d)
+    Ok((m, d))
 }
 
 #[cfg(test)]
@@ -99,10 +184,10 @@ mod test {
 
     #[test]
     fn wrap_invertible() {
-        let (_sk, pk) = onion::keygen();
+        let (_sk, pk) = onion::keygen().unwrap();
         let m = "Hello, world!".as_bytes().to_vec();
         let w = wrap(&pk, &m);
-        let (pk_uw, m_uw) = unwrap(&w);
+        let (pk_uw, m_uw) = unwrap(&w).unwrap();
 
         assert_eq!(pk_uw, pk);
         assert_eq!(m, m_uw);
@@ -135,30 +220,48 @@ mod test {
         let m = vec![123; *CONTENT_SIZE];
         let d = Deaddrop::sample();
         let p = pack(&m, &d);
-        let (mm, dd) = unpack(p.clone());
+        let (mm, dd) = unpack(p.clone()).unwrap();
 
         assert_eq!(m, mm);
         assert_eq!(d, dd);
     }
 
+    #[test]
+    fn short_messages_rejected() {
+        assert!(unwrap(&vec![1, 2, 3]).is_err());
+        assert!(unpack(vec![0; *CONTENT_SIZE]).is_err());
+        // too short to hold a deaddrop, so it gets a random one
+        assert_ne!(location_of(&[0; 4]), location_of(&[0; 4]));
+    }
+
+    #[test]
+    fn onion_has_expected_size() {
+        let (_sk1, pk1) = onion::keygen().unwrap();
+        let (_sk2, pk2) = onion::keygen().unwrap();
+        let m = blank(&Deaddrop::sample());
+
+        let (_dks, w) = forward_onion_encrypt(&vec![pk1, pk2], m).unwrap();
+        assert_eq!(w.len(), onion_size(2));
+    }
+
     #[test]
     fn test_onion() {
-        let (sk1, pk1) = onion::keygen();
-        let (sk2, pk2) = onion::keygen();
+        let (sk1, pk1) = onion::keygen().unwrap();
+        let (sk2, pk2) = onion::keygen().unwrap();
 
         let m = "Hello, onions!".as_bytes().to_vec();
 
         // client encrypts
-        let (dks, w) = forward_onion_encrypt(&vec![pk1, pk2], m.clone());
+        let (dks, w) = forward_onion_encrypt(&vec![pk1, pk2], m.clone()).unwrap();
 
         // server 1 unwrap decrypt
-        let (pku, c) = unwrap(&w);
-        let d1 = onion::derive(&sk1, &pku);
+        let (pku, c) = unwrap(&w).unwrap();
+        let d1 = onion::derive(&sk1, &pku).unwrap();
         let w = onion::decrypt(&d1, c, EncryptionPurpose::Forward).unwrap();
 
         // server 2 unwrap decrypt
-        let (pku, c) = unwrap(&w);
-        let d2 = onion::derive(&sk2, &pku);
+        let (pku, c) = unwrap(&w).unwrap();
+        let d2 = onion::derive(&sk2, &pku).unwrap();
         let w = onion::decrypt(&d2, c, EncryptionPurpose::Forward).unwrap();
 
         assert_eq!(m, w);
@@ -166,10 +269,10 @@ mod test {
         let m = "Hello, client!".as_bytes().to_vec();
 
         // server 2 re-encrypts
-        let c = onion::encrypt(&d2, m.clone(), EncryptionPurpose::Backward);
+        let c = onion::encrypt(&d2, m.clone(), EncryptionPurpose::Backward).unwrap();
 
         // server 1 re-encrypts
-        let c = onion::encrypt(&d1, c, EncryptionPurpose::Backward);
+        let c = onion::encrypt(&d1, c, EncryptionPurpose::Backward).unwrap();
 
         // client decrypts
         let n = backward_onion_decrypt(&dks, c).unwrap();
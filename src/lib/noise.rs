@@ -0,0 +1,184 @@
+use crate::error::Result;
+use crate::message;
+use crate::onion;
+use crate::rayon::prelude::*;
+
+use std::cmp::min;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how long the refill thread backs off after failing to make noise
+const REFILL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How many noise onions to keep ready for a server adding Laplace(μ, b)
+/// noise twice per round; covers all but a vanishing fraction of rounds.
+pub fn target_for(micro: f64, scale: f64) -> usize {
+    f64::ceil(2. * (micro + 5. * scale)) as usize
+}
+
+/// Build `count` noise onions for the servers after us in the chain.
+pub fn generate(other_pks: &Vec<onion::PublicKey>, count: usize) -> Result<Vec<onion::Message>> {
+    (0..count)
+        .into_par_iter()
+        .map(|_| {
+            let m = message::blank(&message::Deaddrop::sample());
+            let (_dks, wrapped) = message::forward_onion_encrypt(other_pks, m)?;
+            Ok(wrapped)
+        })
+        .collect()
+}
+
+/// Noise onions prepared ahead of time, so a round only has to take the
+/// number it samples instead of encrypting them on the critical path.
+#[derive(Debug)]
+pub struct NoisePool {
+    other_pks: Vec<onion::PublicKey>,
+    target: usize,
+    pool: Mutex<Vec<onion::Message>>,
+    low: Condvar,
+    // set once the keys have changed, the refill thread then exits
+    stopped: AtomicBool,
+}
+
+impl NoisePool {
+    /// Start a pool kept topped up to `target` onions by a background thread.
+    pub fn start(other_pks: Vec<onion::PublicKey>, target: usize) -> Arc<NoisePool> {
+        let pool = Arc::new(NoisePool {
+            other_pks,
+            target,
+            pool: Mutex::new(Vec::with_capacity(target)),
+            low: Condvar::new(),
+            stopped: AtomicBool::new(false),
+        });
+
+        let refill = pool.clone();
+        thread::Builder::new()
+            .name("noise_refill".to_string())
+            .spawn(move || refill.refill_until_stopped())
+            .expect("Could not start the noise refill thread");
+        pool
+    }
+
+    /// Take `count` noise onions, making any the pool is short of on the spot.
+    pub fn take(&self, count: usize) -> Result<Vec<onion::Message>> {
+        let mut noise = {
+            let mut pool = self.pool.lock().unwrap();
+            let available = min(count, pool.len());
+            let rest = pool.len() - available;
+            let taken = pool.split_off(rest);
+            self.low.notify_one();
+            taken
+        };
+
+        if noise.len() < count {
+            println!(
+                "Noise pool short by {}, generating inline",
+                count - noise.len()
+            );
+            noise.extend(generate(&self.other_pks, count - noise.len())?);
+        }
+        Ok(noise)
+    }
+
+    /// Stop topping the pool up. What is in it can still be taken.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let _pool = self.pool.lock().unwrap();
+        self.low.notify_one();
+    }
+
+    fn refill_until_stopped(&self) {
+        loop {
+            let missing = {
+                let mut pool = self.pool.lock().unwrap();
+                while pool.len() >= self.target && !self.stopped.load(Ordering::SeqCst) {
+                    pool = self.low.wait(pool).unwrap();
+                }
+                if self.stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                self.target - pool.len()
+            };
+
+            // encrypt without holding the lock, so a round can take what is ready
+            let now = Instant::now();
+            let fresh = match generate(&self.other_pks, missing) {
+                Ok(fresh) => fresh,
+                Err(e) => {
+                    eprintln!("Could not refill the noise pool: {}", e);
+                    thread::sleep(REFILL_BACKOFF);
+                    continue;
+                }
+            };
+            self.pool.lock().unwrap().extend(fresh);
+            println!(
+                "NOISE REFILL TIME ELAPSED (ms): {}",
+                now.elapsed().as_millis()
+            );
+        }
+    }
+}
+
+/// A server's noise pool, started the first time a round asks for it.
+#[derive(Debug, Default)]
+pub struct SharedPool {
+    pool: Mutex<Option<Arc<NoisePool>>>,
+}
+
+impl SharedPool {
+    pub fn new() -> SharedPool {
+        SharedPool::default()
+    }
+
+    /// The pool for `other_pks`, replacing one made for the keys of an
+    /// earlier epoch, whose onions no server could open any more.
+    pub fn get_or_start(&self, other_pks: &Vec<onion::PublicKey>, target: usize) -> Arc<NoisePool> {
+        let mut pool = self.pool.lock().unwrap();
+        match &*pool {
+            Some(p) if p.other_pks == *other_pks => return p.clone(),
+            Some(p) => p.stop(),
+            None => (),
+        }
+        let fresh = NoisePool::start(other_pks.clone(), target);
+        *pool = Some(fresh.clone());
+        fresh
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn take_more_than_pooled() {
+        let (_sk, pk) = onion::keygen().unwrap();
+        let pool = NoisePool::start(vec![pk], 4);
+
+        let noise = pool.take(10).unwrap();
+        assert_eq!(noise.len(), 10);
+        let size = noise[0].len();
+        assert!(noise.iter().all(|m| m.len() == size));
+    }
+
+    #[test]
+    fn new_keys_new_pool() {
+        let shared = SharedPool::new();
+        let (_sk, pk) = onion::keygen().unwrap();
+        let first = shared.get_or_start(&vec![pk.clone()], 2);
+        assert!(Arc::ptr_eq(&first, &shared.get_or_start(&vec![pk], 2)));
+
+        let (_sk, pk) = onion::keygen().unwrap();
+        let second = shared.get_or_start(&vec![pk], 2);
+        assert!(!Arc::ptr_eq(&first, &second));
+        // the old pool can still be drained
+        assert_eq!(first.take(3).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn take_nothing() {
+        let pool = NoisePool::start(vec![], 2);
+        assert!(pool.take(0).unwrap().is_empty());
+    }
+}
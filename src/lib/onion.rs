@@ -1,21 +1,136 @@
 use crate::byteorder::{BigEndian, ByteOrder};
+use crate::error::{Error, Result};
+#[cfg(feature = "hybrid")]
+use crate::kem;
+use crate::ring::rand::SecureRandom;
 use crate::ring::{aead, agreement, digest, hkdf, rand};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Deref;
 use std::sync::Mutex;
 
-pub type PrivateKey = Vec<u8>; // bytes of sk
 pub type PublicKey = Vec<u8>; // bytes of pk
 pub type KeyPair = (PrivateKey, PublicKey);
 pub type Message = Vec<u8>;
 
+/// The algorithms protecting every layer of an onion. All servers of a
+/// chain use the same one, and its code leads each layer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum CipherSuite {
+    // X25519, AES-256-GCM and HKDF-SHA256
+    Aes256Gcm = 1,
+    // X25519, ChaCha20-Poly1305 and HKDF-SHA256, faster without AES instructions
+    ChaCha20Poly1305 = 2,
+    // X25519 and ML-KEM-768 together, AES-256-GCM and HKDF-SHA256, so a
+    // recorded round stays sealed as long as either holds
+    #[cfg(feature = "hybrid")]
+    HybridMlKem768 = 3,
+}
+
+impl CipherSuite {
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_code(code: u8) -> Option<CipherSuite> {
+        match code {
+            1 => Some(CipherSuite::Aes256Gcm),
+            2 => Some(CipherSuite::ChaCha20Poly1305),
+            #[cfg(feature = "hybrid")]
+            3 => Some(CipherSuite::HybridMlKem768),
+            _ => None,
+        }
+    }
+
+    /// The suite named on the command line, `aes256gcm`, `chacha20poly1305`
+    /// or, when built with the `hybrid` feature, `hybrid-mlkem768`.
+    pub fn from_name(name: &str) -> Option<CipherSuite> {
+        match name {
+            "aes256gcm" => Some(CipherSuite::Aes256Gcm),
+            "chacha20poly1305" => Some(CipherSuite::ChaCha20Poly1305),
+            #[cfg(feature = "hybrid")]
+            "hybrid-mlkem768" => Some(CipherSuite::HybridMlKem768),
+            _ => None,
+        }
+    }
+
+    /// Bytes a layer header carries after the ephemeral public key: the KEM
+    /// ciphertext in a hybrid suite, nothing otherwise.
+    pub fn kem_ct_len(self) -> usize {
+        match self {
+            CipherSuite::Aes256Gcm | CipherSuite::ChaCha20Poly1305 => 0,
+            #[cfg(feature = "hybrid")]
+            CipherSuite::HybridMlKem768 => kem::CT_LEN,
+        }
+    }
+
+    fn aead(self) -> &'static aead::Algorithm {
+        match self {
+            CipherSuite::Aes256Gcm => &aead::AES_256_GCM,
+            CipherSuite::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+            #[cfg(feature = "hybrid")]
+            CipherSuite::HybridMlKem768 => &aead::AES_256_GCM,
+        }
+    }
+}
+
+impl Default for CipherSuite {
+    fn default() -> CipherSuite {
+        CipherSuite::Aes256Gcm
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct DerivedKey {
+    suite: CipherSuite,
     secret: Vec<u8>,
     aead_key: Vec<u8>,
 }
 
-impl DerivedKey {
-    pub fn extract_and_expand(&self, info: &[u8], dest: &mut [u8]) {
-        extract_and_expand(&self.secret, info, dest);
+impl Drop for DerivedKey {
+    // so a key ratcheted past does not linger in freed memory
+    fn drop(&mut self) {
+        erase(&mut self.secret);
+        erase(&mut self.aead_key);
+    }
+}
+
+/// Bytes of a private key. Every copy is cleared when it is dropped, so
+/// keys handed out per round do not linger in freed memory.
+#[derive(Clone, Eq, PartialEq)]
+pub struct PrivateKey(Vec<u8>);
+
+impl PrivateKey {
+    pub fn new(sk: Vec<u8>) -> PrivateKey {
+        PrivateKey(sk)
+    }
+}
+
+impl Deref for PrivateKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PrivateKey({} bytes)", self.0.len())
+    }
+}
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        erase(&mut self.0);
+    }
+}
+
+/// Overwrite key material with zeros, in a way the compiler cannot skip
+/// because the memory is about to be freed.
+pub fn erase(k: &mut [u8]) {
+    for b in k.iter_mut() {
+        unsafe { std::ptr::write_volatile(b, 0) };
     }
 }
 
@@ -42,16 +157,32 @@ impl Into<[u8; aead::NONCE_LEN]> for EncryptionPurpose {
 }
 
 static AGREEMENT: &agreement::Algorithm = &agreement::X25519;
-static AEAD: &aead::Algorithm = &aead::AES_256_GCM;
+// HKDF info of an AEAD key, followed by the suite's code so no two suites
+// ever share key bytes
+const AEAD_INFO: &[u8] = b"vuvuzela aead key";
+// HKDF info of a hybrid layer's secret, before its KEM ciphertext and the
+// server's public key
+const HYBRID_INFO: &[u8] = b"vuvuzela hybrid layer";
 static DIGEST: &digest::Algorithm = &digest::SHA256;
 
 lazy_static! {
     static ref RNG: Mutex<rand::SystemRandom> = Mutex::new(rand::SystemRandom::new());
+    static ref SUITE: Mutex<CipherSuite> = Mutex::new(CipherSuite::default());
     pub static ref PK_LEN: usize = {
-        let (_sk, pk) = keygen();
+        let (_sk, pk) = keygen().expect("Could not generate a key pair");
         pk.len()
     };
-    pub static ref TAG_LEN: usize = { AEAD.tag_len() };
+    // the same for every suite, so message sizes do not depend on it
+    pub static ref TAG_LEN: usize = { CipherSuite::default().aead().tag_len() };
+}
+
+/// Set the cipher suite of the chain this process is part of.
+pub fn configure_suite(suite: CipherSuite) {
+    *SUITE.lock().unwrap() = suite;
+}
+
+pub fn suite() -> CipherSuite {
+    *SUITE.lock().unwrap()
 }
 
 macro_rules! rng {
@@ -60,36 +191,197 @@ macro_rules! rng {
     };
 }
 
-// TODO: pass errors up!
+impl DerivedKey {
+    pub fn extract_and_expand(&self, info: &[u8], dest: &mut [u8]) -> Result<()> {
+        extract_and_expand(&self.secret, info, dest)
+    }
+
+    /// The secret the key was made from, for a ratchet to carry forward.
+    pub fn secret(&self) -> &[u8] {
+        &self.secret
+    }
+
+    /// A key agreed with nobody, for a message whose sender sent a bad key;
+    /// whatever is sealed with it cannot be read by anyone.
+    pub fn sample() -> Result<DerivedKey> {
+        // as long as an X25519 shared secret
+        let mut secret = vec![0; 32];
+        rng!().fill(&mut secret)?;
+        DerivedKey::new(suite(), secret)
+    }
+
+    /// A key from a secret made some other way, under the chain's suite.
+    pub fn from_secret(secret: Vec<u8>) -> Result<DerivedKey> {
+        DerivedKey::new(suite(), secret)
+    }
+
+    fn new(suite: CipherSuite, secret: Vec<u8>) -> Result<DerivedKey> {
+        let mut aead_key = vec![0; suite.aead().key_len()];
+        let info = [AEAD_INFO, &[suite.code()]].concat();
+        extract_and_expand(&secret, &info, &mut aead_key)?;
+        Ok(DerivedKey {
+            suite,
+            secret,
+            aead_key,
+        })
+    }
+}
+
+pub fn keygen() -> Result<KeyPair> {
+    let keys = agreement::EphemeralPrivateKey::generate(AGREEMENT, rng!())
+        .map_err(|_| Error::Crypto("key generation failed"))?;
+
+    let pk = keys
+        .compute_public_key()
+        .map_err(|_| Error::Crypto("could not compute public key"))?
+        .as_ref()
+        .to_vec();
+    let sk = PrivateKey::new(keys.as_ref().to_vec());
+
+    Ok((sk, pk))
+}
+
+/// The public key of a private key, for keys made elsewhere.
+pub fn public_key(sk: &[u8]) -> Result<PublicKey> {
+    let key = agreement::EphemeralPrivateKey::new(AGREEMENT, sk)
+        .map_err(|_| Error::Crypto("malformed private key"))?;
+    Ok(key
+        .compute_public_key()
+        .map_err(|_| Error::Crypto("could not compute public key"))?
+        .as_ref()
+        .to_vec())
+}
+
+/// Agree on a key under the chain's cipher suite.
+pub fn derive(k1: &PrivateKey, k2: &[u8]) -> Result<DerivedKey> {
+    derive_for(suite(), k1, k2)
+}
+
+/// Agree on a key under `suite`, whatever the chain's is.
+pub fn derive_for(suite: CipherSuite, k1: &PrivateKey, k2: &[u8]) -> Result<DerivedKey> {
+    // process into well-distributed AEAD key
+    DerivedKey::new(suite, agree(k1, k2)?)
+}
+
+// a server's key is its X25519 key, followed by its KEM key if it has one
+fn split_key(k: &[u8]) -> Result<(&[u8], &[u8])> {
+    // X25519 private and public keys are the same length
+    if k.len() < *PK_LEN {
+        return Err(Error::Crypto("malformed server key"));
+    }
+    Ok(k.split_at(*PK_LEN))
+}
+
+/// The client's side of the key for one onion layer, sealed for the server
+/// with public key `server_pk`: the key, and the KEM ciphertext that goes in
+/// the layer header after `eph_sk`'s public key (empty unless hybrid).
+pub fn layer_key_for(
+    suite: CipherSuite,
+    eph_sk: &PrivateKey,
+    server_pk: &[u8],
+) -> Result<(DerivedKey, Vec<u8>)> {
+    let (x_pk, kem_pk) = split_key(server_pk)?;
+    let secret = agree(eph_sk, x_pk)?;
+    let (kem_secret, ct) = encapsulate(suite, kem_pk)?;
+    Ok((combine(suite, secret, kem_secret, &ct, server_pk)?, ct))
+}
+
+/// The server's side of `layer_key_for`, from its private key and the
+/// layer's ephemeral public key and KEM ciphertext.
+pub fn layer_key_from(
+    suite: CipherSuite,
+    server_sk: &PrivateKey,
+    eph_pk: &[u8],
+    kem_ct: &[u8],
+) -> Result<DerivedKey> {
+    let (x_sk, kem_sk) = split_key(server_sk)?;
+    let secret = agree(x_sk, eph_pk)?;
+    let kem_secret = decapsulate(suite, kem_sk, kem_ct)?;
+    // the server's public key, for a hybrid layer to be bound to
+    let server_pk = if kem_secret.is_empty() {
+        vec![]
+    } else {
+        [public_key(x_sk)?, kem_public_key(kem_sk)?].concat()
+    };
+    combine(suite, secret, kem_secret, kem_ct, &server_pk)
+}
+
+// A layer's key from its X25519 and KEM secrets. A hybrid layer needs both,
+// bound to the KEM ciphertext and the server's public key so the key is
+// only ever the one the client meant for that server; the other suites use
+// the X25519 secret as it is.
+fn combine(
+    suite: CipherSuite,
+    mut secret: Vec<u8>,
+    mut kem_secret: Vec<u8>,
+    kem_ct: &[u8],
+    server_pk: &[u8],
+) -> Result<DerivedKey> {
+    if kem_secret.is_empty() {
+        return DerivedKey::new(suite, secret);
+    }
+    let mut both = [&secret[..], &kem_secret[..]].concat();
+    erase(&mut secret);
+    erase(&mut kem_secret);
+    let mut combined = vec![0; 32];
+    let info = [HYBRID_INFO, kem_ct, server_pk].concat();
+    let res = extract_and_expand(&both, &info, &mut combined);
+    erase(&mut both);
+    res?;
+    DerivedKey::new(suite, combined)
+}
+
+#[cfg(feature = "hybrid")]
+fn encapsulate(suite: CipherSuite, kem_pk: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    match suite {
+        CipherSuite::HybridMlKem768 => kem::encapsulate(kem_pk),
+        _ => Ok((vec![], vec![])),
+    }
+}
+
+#[cfg(not(feature = "hybrid"))]
+fn encapsulate(_: CipherSuite, _: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    Ok((vec![], vec![]))
+}
+
+#[cfg(feature = "hybrid")]
+fn decapsulate(suite: CipherSuite, kem_sk: &[u8], ct: &[u8]) -> Result<Vec<u8>> {
+    match suite {
+        CipherSuite::HybridMlKem768 => kem::decapsulate(kem_sk, ct),
+        _ => Ok(vec![]),
+    }
+}
 
-pub fn keygen() -> (PrivateKey, PublicKey) {
-    let keys =
-        agreement::EphemeralPrivateKey::generate(AGREEMENT, rng!()).expect("Key agreement failed");
+#[cfg(not(feature = "hybrid"))]
+fn decapsulate(_: CipherSuite, _: &[u8], _: &[u8]) -> Result<Vec<u8>> {
+    Ok(vec![])
+}
 
-    let pk = keys.compute_public_key().unwrap().as_ref().to_vec();
-    let sk = keys.as_ref().to_vec();
+#[cfg(feature = "hybrid")]
+fn kem_public_key(kem_sk: &[u8]) -> Result<Vec<u8>> {
+    kem::public_key(kem_sk).map(|pk| pk.to_vec())
+}
 
-    (sk, pk)
+#[cfg(not(feature = "hybrid"))]
+fn kem_public_key(_: &[u8]) -> Result<Vec<u8>> {
+    Ok(vec![])
 }
 
-pub fn derive(k1: &PrivateKey, k2: &PublicKey) -> DerivedKey {
+// the X25519 shared secret of a private and a public key
+fn agree(k1: &[u8], k2: &[u8]) -> Result<Vec<u8>> {
     // key bytes to objects
     let upk = agreement::UnparsedPublicKey::new(AGREEMENT, k2);
-    let usk = agreement::EphemeralPrivateKey::new(AGREEMENT, k1).unwrap();
+    let usk = agreement::EphemeralPrivateKey::new(AGREEMENT, k1)
+        .map_err(|_| Error::Crypto("malformed private key"))?;
 
     // secret point from key exchange
     let secret =
         agreement::agree_ephemeral(&usk, &upk, ring::error::Unspecified, |s| Ok(s.to_vec()))
-            .expect("Key agreement failed");
-
-    // process into well-distributed AEAD key
-    let mut aead_key: Vec<u8> = vec![0; AEAD.key_len()];
-    extract_and_expand(&secret, &[], &mut aead_key);
-
-    DerivedKey { secret, aead_key }
+            .map_err(|_| Error::Crypto("key agreement failed"))?;
+    Ok(secret)
 }
 
-fn extract_and_expand(secret: &[u8], info: &[u8], dest: &mut [u8]) {
+fn extract_and_expand(secret: &[u8], info: &[u8], dest: &mut [u8]) -> Result<()> {
     lazy_static! {
         static ref SALT: hkdf::Salt = hkdf::Salt::new(DIGEST, &[]);
     }
@@ -97,38 +389,54 @@ fn extract_and_expand(secret: &[u8], info: &[u8], dest: &mut [u8]) {
     SALT.extract(secret)
         .expand(info)
         .fill(dest)
-        .expect("Could not extract and expand secret");
+        .map_err(|_| Error::Crypto("could not extract and expand secret"))
 }
 
-pub fn encrypt(k: &DerivedKey, m: Message, p: EncryptionPurpose) -> Message {
-    let sealing_key =
-        aead::SealingKey::new(AEAD, &k.aead_key).expect("Cannot encrypt using derived key.");
+pub fn encrypt(k: &DerivedKey, m: Message, p: EncryptionPurpose) -> Result<Message> {
+    let tag_len = k.suite.aead().tag_len();
+    let mut in_out: Vec<u8> = Vec::with_capacity(m.len() + tag_len);
+    in_out.extend(m);
+    in_out.extend(vec![0; tag_len]);
 
-    let nonce = aead::Nonce::assume_unique_for_key(p.into());
+    seal_in_place(k, &mut in_out, p)?;
+    Ok(in_out)
+}
 
-    let aad = aead::Aad::empty();
+pub fn decrypt(k: &DerivedKey, mut c: Message, p: EncryptionPurpose) -> Result<Message> {
+    let len = open_in_place(k, &mut c, p)?;
+    c.truncate(len);
+    Ok(c)
+}
 
-    let mut in_out: Vec<u8> = Vec::with_capacity(m.len() + AEAD.tag_len());
-    in_out.extend(m);
-    in_out.extend(vec![0; AEAD.tag_len()]);
+/// Encrypt the first `in_out.len() - TAG_LEN` bytes in place, writing the
+/// tag into the last `TAG_LEN` bytes.
+pub fn seal_in_place(k: &DerivedKey, in_out: &mut [u8], p: EncryptionPurpose) -> Result<()> {
+    let aead = k.suite.aead();
+    let sealing_key = aead::SealingKey::new(aead, &k.aead_key)
+        .map_err(|_| Error::Crypto("cannot encrypt using derived key"))?;
 
-    aead::seal_in_place(&sealing_key, nonce, aad, &mut in_out, AEAD.tag_len())
-        .expect("Encryption failed");
+    let nonce = aead::Nonce::assume_unique_for_key(p.into());
+
+    let aad = aead::Aad::empty();
 
-    in_out
+    aead::seal_in_place(&sealing_key, nonce, aad, in_out, aead.tag_len())
+        .map_err(|_| Error::Crypto("encryption failed"))?;
+    Ok(())
 }
 
-pub fn decrypt(k: &DerivedKey, mut c: Message, p: EncryptionPurpose) -> Result<Message, ()> {
-    let opening_key =
-        aead::OpeningKey::new(AEAD, &k.aead_key).expect("Cannot decrypt using derived key.");
+/// Decrypt a ciphertext and tag in place, leaving the plaintext at the start
+/// of `in_out` and returning its length.
+pub fn open_in_place(k: &DerivedKey, in_out: &mut [u8], p: EncryptionPurpose) -> Result<usize> {
+    let opening_key = aead::OpeningKey::new(k.suite.aead(), &k.aead_key)
+        .map_err(|_| Error::Crypto("cannot decrypt using derived key"))?;
 
     let nonce = aead::Nonce::assume_unique_for_key(p.into());
 
     let aad = aead::Aad::empty();
 
-    match aead::open_in_place(&opening_key, nonce, aad, 0, &mut c) {
-        Err(_) => Err(()),
-        Ok(result) => Ok(result.to_vec()),
+    match aead::open_in_place(&opening_key, nonce, aad, 0, in_out) {
+        Err(_) => Err(Error::Crypto("decryption failed")),
+        Ok(result) => Ok(result.len()),
     }
 }
 
@@ -138,44 +446,147 @@ mod test {
 
     #[test]
     fn keygen_randomized() {
-        let (_sk, pk1) = keygen();
-        let (_sk, pk2) = keygen();
+        let (_sk, pk1) = keygen().unwrap();
+        let (_sk, pk2) = keygen().unwrap();
 
         assert_ne!(pk1, pk2);
     }
 
     #[test]
     fn derive_commutes() {
-        let (sk1, pk1) = keygen();
-        let (sk2, pk2) = keygen();
-        let d1 = derive(&sk1, &pk2);
-        let d2 = derive(&sk2, &pk1);
+        let (sk1, pk1) = keygen().unwrap();
+        let (sk2, pk2) = keygen().unwrap();
+        let d1 = derive(&sk1, &pk2).unwrap();
+        let d2 = derive(&sk2, &pk1).unwrap();
 
         assert_eq!(d1, d2);
     }
 
     #[test]
     fn encrypt_invertible() {
-        let (sk1, _pk1) = keygen();
-        let (_sk2, pk2) = keygen();
-        let d = derive(&sk1, &pk2);
+        let (sk1, _pk1) = keygen().unwrap();
+        let (_sk2, pk2) = keygen().unwrap();
+        let d = derive(&sk1, &pk2).unwrap();
 
         let m = "Hello, world!".as_bytes().to_vec();
-        let c = encrypt(&d, m.clone(), EncryptionPurpose::Forward);
+        let c = encrypt(&d, m.clone(), EncryptionPurpose::Forward).unwrap();
         let m_dc = decrypt(&d, c, EncryptionPurpose::Forward).unwrap();
         assert_eq!(m, m_dc);
     }
 
     #[test]
     fn decrypt_can_fail() {
-        let (sk1, pk1) = keygen();
-        let (_sk2, pk2) = keygen();
-        let d1 = derive(&sk1, &pk2);
-        let d2 = derive(&sk1, &pk1);
+        let (sk1, pk1) = keygen().unwrap();
+        let (_sk2, pk2) = keygen().unwrap();
+        let d1 = derive(&sk1, &pk2).unwrap();
+        let d2 = derive(&sk1, &pk1).unwrap();
 
         let m = "Hello, world!".as_bytes().to_vec();
-        let c = encrypt(&d1, m, EncryptionPurpose::Forward);
-        let dc = decrypt(&d2, c, EncryptionPurpose::Forward);
-        assert_eq!(dc, Err(()));
+        let c = encrypt(&d1, m, EncryptionPurpose::Forward).unwrap();
+        match decrypt(&d2, c, EncryptionPurpose::Forward) {
+            Err(Error::Crypto(_)) => (),
+            other => panic!("expected a crypto error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn in_place_matches_owned() {
+        let (sk1, _pk1) = keygen().unwrap();
+        let (_sk2, pk2) = keygen().unwrap();
+        let d = derive(&sk1, &pk2).unwrap();
+
+        let m = "Hello, world!".as_bytes().to_vec();
+        let mut buf = m.clone();
+        buf.resize(m.len() + *TAG_LEN, 0);
+        seal_in_place(&d, &mut buf, EncryptionPurpose::Backward).unwrap();
+        assert_eq!(buf, encrypt(&d, m.clone(), EncryptionPurpose::Backward).unwrap());
+
+        let len = open_in_place(&d, &mut buf, EncryptionPurpose::Backward).unwrap();
+        assert_eq!(&buf[..len], &m[..]);
+    }
+
+    #[test]
+    fn public_key_matches_keygen() {
+        let (sk, pk) = keygen().unwrap();
+        assert_eq!(public_key(&sk).unwrap(), pk);
+    }
+
+    #[test]
+    fn layer_keys_agree() {
+        let (server_sk, server_pk) = keygen().unwrap();
+        let (eph_sk, eph_pk) = keygen().unwrap();
+        let (dk, ct) = layer_key_for(CipherSuite::Aes256Gcm, &eph_sk, &server_pk).unwrap();
+        assert!(ct.is_empty());
+        // the same as a plain agreement outside a hybrid suite
+        assert_eq!(dk, derive(&eph_sk, &server_pk).unwrap());
+        let opened = layer_key_from(CipherSuite::Aes256Gcm, &server_sk, &eph_pk, &ct).unwrap();
+        assert_eq!(dk, opened);
+    }
+
+    #[cfg(feature = "hybrid")]
+    #[test]
+    fn hybrid_layer_keys_agree() {
+        let suite = CipherSuite::HybridMlKem768;
+        let (x_sk, x_pk) = keygen().unwrap();
+        let (kem_sk, kem_pk) = kem::keygen().unwrap();
+        let server_sk = PrivateKey::new([&x_sk[..], &kem_sk[..]].concat());
+        let server_pk = [x_pk.clone(), kem_pk].concat();
+        let (eph_sk, eph_pk) = keygen().unwrap();
+
+        let (dk, ct) = layer_key_for(suite, &eph_sk, &server_pk).unwrap();
+        assert_eq!(ct.len(), suite.kem_ct_len());
+        assert_eq!(layer_key_from(suite, &server_sk, &eph_pk, &ct).unwrap(), dk);
+        // the same secrets give another key for another server's key
+        let secret = agree(&x_sk, &eph_pk).unwrap();
+        let kem_secret = kem::decapsulate(&kem_sk, &ct).unwrap();
+        assert_ne!(combine(suite, secret, kem_secret, &ct, &x_pk).unwrap(), dk);
+        // breaking X25519 alone is not enough
+        assert_ne!(dk, derive_for(suite, &eph_sk, &x_pk).unwrap());
+        // nor is a server without its KEM key
+        assert!(layer_key_for(suite, &eph_sk, &x_pk).is_err());
+    }
+
+    #[test]
+    fn suites_never_share_keys() {
+        let (sk1, _pk1) = keygen().unwrap();
+        let (_sk2, pk2) = keygen().unwrap();
+        let aes = derive_for(CipherSuite::Aes256Gcm, &sk1, &pk2).unwrap();
+        let chacha = derive_for(CipherSuite::ChaCha20Poly1305, &sk1, &pk2).unwrap();
+        assert_eq!(aes.secret, chacha.secret);
+        assert_ne!(aes.aead_key, chacha.aead_key);
+    }
+
+    #[test]
+    fn derive_rejects_bad_public_key() {
+        let (sk, _pk) = keygen().unwrap();
+        assert!(derive(&sk, &[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn suites_do_not_mix() {
+        let (sk1, _pk1) = keygen().unwrap();
+        let (_sk2, pk2) = keygen().unwrap();
+        let aes = derive_for(CipherSuite::Aes256Gcm, &sk1, &pk2).unwrap();
+        let chacha = derive_for(CipherSuite::ChaCha20Poly1305, &sk1, &pk2).unwrap();
+
+        let m = "Hello, world!".as_bytes().to_vec();
+        let c = encrypt(&chacha, m.clone(), EncryptionPurpose::Forward).unwrap();
+        assert_eq!(c.len(), m.len() + *TAG_LEN);
+        assert!(decrypt(&aes, c.clone(), EncryptionPurpose::Forward).is_err());
+        assert_eq!(decrypt(&chacha, c, EncryptionPurpose::Forward).unwrap(), m);
+    }
+
+    #[test]
+    fn suite_codes_invertible() {
+        for s in &[CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305] {
+            assert_eq!(CipherSuite::from_code(s.code()), Some(*s));
+        }
+        assert_eq!(CipherSuite::from_code(0), None);
+        assert_eq!(CipherSuite::from_name("chacha20poly1305"), Some(CipherSuite::ChaCha20Poly1305));
+    }
+
+    #[test]
+    fn sampled_keys_differ() {
+        assert_ne!(DerivedKey::sample().unwrap(), DerivedKey::sample().unwrap());
     }
 }
@@ -1,46 +1,256 @@
+use crate::batch::Batch;
 use crate::rand::prelude::SliceRandom;
+use crate::rayon::prelude::*;
 
-use std::iter;
-use std::time::Instant;
+use std::ptr;
+use std::slice;
+
+/// Longest stretch of a cycle one thread moves on its own. A random
+/// permutation has a cycle covering most of its elements, so cycles are cut
+/// into segments for the threads to share.
+const SEGMENT_LEN: usize = 1 << 12;
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct Permutation {
     map: Vec<usize>,
 }
 
+/// Part of a cycle: `len` positions following the map from `start`, after
+/// which the cycle carries on at `next`, the start of the next segment.
+#[derive(Debug)]
+struct Segment {
+    start: usize,
+    len: usize,
+    next: usize,
+}
+
+/// Elements moved around in place by several threads at once. Every thread
+/// only touches the positions of its own segments, and each segment's start
+/// is read out before any thread writes, so no position is ever accessed by
+/// two threads at the same time.
+trait Slots: Sync {
+    type Saved: Send;
+    unsafe fn read(&self, i: usize) -> Self::Saved;
+    unsafe fn write(&self, i: usize, v: Self::Saved);
+    unsafe fn copy(&self, dst: usize, src: usize);
+}
+
+struct Elements<T> {
+    ptr: *mut T,
+}
+
+unsafe impl<T: Send> Sync for Elements<T> {}
+
+impl<T: Send> Slots for Elements<T> {
+    type Saved = T;
+
+    unsafe fn read(&self, i: usize) -> T {
+        ptr::read(self.ptr.add(i))
+    }
+
+    unsafe fn write(&self, i: usize, v: T) {
+        ptr::write(self.ptr.add(i), v)
+    }
+
+    unsafe fn copy(&self, dst: usize, src: usize) {
+        ptr::copy_nonoverlapping(self.ptr.add(src), self.ptr.add(dst), 1)
+    }
+}
+
+struct Strided {
+    ptr: *mut u8,
+    stride: usize,
+}
+
+unsafe impl Sync for Strided {}
+
+impl Slots for Strided {
+    type Saved = Vec<u8>;
+
+    unsafe fn read(&self, i: usize) -> Vec<u8> {
+        slice::from_raw_parts(self.ptr.add(i * self.stride), self.stride).to_vec()
+    }
+
+    unsafe fn write(&self, i: usize, v: Vec<u8>) {
+        ptr::copy_nonoverlapping(v.as_ptr(), self.ptr.add(i * self.stride), self.stride)
+    }
+
+    unsafe fn copy(&self, dst: usize, src: usize) {
+        ptr::copy_nonoverlapping(
+            self.ptr.add(src * self.stride),
+            self.ptr.add(dst * self.stride),
+            self.stride,
+        )
+    }
+}
+
 impl Permutation {
     pub fn sample(m: usize) -> Permutation {
-        let now = Instant::now();
         let mut map: Vec<usize> = (0..m).collect();
         map.shuffle(&mut rand::thread_rng());
-        println!("Sample took (ms): {}", now.elapsed().as_millis());
         Permutation { map }
     }
 
     pub fn inverse(&self) -> Permutation {
-        let now = Instant::now();
-        let mut map: Vec<usize> = iter::repeat(0).take(self.map.len()).collect();
-        for (i, j) in self.map.iter().enumerate() {
-            map[*j] = i;
-        }
-        println!("Inverse took (ms): {}", now.elapsed().as_millis());
+        let mut map: Vec<usize> = (0..self.map.len()).into_par_iter().collect();
+        self.apply_inverse_in_place(&mut map);
         Permutation { map }
     }
 
-    pub fn apply<T>(&self, input: Vec<T>) -> Vec<T> {
-        let now = Instant::now();
-        let mut tmp: Vec<Option<T>> = Vec::with_capacity(input.len());
-        for x in input {
-            tmp.push(Some(x));
-        }
+    /// Turn this permutation into its inverse without a second map.
+    pub fn invert(&mut self) {
+        let segments = self.segments();
+        // the position after each segment's start, before anything is overwritten
+        let seconds: Vec<usize> = segments.iter().map(|s| self.map[s.start]).collect();
+        let map = Elements {
+            ptr: self.map.as_mut_ptr(),
+        };
+
+        segments
+            .par_iter()
+            .zip(seconds.par_iter())
+            .for_each(|(seg, &second)| unsafe {
+                // positions c_1..c_len of this segment get map[c_t+1] = c_t
+                let mut prev = seg.start;
+                let mut cur = second;
+                for t in 0..seg.len {
+                    let next = if t + 1 < seg.len { map.read(cur) } else { 0 };
+                    map.write(cur, prev);
+                    prev = cur;
+                    cur = next;
+                }
+            });
+    }
+
+    /// Output position `i` gets input element `map[i]`.
+    pub fn apply<T: Send>(&self, mut input: Vec<T>) -> Vec<T> {
+        self.apply_in_place(&mut input);
+        input
+    }
+
+    pub fn apply_in_place<T: Send>(&self, v: &mut [T]) {
+        assert_eq!(v.len(), self.map.len());
+        self.gather(&Elements { ptr: v.as_mut_ptr() });
+    }
+
+    /// Undo `apply_in_place`, without building the inverse map.
+    pub fn apply_inverse_in_place<T: Send>(&self, v: &mut [T]) {
+        assert_eq!(v.len(), self.map.len());
+        self.scatter(&Elements { ptr: v.as_mut_ptr() });
+    }
+
+    pub fn apply_batch(&self, b: &mut Batch) {
+        assert_eq!(b.len(), self.map.len());
+        let stride = b.stride();
+        self.gather(&Strided {
+            ptr: b.as_bytes_mut().as_mut_ptr(),
+            stride,
+        });
+    }
 
-        let mut output: Vec<T> = Vec::with_capacity(tmp.len());
-        for i in &self.map {
-            tmp.push(None);
-            output.push(tmp.swap_remove(*i).unwrap());
+    pub fn apply_inverse_batch(&self, b: &mut Batch) {
+        assert_eq!(b.len(), self.map.len());
+        let stride = b.stride();
+        self.scatter(&Strided {
+            ptr: b.as_bytes_mut().as_mut_ptr(),
+            stride,
+        });
+    }
+
+    // slot[c_t] = slot[c_t+1] along every cycle
+    fn gather<S: Slots>(&self, slots: &S) {
+        let segments = self.segments();
+        // each segment ends with the element at the start of the next one
+        let saved: Vec<S::Saved> = segments
+            .par_iter()
+            .map(|seg| unsafe { slots.read(seg.next) })
+            .collect();
+
+        segments
+            .into_par_iter()
+            .zip(saved.into_par_iter())
+            .for_each(|(seg, last)| unsafe {
+                let mut cur = seg.start;
+                for _ in 1..seg.len {
+                    let src = self.map[cur];
+                    slots.copy(cur, src);
+                    cur = src;
+                }
+                slots.write(cur, last);
+            });
+    }
+
+    // slot[c_t+1] = slot[c_t] along every cycle
+    fn scatter<S: Slots>(&self, slots: &S) {
+        let segments = self.segments();
+        // each segment's first element goes to the second position, but the
+        // segment before writes over the first before we get to it
+        let saved: Vec<S::Saved> = segments
+            .par_iter()
+            .map(|seg| unsafe { slots.read(seg.start) })
+            .collect();
+
+        // one buffer for the positions of a segment, reused for every
+        // segment a worker takes on
+        segments
+            .into_par_iter()
+            .zip(saved.into_par_iter())
+            .for_each_init(
+                || Vec::with_capacity(SEGMENT_LEN + 1),
+                |positions, (seg, first)| unsafe {
+                    positions.clear();
+                    let mut cur = seg.start;
+                    for _ in 0..seg.len {
+                        positions.push(cur);
+                        cur = self.map[cur];
+                    }
+                    positions.push(seg.next);
+
+                    for t in (1..seg.len).rev() {
+                        slots.copy(positions[t + 1], positions[t]);
+                    }
+                    slots.write(positions[1], first);
+                },
+            );
+    }
+
+    // cut every cycle into segments of at most SEGMENT_LEN positions
+    fn segments(&self) -> Vec<Segment> {
+        let n = self.map.len();
+        let mut visited = vec![0u64; (n + 63) / 64];
+        let mut segments = Vec::with_capacity(n / SEGMENT_LEN + 1);
+
+        for i in 0..n {
+            // fixed points stay put, and need no saved element
+            if visited[i / 64] & (1 << (i % 64)) != 0 || self.map[i] == i {
+                continue;
+            }
+            let first = segments.len();
+            let mut cur = i;
+            loop {
+                let start = cur;
+                let mut len = 0;
+                while len < SEGMENT_LEN {
+                    visited[cur / 64] |= 1 << (cur % 64);
+                    len += 1;
+                    cur = self.map[cur];
+                    if cur == i {
+                        break;
+                    }
+                }
+                segments.push(Segment {
+                    start,
+                    len,
+                    next: cur,
+                });
+                if cur == i {
+                    break;
+                }
+            }
+            // the last segment of a cycle leads back to its first
+            segments.last_mut().unwrap().next = segments[first].start;
         }
-        println!("Apply took (ms): {}", now.elapsed().as_millis());
-        output
+        segments
     }
 
     #[cfg(test)]
@@ -85,4 +295,50 @@ mod test {
 
         assert_ne!(pi, rho);
     }
+
+    // big enough that the long cycles are cut into several segments
+    const LARGE: usize = 5 * SEGMENT_LEN + 17;
+
+    #[test]
+    fn large_apply_matches_definition() {
+        let pi = Permutation::sample(LARGE);
+        let v: Vec<usize> = (0..LARGE).map(|i| i * 7).collect();
+        let w = pi.apply(v.clone());
+
+        for i in 0..LARGE {
+            assert_eq!(w[i], v[pi.map[i]]);
+        }
+    }
+
+    #[test]
+    fn large_inverse_undoes_apply() {
+        let pi = Permutation::sample(LARGE);
+        let v: Vec<String> = (0..LARGE).map(|i| i.to_string()).collect();
+        let mut w = pi.apply(v.clone());
+        pi.apply_inverse_in_place(&mut w);
+
+        assert_eq!(w, v);
+    }
+
+    #[test]
+    fn invert_in_place_matches_inverse() {
+        let mut pi = Permutation::sample(LARGE);
+        let rho = pi.inverse();
+        pi.invert();
+
+        assert_eq!(pi, rho);
+    }
+
+    #[test]
+    fn batch_matches_vec() {
+        let pi = Permutation::sample(LARGE);
+        let v: Vec<Vec<u8>> = (0..LARGE).map(|i| vec![i as u8, (i >> 8) as u8]).collect();
+        let mut b = Batch::from_messages(2, &v);
+
+        pi.apply_batch(&mut b);
+        assert_eq!(b.to_messages(), pi.apply(v.clone()));
+
+        pi.apply_inverse_batch(&mut b);
+        assert_eq!(b.to_messages(), v);
+    }
 }
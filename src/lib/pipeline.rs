@@ -0,0 +1,360 @@
+use crate::batch::Batch;
+use crate::error::{Error, Result, RoundError};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How long each phase of a round may take before the round is aborted,
+/// unless configured otherwise.
+pub const DEFAULT_PHASE_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref PHASE_TIMEOUT: Mutex<Duration> = Mutex::new(DEFAULT_PHASE_TIMEOUT);
+}
+
+/// Set how long each phase of a round may take on this server.
+pub fn configure_timeout(timeout: Duration) {
+    *PHASE_TIMEOUT.lock().unwrap() = timeout;
+}
+
+pub fn phase_timeout() -> Duration {
+    *PHASE_TIMEOUT.lock().unwrap()
+}
+
+/// A piece of a round's messages that can be joined back up with the rest.
+pub trait Chunk: Sized {
+    /// Join chunks in order; no chunks gives an empty one.
+    fn concat(chunks: Vec<Self>) -> Self;
+}
+
+impl<T> Chunk for Vec<T> {
+    fn concat(chunks: Vec<Self>) -> Self {
+        let mut all = Vec::with_capacity(chunks.iter().map(|c| c.len()).sum());
+        for c in chunks {
+            all.extend(c);
+        }
+        all
+    }
+}
+
+impl Chunk for Batch {
+    fn concat(chunks: Vec<Self>) -> Self {
+        let (stride, len) = match chunks.first() {
+            Some(c) => (c.stride(), chunks.iter().map(|c| c.len()).sum()),
+            None => return Batch::default(),
+        };
+        let mut all = Batch::with_capacity(stride, len);
+        for c in chunks {
+            all.append(c);
+        }
+        all
+    }
+}
+
+impl<A: Chunk, B: Chunk> Chunk for (A, B) {
+    fn concat(chunks: Vec<Self>) -> Self {
+        let (a, b): (Vec<A>, Vec<B>) = chunks.into_iter().unzip();
+        (A::concat(a), B::concat(b))
+    }
+}
+
+/// How many rounds a server holds chunks for at once. Chunks for rounds
+/// further apart than this are refused, so a neighbour cannot make a server
+/// buffer without bound; the head server's `--pipeline` must stay below it.
+pub const MAX_IN_FLIGHT: u32 = 16;
+
+/// Chunks received for each round still in flight, so that one round's
+/// forward pass can overlap with an earlier round's backward pass. Chunks
+/// may arrive in any order and are put back in order by their offset.
+#[derive(Debug)]
+pub struct RoundBuffers<C = Batch> {
+    rounds: Mutex<Rounds<C>>,
+}
+
+#[derive(Debug)]
+struct Rounds<C> {
+    chunks: HashMap<u32, BTreeMap<u32, C>>,
+    // the newest round taken or discarded, the window ends MAX_IN_FLIGHT
+    // rounds either side of it
+    newest: Option<u32>,
+    // rounds of the window taken or discarded, chunks still arriving for
+    // them are dropped
+    finished: BTreeSet<u32>,
+}
+
+impl<C: Chunk> RoundBuffers<C> {
+    pub fn new() -> RoundBuffers<C> {
+        RoundBuffers {
+            rounds: Mutex::new(Rounds {
+                chunks: HashMap::new(),
+                newest: None,
+                finished: BTreeSet::new(),
+            }),
+        }
+    }
+
+    /// Store the chunk starting at message `offset` of `round`. A chunk for
+    /// a round already taken or discarded is dropped; one for a round
+    /// outside the window, or beyond `MAX_IN_FLIGHT` rounds held, is refused.
+    pub fn insert(&self, round: u32, offset: u32, v: C) -> Result<()> {
+        let mut rounds = self.lock();
+        if rounds.finished.contains(&round) {
+            return Ok(());
+        }
+        let outside = match rounds.newest {
+            Some(n) => round.saturating_add(MAX_IN_FLIGHT) <= n || round >= n + MAX_IN_FLIGHT,
+            None => false,
+        };
+        let full = !rounds.chunks.contains_key(&round)
+            && rounds.chunks.len() >= MAX_IN_FLIGHT as usize;
+        if outside || full {
+            return Err(Error::Round(round, RoundError::OutOfWindow));
+        }
+        rounds
+            .chunks
+            .entry(round)
+            .or_insert_with(BTreeMap::new)
+            .insert(offset, v);
+        Ok(())
+    }
+
+    /// Remove and return everything received for `round`, in order.
+    /// Chunks that still arrive for it are dropped.
+    pub fn take(&self, round: u32) -> C {
+        let chunks = {
+            let mut rounds = self.lock();
+            let newest = rounds.newest.map_or(round, |n| n.max(round));
+            rounds.newest = Some(newest);
+            rounds.finished.insert(round);
+            // rounds left behind the window are forgotten
+            let behind = |r: u32| r.saturating_add(MAX_IN_FLIGHT) <= newest;
+            rounds.finished.retain(|&r| !behind(r));
+            rounds.chunks.retain(|&r, _| !behind(r));
+            rounds.chunks.remove(&round).unwrap_or_default()
+        };
+        C::concat(chunks.into_iter().map(|(_, c)| c).collect())
+    }
+
+    /// Drop everything received for an aborted round, and anything that
+    /// still arrives for it.
+    pub fn discard(&self, round: u32) -> C {
+        self.take(round)
+    }
+
+    fn lock(&self) -> MutexGuard<Rounds<C>> {
+        match self.rounds.lock() {
+            Err(e) => e.into_inner(),
+            Ok(o) => o,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Signal {
+    Ended,
+    Aborted,
+}
+
+/// Rounds a neighbouring server has signalled as done, or as aborted.
+#[derive(Debug, Default)]
+pub struct RoundSignals {
+    signals: Mutex<HashMap<u32, Signal>>,
+    cvar: Condvar,
+    // rounds already waited on, signals arriving late for them are dropped
+    finished: RoundOnce,
+}
+
+impl RoundSignals {
+    pub fn new() -> RoundSignals {
+        RoundSignals::default()
+    }
+
+    pub fn signal(&self, round: u32) {
+        let mut signals = self.signals.lock().unwrap();
+        if self.finished.contains(round) {
+            return;
+        }
+        // an abort wins over a late end
+        signals.entry(round).or_insert(Signal::Ended);
+        self.cvar.notify_all();
+    }
+
+    /// Wake up whoever waits on `round` with an error.
+    pub fn abort(&self, round: u32) {
+        let mut signals = self.signals.lock().unwrap();
+        if self.finished.contains(round) {
+            return;
+        }
+        signals.insert(round, Signal::Aborted);
+        self.cvar.notify_all();
+    }
+
+    /// Block until `round` is signalled, consuming the signal. Fails if the
+    /// round is aborted or `timeout` passes first.
+    pub fn wait(&self, round: u32, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut signals = self.signals.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let signal = signals.remove(&round);
+            if signal.is_none() && now < deadline {
+                let (s, _) = self.cvar.wait_timeout(signals, deadline - now).unwrap();
+                signals = s;
+                continue;
+            }
+            self.finished.first(round);
+            return match signal {
+                Some(Signal::Ended) => Ok(()),
+                Some(Signal::Aborted) => Err(Error::Round(round, RoundError::Aborted)),
+                None => Err(Error::Round(round, RoundError::TimedOut)),
+            };
+        }
+    }
+}
+
+/// Rounds a request has already been acted on for, so one repeated after
+/// a reconnect is ignored.
+#[derive(Debug, Default)]
+pub struct RoundOnce {
+    seen: Mutex<BTreeSet<u32>>,
+}
+
+impl RoundOnce {
+    // rounds remembered, far more than are ever in flight
+    const KEEP: usize = 1024;
+
+    pub fn new() -> RoundOnce {
+        RoundOnce::default()
+    }
+
+    /// True the first time it is called for `round`.
+    pub fn first(&self, round: u32) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.insert(round) {
+            return false;
+        }
+        while seen.len() > RoundOnce::KEEP {
+            let oldest = *seen.iter().next().unwrap();
+            seen.remove(&oldest);
+        }
+        true
+    }
+
+    /// Whether `first` has been called for `round`.
+    pub fn contains(&self, round: u32) -> bool {
+        self.seen.lock().unwrap().contains(&round)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn buffers_keep_rounds_apart() {
+        let b = RoundBuffers::new();
+        b.insert(1, 0, vec![vec![1]]).unwrap();
+        b.insert(2, 0, vec![vec![2]]).unwrap();
+        b.insert(1, 1, vec![vec![3]]).unwrap();
+
+        assert_eq!(b.take(1), vec![vec![1], vec![3]]);
+        assert_eq!(b.take(1), Vec::<Vec<u8>>::new());
+        assert_eq!(b.take(2), vec![vec![2]]);
+        // late chunks for a round taken are dropped
+        b.insert(1, 2, vec![vec![4]]).unwrap();
+        assert_eq!(b.take(1), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn buffers_reorder_chunks() {
+        let b = RoundBuffers::new();
+        b.insert(0, 2, vec!['c']).unwrap();
+        b.insert(0, 0, vec!['a', 'b']).unwrap();
+
+        assert_eq!(b.take(0), vec!['a', 'b', 'c']);
+    }
+
+    #[test]
+    fn buffers_join_batches_and_keys() {
+        let b = RoundBuffers::new();
+        b.insert(3, 1, (vec!['b'], Batch::from_messages(1, &vec![vec![2]]))).unwrap();
+        b.insert(3, 0, (vec!['a'], Batch::from_messages(1, &vec![vec![1]]))).unwrap();
+
+        let (keys, batch) = b.take(3);
+        assert_eq!(keys, vec!['a', 'b']);
+        assert_eq!(batch.to_messages(), vec![vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn round_once_only_once() {
+        let once = RoundOnce::new();
+        assert!(once.first(3));
+        assert!(!once.first(3));
+        assert!(once.first(4));
+    }
+
+    #[test]
+    fn signals_wake_the_right_round() {
+        let s = Arc::new(RoundSignals::new());
+        let waiter = {
+            let s = s.clone();
+            thread::spawn(move || s.wait(5, Duration::from_secs(10)))
+        };
+        s.signal(4);
+        s.signal(5);
+        waiter.join().unwrap().unwrap();
+
+        // round 4's signal is still there for its own waiter
+        s.wait(4, Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
+    fn signals_abort_and_time_out() {
+        let s = RoundSignals::new();
+        s.abort(1);
+        s.signal(1);
+        match s.wait(1, Duration::from_secs(10)) {
+            Err(Error::Round(1, RoundError::Aborted)) => {}
+            other => panic!("expected round 1 to be aborted, got {:?}", other),
+        }
+
+        match s.wait(2, Duration::from_millis(10)) {
+            Err(Error::Round(2, RoundError::TimedOut)) => {}
+            other => panic!("expected round 2 to time out, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn discarded_rounds_drop_late_chunks() {
+        let b = RoundBuffers::new();
+        b.insert(1, 0, vec![1]).unwrap();
+        b.insert(2, 0, vec![2]).unwrap();
+        assert_eq!(b.discard(1), vec![1]);
+
+        b.insert(1, 1, vec![3]).unwrap();
+        assert_eq!(b.take(1), Vec::<u8>::new());
+        assert_eq!(b.take(2), vec![2]);
+    }
+
+    #[test]
+    fn buffers_refuse_rounds_out_of_the_window() {
+        let b = RoundBuffers::new();
+        for r in 0..MAX_IN_FLIGHT {
+            b.insert(100 + r, 0, vec![r]).unwrap();
+        }
+        // no more rounds at once than can be in flight
+        assert!(b.insert(200, 0, vec![0]).is_err());
+        b.insert(100, 1, vec![0]).unwrap();
+
+        assert_eq!(b.take(100), vec![0, 0]);
+        // nor rounds far behind or ahead of the ones taken
+        assert!(b.insert(100 - MAX_IN_FLIGHT, 0, vec![0]).is_err());
+        assert!(b.insert(100 + MAX_IN_FLIGHT, 0, vec![0]).is_err());
+        b.insert(100 + MAX_IN_FLIGHT - 1, 1, vec![0]).unwrap();
+        // and rounds left behind are dropped
+        assert_eq!(b.take(100 + 2 * MAX_IN_FLIGHT), Vec::<u32>::new());
+        assert_eq!(b.take(100 + MAX_IN_FLIGHT - 1), Vec::<u32>::new());
+    }
+}
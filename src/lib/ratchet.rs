@@ -0,0 +1,400 @@
+use crate::byteorder::{BigEndian, ByteOrder};
+use crate::error::{Error, Result};
+use crate::message::Deaddrop;
+use crate::onion::{self, DerivedKey, EncryptionPurpose, PrivateKey, PublicKey};
+
+// HKDF info strings, each key of a round comes from the chain key with its own
+const SEED_INFO: &[u8] = b"conversation";
+const NEXT_INFO: &[u8] = b"next round";
+const DEADDROP_INFO: &[u8] = b"deaddrop";
+const MESSAGE_INFO: &[u8] = b"message to ";
+const FIRST_INFO: &[u8] = b"first text to ";
+
+// bytes of a chain key, as long as an X25519 shared secret
+const CHAIN_LEN: usize = 32;
+// leads the state written by `to_bytes`
+const STATE_VERSION: u8 = 2;
+// how many of our ephemeral keys are kept for the other side to seal to
+const KEPT_KEYS: usize = 16;
+// the reference of a text sealed before any of the other side's keys arrived
+const NO_KEY: u32 = u32::max_value();
+// each text key seals a single text
+const NONCE: u32 = 0;
+
+/// Bytes of a message's text, after its ephemeral key, the reference to
+/// the key it was sealed to and the text's own tag.
+pub fn text_size() -> usize {
+    crate::message::RAW_SIZE - header_len() - *onion::TAG_LEN
+}
+
+fn header_len() -> usize {
+    *onion::PK_LEN + 4
+}
+
+/// The keys of one round of a conversation. Each key seals a single
+/// message, so it is used with a fixed nonce.
+#[derive(Debug)]
+pub struct RoundKeys {
+    pub round: u32,
+    /// Where both sides leave their messages this round.
+    pub deaddrop: Deaddrop,
+    /// Seals our message to the other side.
+    pub to_peer: DerivedKey,
+    /// Opens the other side's message to us.
+    pub from_peer: DerivedKey,
+    /// The ephemeral public key our message carries, for the other side to
+    /// seal its later texts to.
+    pub eph_pk: PublicKey,
+    /// The round of the other side's ephemeral key our text is sealed to.
+    pub peer_ref: u32,
+    /// Seals our text.
+    pub text_key: DerivedKey,
+    // opens a text from the other side sealed before it had any of our keys
+    first_from_peer: DerivedKey,
+}
+
+/// One side of a conversation's keys. The two sides start from the key
+/// they agree on and step a chain key forward every round, whatever
+/// happened in it:
+///  chain_0 = HKDF(derive(sk_alice, pk_bob), "conversation")
+///  chain_r+1 = HKDF(chain_r, "next round")
+/// so both always agree on a round's deaddrop and the keys of its messages,
+/// even after lost messages or a restart. A message holds a fresh ephemeral
+/// public key, the round of the other side's newest ephemeral key we have,
+/// and the text sealed under the X25519 secret of the two. The other side
+/// keeps the private keys of its last few messages to open those texts,
+/// and drops the ones older than any a text was sealed to. A text sealed
+/// before any of the other side's keys arrived uses a key from the chain.
+pub struct Ratchet {
+    // the first round whose keys can still be taken, `chain` is for it
+    round: u32,
+    chain: DerivedKey,
+    pk: PublicKey,
+    peer_pk: PublicKey,
+    // our ephemeral keys by the round of the message that carried them,
+    // oldest first
+    mine: Vec<(u32, PrivateKey)>,
+    // the other side's newest ephemeral key and the round it came in
+    theirs: Option<(u32, PublicKey)>,
+}
+
+impl Ratchet {
+    /// Start the conversation between the holders of `sk` and `peer_pk`,
+    /// at round 0. Both sides get the same chain.
+    pub fn new(sk: &PrivateKey, peer_pk: &PublicKey) -> Result<Ratchet> {
+        let dk = onion::derive(sk, peer_pk)?;
+        let mut seed = vec![0; CHAIN_LEN];
+        dk.extract_and_expand(SEED_INFO, &mut seed)?;
+        Ok(Ratchet {
+            round: 0,
+            chain: DerivedKey::from_secret(seed)?,
+            pk: onion::public_key(sk)?,
+            peer_pk: peer_pk.clone(),
+            mine: vec![],
+            theirs: None,
+        })
+    }
+
+    /// The first round whose keys can still be taken.
+    pub fn round(&self) -> u32 {
+        self.round
+    }
+
+    /// Whether this is the conversation between the holders of `pk` and
+    /// `peer_pk`.
+    pub fn is_between(&self, pk: &[u8], peer_pk: &[u8]) -> bool {
+        self.pk == pk && self.peer_pk == peer_pk
+    }
+
+    /// Take the keys of `round`, ratcheting past every round before it. A
+    /// round's keys are only handed out once, so no message key seals two
+    /// messages.
+    pub fn keys(&mut self, round: u32) -> Result<RoundKeys> {
+        let (eph_sk, _) = onion::keygen()?;
+        self.keys_with(round, eph_sk)
+    }
+
+    /// Like `keys`, with the ephemeral key given rather than fresh.
+    pub fn keys_with(&mut self, round: u32, eph_sk: PrivateKey) -> Result<RoundKeys> {
+        if round < self.round {
+            return Err(Error::Crypto("keys of the round already ratcheted past"));
+        }
+        while self.round < round {
+            self.step()?;
+        }
+        let (peer_ref, text_key) = match &self.theirs {
+            Some((r, peer_eph_pk)) => (*r, onion::derive(&eph_sk, peer_eph_pk)?),
+            None => (NO_KEY, self.key(FIRST_INFO, &self.peer_pk)?),
+        };
+        let keys = RoundKeys {
+            round,
+            deaddrop: Deaddrop::new(&self.chain, DEADDROP_INFO)?,
+            to_peer: self.key(MESSAGE_INFO, &self.peer_pk)?,
+            from_peer: self.key(MESSAGE_INFO, &self.pk)?,
+            eph_pk: onion::public_key(&eph_sk)?,
+            peer_ref,
+            text_key,
+            first_from_peer: self.key(FIRST_INFO, &self.pk)?,
+        };
+        self.step()?;
+        self.mine.push((round, eph_sk));
+        if self.mine.len() > KEPT_KEYS {
+            self.mine.remove(0);
+        }
+        Ok(keys)
+    }
+
+    /// Our message of the round of `keys`, with `text` padded to
+    /// `text_size()` bytes and sealed.
+    pub fn seal(keys: &RoundKeys, text: &[u8]) -> Result<Vec<u8>> {
+        if text.len() > text_size() {
+            return Err(Error::Framing(format!(
+                "message of {} bytes, at most {} fit",
+                text.len(),
+                text_size()
+            )));
+        }
+        let mut padded = text.to_vec();
+        padded.resize(text_size(), 0);
+        let sealed = onion::encrypt(&keys.text_key, padded, EncryptionPurpose::FromBytes(NONCE))?;
+        let mut peer_ref = [0; 4];
+        BigEndian::write_u32(&mut peer_ref, keys.peer_ref);
+        Ok([&keys.eph_pk[..], &peer_ref, &sealed].concat())
+    }
+
+    /// Open the other side's message of the round of `keys`, giving its
+    /// padded text. Its ephemeral key is then the one our texts are sealed
+    /// to, and our keys older than the one it was sealed to are dropped.
+    pub fn open(&mut self, keys: &RoundKeys, m: &[u8]) -> Result<Vec<u8>> {
+        if m.len() != header_len() + text_size() + *onion::TAG_LEN {
+            return Err(Error::Crypto("malformed conversation message"));
+        }
+        let (peer_eph_pk, rest) = m.split_at(*onion::PK_LEN);
+        let (peer_ref, sealed) = rest.split_at(4);
+        let peer_ref = BigEndian::read_u32(peer_ref);
+        let text_key = match peer_ref {
+            NO_KEY => None,
+            r => match self.mine.iter().find(|(round, _)| *round == r) {
+                Some((_, eph_sk)) => Some(onion::derive(eph_sk, peer_eph_pk)?),
+                None => return Err(Error::Crypto("text sealed to a key no longer kept")),
+            },
+        };
+        let text_key = text_key.as_ref().unwrap_or(&keys.first_from_peer);
+        let text = onion::decrypt(text_key, sealed.to_vec(), EncryptionPurpose::FromBytes(NONCE))?;
+
+        if self.theirs.as_ref().map_or(true, |(r, _)| *r < keys.round) {
+            self.theirs = Some((keys.round, peer_eph_pk.to_vec()));
+        }
+        if peer_ref != NO_KEY {
+            // the other side has this key, so it no longer seals to older ones
+            self.mine.retain(|(round, _)| *round >= peer_ref);
+        }
+        Ok(text)
+    }
+
+    /// The state, to be kept sealed like a private key:
+    ///  version | round | chain key | pk | peer pk
+    ///   | 0, or 1 | their round | their ephemeral pk
+    ///   | count | count times (round | our ephemeral sk)
+    /// where rounds are big-endian u32s and the count a byte.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut b = vec![STATE_VERSION];
+        push_u32(&mut b, self.round);
+        b.extend_from_slice(self.chain.secret());
+        b.extend_from_slice(&self.pk);
+        b.extend_from_slice(&self.peer_pk);
+        match &self.theirs {
+            Some((r, peer_eph_pk)) => {
+                b.push(1);
+                push_u32(&mut b, *r);
+                b.extend_from_slice(peer_eph_pk);
+            }
+            None => b.push(0),
+        }
+        b.push(self.mine.len() as u8);
+        for (r, eph_sk) in &self.mine {
+            push_u32(&mut b, *r);
+            b.extend_from_slice(eph_sk);
+        }
+        b
+    }
+
+    /// Read the state written by `to_bytes`.
+    pub fn from_bytes(b: &[u8]) -> Result<Ratchet> {
+        let pk_len = *onion::PK_LEN;
+        let mut r = Reader(b);
+        if r.take(1)? != [STATE_VERSION] {
+            return Err(malformed());
+        }
+        let round = r.u32()?;
+        let chain = DerivedKey::from_secret(r.take(CHAIN_LEN)?.to_vec())?;
+        let pk = r.take(pk_len)?.to_vec();
+        let peer_pk = r.take(pk_len)?.to_vec();
+        let theirs = match r.take(1)?[0] {
+            0 => None,
+            1 => Some((r.u32()?, r.take(pk_len)?.to_vec())),
+            _ => return Err(malformed()),
+        };
+        let count = r.take(1)?[0] as usize;
+        if count > KEPT_KEYS {
+            return Err(malformed());
+        }
+        let mut mine = Vec::with_capacity(count);
+        for _ in 0..count {
+            mine.push((r.u32()?, PrivateKey::new(r.take(pk_len)?.to_vec())));
+        }
+        if !r.0.is_empty() {
+            return Err(malformed());
+        }
+        Ok(Ratchet {
+            round,
+            chain,
+            pk,
+            peer_pk,
+            mine,
+            theirs,
+        })
+    }
+
+    // a key of the current round, for the holder of `pk`
+    fn key(&self, info: &[u8], pk: &PublicKey) -> Result<DerivedKey> {
+        let info = [info, pk].concat();
+        let mut secret = vec![0; CHAIN_LEN];
+        self.chain.extract_and_expand(&info, &mut secret)?;
+        DerivedKey::from_secret(secret)
+    }
+
+    // on to the next round's chain key, dropping this one
+    fn step(&mut self) -> Result<()> {
+        let mut secret = vec![0; CHAIN_LEN];
+        self.chain.extract_and_expand(NEXT_INFO, &mut secret)?;
+        self.chain = DerivedKey::from_secret(secret)?;
+        self.round = self
+            .round
+            .checked_add(1)
+            .ok_or(Error::Crypto("conversation ran out of rounds"))?;
+        Ok(())
+    }
+}
+
+fn malformed() -> Error {
+    Error::Crypto("malformed conversation state")
+}
+
+fn push_u32(b: &mut Vec<u8>, v: u32) {
+    let mut bytes = [0; 4];
+    BigEndian::write_u32(&mut bytes, v);
+    b.extend_from_slice(&bytes);
+}
+
+// reads the state written by `to_bytes` front to back
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(malformed());
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(BigEndian::read_u32(self.take(4)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pair() -> (Ratchet, Ratchet) {
+        let (sk_a, pk_a) = onion::keygen().unwrap();
+        let (sk_b, pk_b) = onion::keygen().unwrap();
+        (
+            Ratchet::new(&sk_a, &pk_b).unwrap(),
+            Ratchet::new(&sk_b, &pk_a).unwrap(),
+        )
+    }
+
+    // both sides send in `round`, and each message reaches the other side
+    // if its flag is set
+    fn exchange(alice: &mut Ratchet, bob: &mut Ratchet, round: u32, to_bob: bool, to_alice: bool) {
+        let (a, b) = (alice.keys(round).unwrap(), bob.keys(round).unwrap());
+        assert_eq!(a.deaddrop, b.deaddrop);
+        let text = format!("round {}", round).into_bytes();
+        let from_a = Ratchet::seal(&a, &text).unwrap();
+        let from_b = Ratchet::seal(&b, &text).unwrap();
+        if to_bob {
+            assert!(bob.open(&b, &from_a).unwrap().starts_with(&text));
+        }
+        if to_alice {
+            assert!(alice.open(&a, &from_b).unwrap().starts_with(&text));
+        }
+    }
+
+    #[test]
+    fn both_sides_agree() {
+        let (mut alice, mut bob) = pair();
+        let a = alice.keys(5).unwrap();
+        let b = bob.keys(5).unwrap();
+        assert_eq!(a.deaddrop, b.deaddrop);
+        assert_eq!(a.to_peer, b.from_peer);
+        assert_eq!(a.from_peer, b.to_peer);
+        // each direction has its own key
+        assert_ne!(a.to_peer, a.from_peer);
+    }
+
+    #[test]
+    fn past_rounds_are_gone() {
+        let (mut alice, _) = pair();
+        let a = alice.keys(1).unwrap();
+        // the next round can be taken before the reply to this one
+        let b = alice.keys(2).unwrap();
+        assert_ne!(a.to_peer, b.to_peer);
+        assert_eq!(alice.round(), 3);
+        assert!(alice.keys(2).is_err());
+    }
+
+    #[test]
+    fn texts_sealed_to_ephemeral_keys() {
+        let (mut alice, mut bob) = pair();
+        exchange(&mut alice, &mut bob, 1, true, true);
+        let (a, b) = (alice.keys(2).unwrap(), bob.keys(2).unwrap());
+        assert_eq!((a.peer_ref, b.peer_ref), (1, 1));
+        // a key from the chain no longer opens the text
+        assert_ne!(a.text_key, a.first_from_peer);
+        let from_a = Ratchet::seal(&a, b"hi").unwrap();
+        assert!(bob.open(&b, &from_a).unwrap().starts_with(b"hi"));
+        // so Bob dropped his keys from before round 1
+        assert_eq!(bob.mine.iter().map(|(r, _)| *r).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn lost_messages_do_not_split_the_sides() {
+        let (mut alice, mut bob) = pair();
+        exchange(&mut alice, &mut bob, 1, true, true);
+        // Bob's reply lost on the way back, then Alice's
+        exchange(&mut alice, &mut bob, 2, true, false);
+        exchange(&mut alice, &mut bob, 3, false, true);
+        // rounds missed altogether, and pipelined ones
+        alice.keys(4).unwrap();
+        exchange(&mut alice, &mut bob, 7, true, true);
+        exchange(&mut alice, &mut bob, 8, true, true);
+    }
+
+    #[test]
+    fn state_kept_across_restarts() {
+        let (mut alice, mut bob) = pair();
+        exchange(&mut alice, &mut bob, 1, true, true);
+        let a = alice.keys(2).unwrap();
+        let mut alice = Ratchet::from_bytes(&alice.to_bytes()).unwrap();
+        let b = bob.keys(2).unwrap();
+        let from_b = Ratchet::seal(&b, b"after").unwrap();
+        assert!(alice.open(&a, &from_b).unwrap().starts_with(b"after"));
+        let mut bob = Ratchet::from_bytes(&bob.to_bytes()).unwrap();
+        exchange(&mut alice, &mut bob, 3, true, true);
+        assert!(Ratchet::from_bytes(&alice.to_bytes()[1..]).is_err());
+    }
+}
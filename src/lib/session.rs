@@ -0,0 +1,191 @@
+use crate::head_rpc::{FetchResult, Ticket};
+use crate::onion;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Sessions that have not polled for this long are dropped.
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub type SessionId = u64;
+
+/// Pushed to a client over its session.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Event {
+    // round `round` accepts messages until `deadline` (ms since the UNIX epoch)
+    RoundOpen { round: u32, deadline: u64 },
+    // outcome of a message submitted over this session
+    RoundResult { ticket: Ticket, reply: FetchResult },
+}
+
+/// Milliseconds since the UNIX epoch, as used for round deadlines.
+pub fn unix_millis(t: SystemTime) -> u64 {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+#[derive(Debug)]
+struct Session {
+    events: VecDeque<Event>,
+    // tickets whose replies should be pushed to this session
+    pending: Vec<Ticket>,
+    last_poll: Instant,
+}
+
+/// Per-client event queues for long-lived connections.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    next_id: SessionId,
+    sessions: HashMap<SessionId, Session>,
+    // the most recent announcement, replayed to new sessions
+    current: Option<Event>,
+}
+
+impl Sessions {
+    pub fn open(&mut self) -> SessionId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut events = VecDeque::new();
+        if let Some(e) = &self.current {
+            events.push_back(e.clone());
+        }
+        self.sessions.insert(
+            id,
+            Session {
+                events,
+                pending: vec![],
+                last_poll: Instant::now(),
+            },
+        );
+        id
+    }
+
+    pub fn contains(&self, id: SessionId) -> bool {
+        self.sessions.contains_key(&id)
+    }
+
+    /// Remember that the reply for `ticket` belongs to session `id`.
+    pub fn watch(&mut self, id: SessionId, ticket: Ticket) -> bool {
+        match self.sessions.get_mut(&id) {
+            Some(s) => {
+                s.pending.push(ticket);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn announce(&mut self, round: u32, deadline: u64) {
+        let e = Event::RoundOpen { round, deadline };
+        for s in self.sessions.values_mut() {
+            s.events.push_back(e.clone());
+        }
+        self.current = Some(e);
+    }
+
+    /// Push the replies of a finished round to the sessions waiting on them.
+    pub fn deliver(&mut self, round: u32, replies: &[onion::Message]) {
+        self.settle(round, |t| match replies.get(t.slot as usize) {
+            Some(r) => FetchResult::Reply(r.clone()),
+            None => FetchResult::Failed,
+        });
+    }
+
+    /// Tell the sessions waiting on an aborted round that it failed.
+    pub fn fail(&mut self, round: u32) {
+        self.settle(round, |_| FetchResult::Failed);
+    }
+
+    fn settle<F: Fn(&Ticket) -> FetchResult>(&mut self, round: u32, reply: F) {
+        for s in self.sessions.values_mut() {
+            let (done, waiting) = s.pending.drain(..).partition(|t| t.round == round);
+            s.pending = waiting;
+            for ticket in done {
+                let reply = reply(&ticket);
+                s.events.push_back(Event::RoundResult { ticket, reply });
+            }
+        }
+    }
+
+    /// Take the queued events for a session, None if the session is unknown.
+    pub fn poll(&mut self, id: SessionId) -> Option<Vec<Event>> {
+        let s = self.sessions.get_mut(&id)?;
+        s.last_poll = Instant::now();
+        Some(s.events.drain(..).collect())
+    }
+
+    pub fn has_events(&self, id: SessionId) -> bool {
+        match self.sessions.get(&id) {
+            Some(s) => !s.events.is_empty(),
+            None => true,
+        }
+    }
+
+    /// Forget sessions whose client has stopped polling.
+    pub fn expire(&mut self) {
+        self.sessions
+            .retain(|_, s| s.last_poll.elapsed() < SESSION_IDLE_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn announcements_reach_new_sessions() {
+        let mut s = Sessions::default();
+        let a = s.open();
+        s.announce(4, 1000);
+        let b = s.open();
+
+        let open = Event::RoundOpen {
+            round: 4,
+            deadline: 1000,
+        };
+        assert_eq!(s.poll(a), Some(vec![open.clone()]));
+        assert_eq!(s.poll(b), Some(vec![open]));
+        assert_eq!(s.poll(a), Some(vec![]));
+    }
+
+    #[test]
+    fn results_go_to_their_session() {
+        let mut s = Sessions::default();
+        let a = s.open();
+        let b = s.open();
+        let ticket = Ticket { round: 2, slot: 1 };
+        assert!(s.watch(a, ticket));
+
+        s.deliver(1, &[vec![0], vec![1]]);
+        assert_eq!(s.poll(a), Some(vec![]));
+
+        s.deliver(2, &[vec![0], vec![1]]);
+        let reply = FetchResult::Reply(vec![1]);
+        assert_eq!(s.poll(a), Some(vec![Event::RoundResult { ticket, reply }]));
+        assert_eq!(s.poll(b), Some(vec![]));
+    }
+
+    #[test]
+    fn failed_rounds_reach_their_sessions() {
+        let mut s = Sessions::default();
+        let a = s.open();
+        let ticket = Ticket { round: 3, slot: 0 };
+        assert!(s.watch(a, ticket));
+
+        s.fail(3);
+        let reply = FetchResult::Failed;
+        assert_eq!(s.poll(a), Some(vec![Event::RoundResult { ticket, reply }]));
+
+        // the late replies of an aborted round go nowhere
+        s.deliver(3, &[vec![0]]);
+        assert_eq!(s.poll(a), Some(vec![]));
+    }
+
+    #[test]
+    fn unknown_session() {
+        let mut s = Sessions::default();
+        assert!(!s.watch(7, Ticket { round: 0, slot: 0 }));
+        assert_eq!(s.poll(7), None);
+    }
+}
@@ -0,0 +1,152 @@
+#![allow(non_snake_case)]
+
+use crate::batch::{self, Batch};
+use crate::conn::Peers;
+use crate::error::{Error, Result};
+use crate::message;
+use crate::util::{self, shard_of};
+use crate::wire;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+use tarpc::futures::future::Ready;
+use tarpc::futures::stream::{self, StreamExt};
+use tarpc::futures::*;
+use tarpc::context;
+
+// shards asked at once
+const SHARD_WINDOW: usize = 64;
+
+lazy_static! {
+    // deaddrop shard servers, empty if the last server does the exchange itself
+    pub static ref SHARDS: Mutex<Vec<SocketAddr>> = Mutex::new(vec![]);
+    // connections kept open to the shard servers
+    static ref SHARD_PEERS: Peers<Client> = Peers::new();
+}
+
+service! {
+    // RPC's for the deaddrop shard servers
+    //
+    //  --------------------      ------------------
+    //  | Deaddrop Server  | <->  | Deaddrop Shard |  (one of several)
+    //  --------------------      ------------------
+    //
+    // swap messages sharing a deaddrop among those of one shard, replies
+    // come back in the order the messages were sent
+    rpc Exchange(round: u32, messages: Batch) -> Batch;
+}
+
+dial!();
+
+/// Hand the exchange off to `shards`, instead of doing it on this server.
+pub fn configure_shards(shards: Vec<SocketAddr>) {
+    *SHARDS.lock().unwrap() = shards;
+}
+
+/// Split a round by deaddrop location into one part per shard, each with
+/// the positions its messages came from.
+pub fn split(m_vec: &Batch, shards: usize) -> Vec<(Vec<usize>, Batch)> {
+    let mut parts: Vec<(Vec<usize>, Batch)> = (0..shards)
+        .map(|_| (vec![], Batch::new(m_vec.stride())))
+        .collect();
+    for (i, w) in m_vec.iter().enumerate() {
+        let shard = shard_of(message::location_of(w).location(), shards);
+        let (ref mut indices, ref mut part) = parts[shard];
+        indices.push(i);
+        part.push(w);
+    }
+    parts
+}
+
+/// Put the shards' replies back in the order of the round.
+pub fn join(parts: Vec<(Vec<usize>, Batch)>, n: usize) -> Batch {
+    let mut out = Batch::with_capacity(*message::CONTENT_SIZE, n);
+    out.resize(n);
+    for (indices, part) in parts {
+        for (k, i) in indices.into_iter().enumerate() {
+            out.get_mut(i).copy_from_slice(part.get(k));
+        }
+    }
+    out
+}
+
+/// Run a round's deaddrop exchange across the shard servers.
+pub async fn exchange(round: u32, m_vec: Batch, shards: Vec<SocketAddr>) -> Result<Batch> {
+    let n = m_vec.len();
+    let now = Instant::now();
+    let parts = split(&m_vec, shards.len());
+    batch::POOL.give(m_vec);
+
+    let replies: Vec<Result<(Vec<usize>, Batch)>> = await!(stream::iter(
+        parts.into_iter().zip(shards.into_iter())
+    )
+    .map(|((indices, part), addr)| {
+        async move {
+            // the exchange is a pure function of the part, so it is safe to retry
+            let reply = await!(SHARD_PEERS.call(addr, dial, |mut client| {
+                let part = part.clone();
+                async move { await!(client.Exchange(context::current(), round, part)) }
+            }))?;
+            if reply.len() != indices.len() {
+                return Err(Error::Framing(format!("deaddrop shard {} lost messages", addr)));
+            }
+            wire::check_batch(&reply, *message::CONTENT_SIZE)?;
+            Ok((indices, reply))
+        }
+    })
+    .buffer_unordered(SHARD_WINDOW)
+    .collect());
+
+    let mut parts = Vec::with_capacity(replies.len());
+    for r in replies {
+        parts.push(r?);
+    }
+    let out = join(parts, n);
+    println!(
+        "SHARDED DEADDROP TIME ELAPSED (ms): {}",
+        now.elapsed().as_millis()
+    );
+    Ok(out)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DeaddropShard;
+
+impl self::Service for DeaddropShard {
+    type ExchangeFut = Ready<Batch>;
+
+    fn Exchange(self, _: context::Context, round: u32, messages: Batch) -> Self::ExchangeFut {
+        println!("exchanging {} messages of round {}", messages.len(), round);
+        if let Err(e) = wire::check_batch(&messages, *message::CONTENT_SIZE + 4) {
+            // an empty reply fails the round on the deaddrop server
+            eprintln!("Bad messages for round {}: {}", round, e);
+            return future::ready(Batch::new(*message::CONTENT_SIZE));
+        }
+        // every deaddrop lands on exactly one shard, so this is the whole exchange for them
+        future::ready(util::deaddrop(messages))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split_exchange_join_matches_whole() {
+        let input: Vec<Vec<u8>> = (0..2000u32)
+            .map(|i| {
+                let location = rand::random::<u32>() % 500 * 8_589_934;
+                let d = message::Deaddrop::from_bytes(&location.to_be_bytes());
+                message::pack(&vec![(i % 251) as u8; *message::CONTENT_SIZE], &d)
+            })
+            .collect();
+        let batch = Batch::from_messages(message::onion_size(0), &input);
+
+        let parts = split(&batch, 5)
+            .into_iter()
+            .map(|(indices, part)| (indices, util::deaddrop(part)))
+            .collect();
+
+        assert_eq!(join(parts, input.len()), util::deaddrop(batch));
+    }
+}
@@ -0,0 +1,71 @@
+use crate::batch::{self, Batch};
+use crate::error::{Error, Result, RoundError};
+use crate::frame::{Frame, Kind};
+use std::io;
+use std::sync::Arc;
+use tarpc::futures::stream::{self, StreamExt};
+use tarpc::futures::Future;
+
+/// Messages per SendMessages call.
+pub const CHUNK_SIZE: usize = 1024;
+/// Chunks in flight at once; the receiver acknowledges a chunk only once it
+/// has processed it, so this bounds how far a sender can run ahead.
+pub const WINDOW: usize = 8;
+
+/// Split a batch into chunks tagged with the offset of their first message,
+/// handing the batch's buffer back to the pool once copied out.
+pub fn into_chunks(m_vec: Batch) -> Vec<(u32, Batch)> {
+    let chunks = m_vec
+        .chunks(CHUNK_SIZE)
+        .into_iter()
+        .enumerate()
+        .map(|(i, c)| ((i * CHUNK_SIZE) as u32, c))
+        .collect();
+    batch::POOL.give(m_vec);
+    chunks
+}
+
+/// Stream a batch to a neighbouring server as `kind` frames, keeping up to
+/// `WINDOW` chunks in flight. `send` issues one SendMessages call for an
+/// encoded frame, shared rather than copied when the call is retried.
+pub async fn stream_chunks<F, Fut>(round: u32, kind: Kind, m_vec: Batch, send: F) -> Result<()>
+where
+    F: FnMut(Arc<Vec<u8>>) -> Fut,
+    Fut: Future<Output = io::Result<bool>>,
+{
+    let frames = into_chunks(m_vec)
+        .into_iter()
+        .map(|(offset, c)| Arc::new(Frame::new(kind, round, offset, c).encode()));
+    let acks: Vec<io::Result<bool>> = await!(stream::iter(frames)
+        .map(send)
+        .buffer_unordered(WINDOW)
+        .collect());
+    for ack in acks {
+        if !ack? {
+            return Err(Error::Round(round, RoundError::Rejected));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chunks_cover_batch_in_order() {
+        let m_vec: Vec<Vec<u8>> = (0..(2 * CHUNK_SIZE + 3)).map(|i| vec![i as u8]).collect();
+        let chunks = into_chunks(Batch::from_messages(1, &m_vec));
+
+        let offsets: Vec<u32> = chunks.iter().map(|(o, _)| *o).collect();
+        assert_eq!(offsets, vec![0, CHUNK_SIZE as u32, 2 * CHUNK_SIZE as u32]);
+
+        let joined: Vec<Vec<u8>> = chunks.into_iter().flat_map(|(_, c)| c.to_messages()).collect();
+        assert_eq!(joined, m_vec);
+    }
+
+    #[test]
+    fn no_chunks_for_empty_batch() {
+        assert!(into_chunks(Batch::new(4)).is_empty());
+    }
+}
@@ -1,16 +1,24 @@
+use crate::batch::Batch;
+use crate::error::Result;
 use crate::message;
+use crate::noise::{self, NoisePool};
 use crate::onion;
 use crate::permute::Permutation;
+use crate::wire::Layer;
 use crate::rand::distributions::Distribution;
 use crate::rayon::prelude::*;
 
+use std::cmp::min;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Instant;
 
 pub struct Settings<D: Distribution<u32>> {
     pub other_pks: Vec<onion::PublicKey>,
     pub sk: onion::PrivateKey,
     pub noise: D,
+    // noise onions made ahead of time, if None they are made during the round
+    pub pool: Option<Arc<NoisePool>>,
 }
 
 #[derive(Debug)]
@@ -20,41 +28,64 @@ pub struct State {
     n: usize,
 }
 
-pub fn forward<D>(
-    input: Vec<onion::Message>,
-    settings: &Settings<D>,
-) -> (State, Vec<onion::Message>)
+pub fn forward<D>(mut input: Batch, settings: &Settings<D>) -> Result<(State, Batch)>
 where
     D: Distribution<u32> + Sync,
 {
-    let mut rng = rand::thread_rng();
-    let n = input.len();
-
-    // unwrap, decrypt, and store keys
-    let mut keys: Vec<onion::DerivedKey> = Vec::with_capacity(n);
-    let mut inners: Vec<onion::Message> = Vec::with_capacity(n);
-
     let now = Instant::now();
-    let _unwrapped = input
-        .par_iter()
-        .map(|wrapped| {
-            let (pk, cipher) = message::unwrap(&wrapped);
-            let dk = onion::derive(&settings.sk, &pk);
-            let inner = match onion::decrypt(&dk, cipher, onion::EncryptionPurpose::Forward) {
-                Ok(m) => m,
-
-                // for security, replace bad messages with fakes
-                Err(()) => message::blank(&message::Deaddrop::sample()),
-            };
-
-            (dk, inner)
-        })
-        .unzip_into_vecs(&mut keys, &mut inners);
+    let keys = decrypt(&mut input, &settings.sk)?;
     println!(
         "FORWARD DECRYPT TIME ELAPSED (ms): {}",
         now.elapsed().as_millis()
     );
 
+    mix((keys, input), settings)
+}
+
+/// Peel our layer off each message in place, keeping the key for the way
+/// back. Works on any part of a batch, so chunks can be decrypted as they arrive.
+pub fn decrypt(input: &mut Batch, sk: &onion::PrivateKey) -> Result<Vec<onion::DerivedKey>> {
+    let inner = input.stride().saturating_sub(message::layer_size());
+    let keys = input
+        .par_iter_mut()
+        .map(|wrapped| {
+            let header_len = min(message::header_size(), wrapped.len());
+            let (header, cipher) = wrapped.split_at_mut(header_len);
+            // a layer for another cipher suite is as bad as one that fails to open
+            let opened = Layer::split(header)
+                .and_then(|l| l.key(sk))
+                .and_then(|dk| {
+                    onion::open_in_place(&dk, cipher, onion::EncryptionPurpose::Forward)?;
+                    Ok(dk)
+                });
+            match opened {
+                Ok(dk) => Ok(dk),
+                Err(_) => {
+                    // for security, replace bad messages with fakes, and
+                    // answer them under a key nobody else holds
+                    message::blank_into(&mut cipher[..inner], &message::Deaddrop::sample());
+                    onion::DerivedKey::sample()
+                }
+            }
+        })
+        .collect::<Result<_>>()?;
+
+    input.shrink_stride(message::header_size(), inner);
+    Ok(keys)
+}
+
+/// Add noise to a decrypted batch and shuffle it.
+pub fn mix<D>(
+    decrypted: (Vec<onion::DerivedKey>, Batch),
+    settings: &Settings<D>,
+) -> Result<(State, Batch)>
+where
+    D: Distribution<u32> + Sync,
+{
+    let mut rng = rand::thread_rng();
+    let (keys, mut all) = decrypted;
+    let n = keys.len();
+
     // add noise
     let n1 = settings.noise.sample(&mut rng);
     let n2 = settings.noise.sample(&mut rng) / 2;
@@ -63,25 +94,18 @@ where
     let m = n + adding;
 
     let now = Instant::now();
-    let noise1 = (0..n1).into_par_iter().map(|_| {
-        let m = message::blank(&message::Deaddrop::sample());
-        let (_dks, wrapped) = message::forward_onion_encrypt(&settings.other_pks, m);
-        wrapped
-    });
-
-    let noise2 = (0..n2).into_par_iter().flat_map(|_| {
-        let r: Vec<onion::Message> = (0..2)
-            .into_iter()
-            .map(|__| {
-                let m = message::blank(&message::Deaddrop::sample());
-                let (_dks, wrapped) = message::forward_onion_encrypt(&settings.other_pks, m);
-                wrapped
-            })
-            .collect();
-        r
-    });
+    let noise = match &settings.pool {
+        Some(pool) => pool.take(adding)?,
+        None => noise::generate(&settings.other_pks, adding)?,
+    };
 
-    let all: Vec<onion::Message> = inners.into_par_iter().chain(noise1).chain(noise2).collect();
+    if all.is_empty() {
+        // nothing arrived this round, so there is no stride to go by yet
+        all = Batch::with_capacity(message::onion_size(settings.other_pks.len()), adding);
+    }
+    for w in noise.iter() {
+        all.push(w);
+    }
     println!(
         "FORWARD NOISE ADDITION TIME ELAPSED (ms): {}",
         now.elapsed().as_millis()
@@ -89,35 +113,46 @@ where
 
     // permute
     let permutation = Permutation::sample(m);
-    let output: Vec<onion::Message> = permutation.apply(all);
+    permutation.apply_batch(&mut all);
 
-    (
+    Ok((
         State {
             keys,
             permutation,
             n,
         },
-        output,
-    )
+        all,
+    ))
 }
 
-pub fn backward(state: State, input: Vec<onion::Message>) -> Vec<onion::Message> {
-    // unpermute
-    let unpermuted = state.permutation.inverse().apply(input);
+pub fn backward(state: State, mut unpermuted: Batch) -> Result<Batch> {
+    // unpermute, and drop the noise we added
+    state.permutation.apply_inverse_batch(&mut unpermuted);
+    unpermuted.truncate(state.n);
 
     let now = Instant::now();
-    // re-encrypt
-    let result = unpermuted
-        .into_par_iter()
+    // re-encrypt in place, after making room for the tags
+    let stride = unpermuted.stride() + *onion::TAG_LEN;
+    unpermuted.grow_stride(stride);
+    unpermuted
+        .par_iter_mut()
         .zip(state.keys.par_iter())
-        .map(|(m, dk)| onion::encrypt(dk, m, onion::EncryptionPurpose::Backward))
-        .collect();
+        .try_for_each(|(m, dk)| onion::seal_in_place(dk, m, onion::EncryptionPurpose::Backward))?;
 
     println!(
         "BACKWARDS Re-encrypt TIME ELAPSED (ms): {}",
         now.elapsed().as_millis()
     );
-    result
+    Ok(unpermuted)
+}
+
+/// Shards the deaddrop exchange is split into, each run on its own thread.
+pub const DEADDROP_SHARDS: usize = 64;
+
+/// The shard a deaddrop location belongs to, by its leading bits, so that
+/// shards cover equal ranges of locations for any number of shards.
+pub fn shard_of(location: u32, shards: usize) -> usize {
+    ((location as u64 * shards as u64) >> 32) as usize
 }
 
 enum DeaddropState {
@@ -125,17 +160,16 @@ enum DeaddropState {
     Twice,
 }
 
-pub fn deaddrop(mut input: Vec<onion::Message>) -> Vec<onion::Message> {
+/// Pair up messages for the same deaddrop among `indices`, which must be in
+/// increasing order. Returns the pairs of positions to swap.
+fn exchange(indices: &[usize], locations: &[u32]) -> Vec<(usize, usize)> {
     const HASH_MARGIN: usize = 1; // tune up as needed to prevent map reallocation
 
-    let n = input.len();
-    let mut map: HashMap<u32, DeaddropState> = HashMap::with_capacity(HASH_MARGIN * n);
-    let mut output: Vec<onion::Message> = Vec::with_capacity(n);
+    let mut map: HashMap<u32, DeaddropState> = HashMap::with_capacity(HASH_MARGIN * indices.len());
+    let mut swaps = vec![];
 
-    for (i, w) in input.drain(0..).enumerate() {
-        let (m, d) = message::unpack(w);
-        let dl = d.location();
-        output.push(m);
+    for &i in indices {
+        let dl = locations[i];
 
         match map.remove(&dl) {
             Some(DeaddropState::Twice) => {
@@ -145,8 +179,7 @@ pub fn deaddrop(mut input: Vec<onion::Message>) -> Vec<onion::Message> {
                 );
             }
             Some(DeaddropState::Once(j)) => {
-                let mm = output.swap_remove(j);
-                output.push(mm);
+                swaps.push((i, j));
                 map.insert(dl, DeaddropState::Twice);
             }
             None => {
@@ -155,7 +188,40 @@ pub fn deaddrop(mut input: Vec<onion::Message>) -> Vec<onion::Message> {
         }
     }
 
-    output
+    swaps
+}
+
+pub fn deaddrop(input: Batch) -> Batch {
+    deaddrop_sharded(input, DEADDROP_SHARDS)
+}
+
+/// Swap the contents of messages sharing a deaddrop. Messages are split
+/// into `shards` by location, and every shard is exchanged on its own;
+/// messages for one deaddrop always land in the same shard, so the output
+/// does not depend on the number of shards.
+pub fn deaddrop_sharded(mut input: Batch, shards: usize) -> Batch {
+    let locations: Vec<u32> = input
+        .par_iter()
+        .map(|w| message::location_of(w).location())
+        .collect();
+
+    // positions for each shard, in their original order
+    let mut buckets: Vec<Vec<usize>> = (0..shards)
+        .map(|_| Vec::with_capacity(input.len() / shards + 1))
+        .collect();
+    for (i, &dl) in locations.iter().enumerate() {
+        buckets[shard_of(dl, shards)].push(i);
+    }
+
+    let swaps: Vec<(usize, usize)> = buckets
+        .par_iter()
+        .flat_map(|b| exchange(b, &locations))
+        .collect();
+    input.swap_disjoint(&swaps);
+
+    // strip the deaddrops, leaving just the contents
+    input.shrink_stride(0, *message::CONTENT_SIZE);
+    input
 }
 
 #[cfg(test)]
@@ -175,7 +241,62 @@ mod test {
             message::pack(&m2, &d_shared),
             message::pack(&m3, &d_loner),
         ];
+        let input = Batch::from_messages(message::onion_size(0), &input);
+
+        assert_eq!(deaddrop(input).to_messages(), vec![m2, m1, m3]);
+    }
 
-        assert_eq!(deaddrop(input), vec![m2, m1, m3]);
+    // the exchange as it was done before sharding
+    fn reference_deaddrop(mut input: Vec<onion::Message>) -> Vec<onion::Message> {
+        let n = input.len();
+        let mut map: HashMap<u32, DeaddropState> = HashMap::with_capacity(n);
+        let mut output: Vec<onion::Message> = Vec::with_capacity(n);
+
+        for (i, w) in input.drain(0..).enumerate() {
+            let (m, d) = message::unpack(w).unwrap();
+            let dl = d.location();
+            output.push(m);
+
+            match map.remove(&dl) {
+                Some(DeaddropState::Twice) => {}
+                Some(DeaddropState::Once(j)) => {
+                    let mm = output.swap_remove(j);
+                    output.push(mm);
+                    map.insert(dl, DeaddropState::Twice);
+                }
+                None => {
+                    map.insert(dl, DeaddropState::Once(i));
+                }
+            }
+        }
+
+        output
+    }
+
+    #[test]
+    fn sharded_matches_reference() {
+        // few enough locations that most are shared, some three or more times
+        let input: Vec<onion::Message> = (0..5000u32)
+            .map(|i| {
+                let location = rand::random::<u32>() % 3000 * 1_431_655;
+                let d = message::Deaddrop::from_bytes(&location.to_be_bytes());
+                message::pack(&vec![(i % 251) as u8; *message::CONTENT_SIZE], &d)
+            })
+            .collect();
+        let expected = reference_deaddrop(input.clone());
+
+        for &shards in &[1, 7, DEADDROP_SHARDS] {
+            let batch = Batch::from_messages(message::onion_size(0), &input);
+            assert_eq!(deaddrop_sharded(batch, shards).to_messages(), expected);
+        }
+    }
+
+    #[test]
+    fn shards_split_by_prefix() {
+        assert_eq!(shard_of(0, 4), 0);
+        assert_eq!(shard_of(0x3fff_ffff, 4), 0);
+        assert_eq!(shard_of(0x4000_0000, 4), 1);
+        assert_eq!(shard_of(u32::max_value(), 4), 3);
+        assert_eq!(shard_of(u32::max_value(), 1), 0);
     }
 }
@@ -0,0 +1,150 @@
+use crate::byteorder::{BigEndian, ByteOrder};
+use crate::client_util;
+use crate::error::{Error, Result};
+use crate::message::{self, CONTENT_SIZE};
+use crate::onion::{self, EncryptionPurpose, PrivateKey};
+use crate::ratchet::Ratchet;
+
+/// The round the vectors are made for.
+pub const ROUND: u32 = 7;
+/// Alice's message to Bob.
+pub const MESSAGE: &[u8] = b"Known answer";
+/// Every byte of the reply content the last server sends back.
+pub const REPLY_BYTE: u8 = 0x42;
+
+// a private key of 32 copies of `b`, none of these are secret
+fn key(b: u8) -> PrivateKey {
+    PrivateKey::new(vec![b; 32])
+}
+
+/// Private keys of the servers, in chain order.
+pub fn server_sks() -> Vec<PrivateKey> {
+    (0..crate::NUM_SERVERS as u8).map(|i| key(0x10 + i)).collect()
+}
+
+pub fn alice_sk() -> PrivateKey {
+    key(0xa1)
+}
+
+pub fn bob_sk() -> PrivateKey {
+    key(0xb0)
+}
+
+/// Ephemeral keys for the bare onion, one per server.
+pub fn onion_ephemeral_sks() -> Vec<PrivateKey> {
+    (0..crate::NUM_SERVERS as u8).map(|i| key(0xe0 + i)).collect()
+}
+
+/// The ephemeral key Alice's message carries for the ratchet.
+pub fn ratchet_ephemeral_sk() -> PrivateKey {
+    key(0xc0)
+}
+
+/// Ephemeral keys for Alice's wrapped message, one per server.
+pub fn wrap_ephemeral_sks() -> Vec<PrivateKey> {
+    (0..crate::NUM_SERVERS as u8).map(|i| key(0xf0 + i)).collect()
+}
+
+/// Named outputs, in the order they are recorded.
+pub type Vectors = Vec<(String, Vec<u8>)>;
+
+/// Compute every vector from the fixed inputs above:
+///  server_pk.i : public key of server i
+///  alice_pk, bob_pk : the clients' public keys
+///  deaddrop : Alice and Bob's deaddrop for the round, from their ratchet
+///  onion : `MESSAGE` onion encrypted for the chain
+///  wrap : `MESSAGE` wrapped by Alice for Bob with `client_util::wrap`
+///  reply : `REPLY_BYTE` content re-encrypted by each server on the way back
+///   to Alice, for the server keys of `wrap`
+pub fn generate() -> Result<Vectors> {
+    let mut v = Vectors::new();
+    let mut server_pks = vec![];
+    for (i, sk) in server_sks().iter().enumerate() {
+        let pk = onion::public_key(sk)?;
+        v.push((format!("server_pk.{}", i), pk.clone()));
+        server_pks.push(pk);
+    }
+    let alice_pk = onion::public_key(&alice_sk())?;
+    let bob_pk = onion::public_key(&bob_sk())?;
+    v.push(("alice_pk".to_string(), alice_pk));
+    v.push(("bob_pk".to_string(), bob_pk.clone()));
+
+    let keys = Ratchet::new(&alice_sk(), &bob_pk)?.keys_with(ROUND, ratchet_ephemeral_sk())?;
+    let mut drop = vec![0; 4];
+    BigEndian::write_u32(&mut drop, keys.deaddrop.location());
+    v.push(("deaddrop".to_string(), drop));
+
+    let (_, o) =
+        message::forward_onion_encrypt_with(&server_pks, &onion_ephemeral_sks(), MESSAGE.to_vec())?;
+    v.push(("onion".to_string(), o));
+
+    let (server_dks, w) =
+        client_util::wrap_with(MESSAGE.to_vec(), &keys, &server_pks, &wrap_ephemeral_sks())?;
+    v.push(("wrap".to_string(), w));
+
+    // the last server encrypts first
+    let mut reply = vec![REPLY_BYTE; *CONTENT_SIZE];
+    for dk in server_dks.iter().rev() {
+        reply = onion::encrypt(dk, reply, EncryptionPurpose::Backward)?;
+    }
+    v.push(("reply".to_string(), reply));
+
+    Ok(v)
+}
+
+/// One `name: hex` line per vector.
+pub fn format(v: &Vectors) -> String {
+    let mut out = String::new();
+    for (name, bytes) in v {
+        out.push_str(name);
+        out.push_str(": ");
+        for b in bytes {
+            out.push_str(&format!("{:02x}", b));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Read vectors written by `format`.
+pub fn parse(s: &str) -> Result<Vectors> {
+    let mut v = Vectors::new();
+    for line in s.lines().filter(|l| !l.trim().is_empty()) {
+        let mut parts = line.splitn(2, ": ");
+        let name = parts.next().unwrap_or("");
+        let hex = parts
+            .next()
+            .ok_or_else(|| Error::Framing(format!("no value for {}", name)))?
+            .trim();
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(Error::Framing(format!("bad hex for {}", name)));
+        }
+        let mut bytes = Vec::with_capacity(hex.len() / 2);
+        for i in (0..hex.len()).step_by(2) {
+            let b = u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| Error::Framing(format!("bad hex for {}", name)))?;
+            bytes.push(b);
+        }
+        v.push((name.to_string(), bytes));
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_invertible() {
+        let v = vec![("a".to_string(), vec![0, 1, 0xff]), ("b".to_string(), vec![])];
+        assert_eq!(format(&v), "a: 0001ff\nb: \n");
+        assert_eq!(parse(&format(&v)).unwrap(), v);
+        assert!(parse("a: 0g").is_err());
+        assert!(parse("a").is_err());
+    }
+
+    #[test]
+    fn generate_deterministic() {
+        assert_eq!(generate().unwrap(), generate().unwrap());
+    }
+}
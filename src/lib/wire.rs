@@ -0,0 +1,246 @@
+use crate::batch::Batch;
+use crate::error::{Error, Result};
+use crate::message::{self, CONTENT_SIZE, RAW_SIZE};
+use crate::onion::{self, CipherSuite};
+
+/// A server's place in a chain, which fixes the size of everything it
+/// receives and sends.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Hop {
+    servers: usize,
+    index: usize,
+}
+
+impl Hop {
+    /// Server `index` (the head server is 0) of a chain of `servers`.
+    pub fn new(servers: usize, index: usize) -> Hop {
+        assert!(index < servers, "server {} is not in a chain of {}", index, servers);
+        Hop { servers, index }
+    }
+
+    /// The head server of a chain of `servers`, which takes messages from clients.
+    pub fn head(servers: usize) -> Hop {
+        Hop::new(servers, 0)
+    }
+
+    // servers after this one
+    fn after(&self) -> usize {
+        self.servers - self.index - 1
+    }
+
+    /// Onions arriving at this server, still wrapped in its own layer.
+    pub fn onion_size(&self) -> usize {
+        message::onion_size(self.after() + 1)
+    }
+
+    /// Onions passed on to the next server, or the packed messages the
+    /// last server exchanges.
+    pub fn forward_size(&self) -> usize {
+        message::onion_size(self.after())
+    }
+
+    /// Replies arriving from the next server.
+    pub fn reply_in_size(&self) -> usize {
+        *CONTENT_SIZE + self.after() * *onion::TAG_LEN
+    }
+
+    /// Replies passed back to the previous server, or to the clients.
+    pub fn reply_out_size(&self) -> usize {
+        self.reply_in_size() + *onion::TAG_LEN
+    }
+}
+
+fn wrong_size(what: &str, expected: usize, got: usize) -> Error {
+    Error::Framing(format!("{} of {} bytes, expected {}", what, got, expected))
+}
+
+/// An onion of the size expected at some hop, as sent by a client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Onion(onion::Message);
+
+impl Onion {
+    /// Check a message is an onion for `hop`, under the chain's cipher suite.
+    pub fn parse(m: onion::Message, hop: Hop) -> Result<Onion> {
+        if m.len() != hop.onion_size() {
+            return Err(wrong_size("onion", hop.onion_size(), m.len()));
+        }
+        Layer::split(&m)?;
+        Ok(Onion(m))
+    }
+
+    /// The outermost layer, for the server it was sent to.
+    pub fn layer(&self) -> Layer {
+        // checked when parsed
+        Layer::split(&self.0).expect("onion without a layer")
+    }
+
+    pub fn into_bytes(self) -> onion::Message {
+        self.0
+    }
+}
+
+/// One server's layer of an onion: the cipher suite, the client's
+/// ephemeral public key, the KEM ciphertext of a hybrid suite, and
+/// everything after them sealed for that server.
+#[derive(Debug)]
+pub struct Layer<'a> {
+    pub suite: CipherSuite,
+    pub pk: &'a [u8],
+    pub kem_ct: &'a [u8],
+    pub sealed: &'a [u8],
+}
+
+impl<'a> Layer<'a> {
+    /// Split a layer off a message, failing if it is too short to hold a
+    /// header or is for another cipher suite than the chain's. A sealed
+    /// part too short for its tag fails to open.
+    pub fn split(w: &'a [u8]) -> Result<Layer<'a>> {
+        if w.len() < message::header_size() {
+            return Err(wrong_size("layer", message::header_size(), w.len()));
+        }
+        let suite = match CipherSuite::from_code(w[0]) {
+            Some(s) if s == onion::suite() => s,
+            _ => return Err(Error::UnsupportedSuite(w[0])),
+        };
+        let (pk, rest) = w[1..].split_at(*onion::PK_LEN);
+        let (kem_ct, sealed) = rest.split_at(suite.kem_ct_len());
+        Ok(Layer {
+            suite,
+            pk,
+            kem_ct,
+            sealed,
+        })
+    }
+
+    /// The key this layer is sealed under, for the server holding `sk`.
+    pub fn key(&self, sk: &onion::PrivateKey) -> Result<onion::DerivedKey> {
+        onion::layer_key_from(self.suite, sk, self.pk, self.kem_ct)
+    }
+}
+
+/// A client's plaintext, padded to the fixed message size.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Payload(Vec<u8>);
+
+impl Payload {
+    /// Pad `m` to `RAW_SIZE` bytes, failing if it does not fit.
+    pub fn new(mut m: Vec<u8>) -> Result<Payload> {
+        if m.len() > RAW_SIZE {
+            return Err(Error::Framing(format!(
+                "message of {} bytes, at most {} fit",
+                m.len(),
+                RAW_SIZE
+            )));
+        }
+        m.resize(RAW_SIZE, 0);
+        Ok(Payload(m))
+    }
+
+    /// Take a decrypted payload, which must be exactly `RAW_SIZE` bytes.
+    pub fn parse(m: Vec<u8>) -> Result<Payload> {
+        if m.len() != RAW_SIZE {
+            return Err(wrong_size("payload", RAW_SIZE, m.len()));
+        }
+        Ok(Payload(m))
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Check a batch received from another server holds whole messages of `size` bytes.
+pub fn check_batch(b: &Batch, size: usize) -> Result<()> {
+    if b.stride() != size {
+        return Err(wrong_size("batch of messages", size, b.stride()));
+    }
+    if b.as_bytes().len() % size != 0 {
+        return Err(Error::Framing(format!(
+            "batch of {} bytes is not a whole number of {} byte messages",
+            b.as_bytes().len(),
+            size
+        )));
+    }
+    Ok(())
+}
+
+/// Check every message in `v` is `size` bytes long.
+pub fn check_messages(v: &[onion::Message], size: usize) -> Result<()> {
+    match v.iter().find(|m| m.len() != size) {
+        Some(m) => Err(wrong_size("message", size, m.len())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sizes_shrink_along_the_chain() {
+        let head = Hop::head(3);
+        assert_eq!(head.onion_size(), message::onion_size(3));
+        assert_eq!(head.forward_size(), Hop::new(3, 1).onion_size());
+        assert_eq!(Hop::new(3, 2).forward_size(), *CONTENT_SIZE + 4);
+
+        // replies grow by a tag at each server on the way back
+        assert_eq!(Hop::new(3, 2).reply_in_size(), *CONTENT_SIZE);
+        assert_eq!(Hop::new(3, 2).reply_out_size(), Hop::new(3, 1).reply_in_size());
+        assert_eq!(head.reply_out_size(), *CONTENT_SIZE + 3 * *onion::TAG_LEN);
+    }
+
+    #[test]
+    fn onion_checks_size() {
+        let hop = Hop::head(2);
+        let mut m = vec![0; hop.onion_size()];
+        m[0] = onion::suite().code();
+        assert!(Onion::parse(m.clone(), hop).is_ok());
+        assert!(Onion::parse(m[1..].to_vec(), hop).is_err());
+        assert!(Onion::parse(vec![], hop).is_err());
+    }
+
+    #[test]
+    fn short_layer_rejected() {
+        assert!(Layer::split(&[1, 2, 3]).is_err());
+        let mut w = vec![7; message::layer_size()];
+        w[0] = onion::suite().code();
+        let layer = Layer::split(&w).unwrap();
+        assert_eq!(layer.pk.len(), *onion::PK_LEN);
+        assert_eq!(layer.sealed.len(), *onion::TAG_LEN);
+        assert!(Layer::split(&w[..message::header_size()]).unwrap().sealed.is_empty());
+    }
+
+    #[test]
+    fn other_suites_rejected() {
+        let hop = Hop::head(2);
+        let mut m = vec![0; hop.onion_size()];
+        assert!(Onion::parse(m.clone(), hop).is_err());
+        m[0] = 0xff;
+        match Onion::parse(m, hop) {
+            Err(Error::UnsupportedSuite(0xff)) => (),
+            other => panic!("expected an unsupported suite, got {:?}", other),
+        }
+        // the wrong size is not mistaken for another suite
+        match Onion::parse(vec![0xff; hop.onion_size() - 1], hop) {
+            Err(Error::Framing(_)) => (),
+            other => panic!("expected a framing error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn payload_padded_or_rejected() {
+        let p = Payload::new(b"hi".to_vec()).unwrap().into_bytes();
+        assert_eq!(p.len(), RAW_SIZE);
+        assert_eq!(&p[..2], b"hi");
+        assert!(Payload::new(vec![1; RAW_SIZE + 1]).is_err());
+        assert!(Payload::parse(vec![1; RAW_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn batches_checked() {
+        let b = Batch::from_messages(4, &vec![vec![1; 4], vec![2; 4]]);
+        assert!(check_batch(&b, 4).is_ok());
+        assert!(check_batch(&b, 8).is_err());
+        assert!(check_messages(&[vec![1; 4], vec![2; 3]], 4).is_err());
+    }
+}
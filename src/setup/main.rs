@@ -1,16 +1,44 @@
+extern crate clap;
 extern crate sharedlib;
-use crate::sharedlib::{keys, onion};
+use crate::sharedlib::error::Result;
+use crate::sharedlib::keys::{self, Encrypted, FileStore};
+use crate::sharedlib::{epoch, onion};
+use clap::{App, Arg};
+use std::process;
 
-fn main() {
-    keys::makedirs().expect("Failed to make dirs");
+fn setup(keys_dir: &str, passphrase_file: &str) -> Result<()> {
+    // every private key is sealed with the passphrase
+    let passphrase = keys::read_passphrase(passphrase_file)?;
+    keys::configure_store(Encrypted::new(FileStore::new(keys_dir), passphrase), None)?;
 
     for i in 0..sharedlib::NUM_CLIENTS {
-        keys::put(keys::PartyType::Client.with_id(i), onion::keygen())
-            .expect("Failed to write client key");
+        keys::put(keys::PartyType::Client.with_id(i), onion::keygen()?)?;
     }
 
     for i in 0..sharedlib::NUM_SERVERS {
-        keys::put(keys::PartyType::Server.with_id(i), onion::keygen())
-            .expect("Failed to write server key");
+        // servers make their onion keys themselves, one pair per epoch
+        keys::put_identity(keys::PartyType::Server.with_id(i), epoch::identity_keygen()?)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let matches = App::new("Vuvuzela Setup")
+                    .version("1.0")
+                    .about("Vuvuzela Setup")
+                    .arg(Arg::with_name("keys")
+                        .long("keys")
+                        .help("Specifies the directory to write the key files to")
+                        .takes_value(true))
+                    .arg(Arg::with_name("passphrase_file")
+                        .long("passphrase_file")
+                        .help("Specifies a file holding the passphrase to seal the private keys with, asked for on the terminal if unset")
+                        .takes_value(true))
+                    .get_matches();
+
+    let keys_dir = matches.value_of("keys").unwrap_or("./keys");
+    if let Err(e) = setup(keys_dir, matches.value_of("passphrase_file").unwrap_or("")) {
+        eprintln!("Failed to set up keys: {}", e);
+        process::exit(1);
     }
 }
@@ -27,9 +27,11 @@ use crate::send::rpc_put;
 use crate::tarpc::futures::compat::Executor01CompatExt;
 use crate::tarpc::futures::FutureExt;
 use crate::tarpc::futures::TryFutureExt;
-use sharedlib::keys::{get, get_keypair, PartyType};
+use sharedlib::epoch;
+use sharedlib::keys::{self, get_keypair, Encrypted, FileStore, PartyType};
 use sharedlib::onion::{PrivateKey, PublicKey};
 use std::io;
+use std::process;
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
@@ -79,6 +81,14 @@ lazy_static! {
                             .long("connections")
                             .help("Specifies how many connections to open per round")
                             .takes_value(true))
+                        .arg(Arg::with_name("keys")
+                            .long("keys")
+                            .help("Specifies the directory holding the key files")
+                            .takes_value(true))
+                        .arg(Arg::with_name("passphrase_file")
+                            .long("passphrase_file")
+                            .help("Specifies a file holding the passphrase of the key files, asked for on the terminal if unset")
+                            .takes_value(true))
                         .get_matches();
 
         // if these unwraps fail, we must panic!
@@ -97,6 +107,11 @@ lazy_static! {
         let server_port = String::from(matches.value_of("port").unwrap_or("8080").clone());
         let connections = String::from(matches.value_of("connections").unwrap_or("10").clone());
 
+        let keys_dir = String::from(matches.value_of("keys").unwrap_or("./keys").clone());
+        let passphrase_file = String::from(matches.value_of("passphrase_file").unwrap_or("").clone());
+
+        m.insert(String::from("keys"), keys_dir);
+        m.insert(String::from("passphrase_file"), passphrase_file);
         m.insert(String::from("connections"), connections);
         m.insert(String::from("server_ip"), server_ip);
         m.insert(String::from("server_port"), server_port);
@@ -115,16 +130,11 @@ lazy_static! {
         priv_key
     };
 
-    static ref SERVER_PUB_KEYS: Vec<PublicKey> = {
-        let mut spk = vec![];
-        spk.push(get(PartyType::Server.with_id(0)).unwrap());
-        spk.push(get(PartyType::Server.with_id(1)).unwrap());
-        spk.push(get(PartyType::Server.with_id(2)).unwrap());
-        spk
-    };
+    // the keys the servers sign their onion keys with
+    static ref SERVER_IDENTITY_PKS: Vec<PublicKey> = epoch::identity_pks().unwrap();
 }
 
-pub async fn spawn_many(thread_id: usize, remote_uid: usize) -> io::Result<()> {
+pub async fn spawn_many(rounds: usize, remote_uid: usize) -> io::Result<()> {
     let uid = HASHMAP
         .get(&String::from("uid"))
         .unwrap()
@@ -144,7 +154,7 @@ pub async fn spawn_many(thread_id: usize, remote_uid: usize) -> io::Result<()> {
         String::from(""),
         uid,
         remote_uid,
-        thread_id
+        rounds
     ))
     .unwrap();
 
@@ -162,6 +172,25 @@ fn main() {
         .unwrap();
     tarpc::init(runtime.executor().compat());
 
+    // private keys are sealed on disk, make sure ours opens before we start,
+    // it is kept in memory from then on
+    let uid = HASHMAP
+        .get(&String::from("uid"))
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    let keys_dir = HASHMAP.get(&String::from("keys")).unwrap();
+    let passphrase_file = HASHMAP.get(&String::from("passphrase_file")).unwrap();
+    let unlocked = keys::read_passphrase(passphrase_file).and_then(|p| {
+        let own = PartyType::Client.with_id(uid);
+        keys::configure_store(Encrypted::new(FileStore::new(keys_dir), p), Some(own.clone()))?;
+        get_keypair(own)
+    });
+    if let Err(e) = unlocked {
+        eprintln!("Unable to open our key: {}", e);
+        process::exit(1);
+    }
+
     let connections = HASHMAP
         .get(&String::from("connections"))
         .unwrap()
@@ -169,23 +198,20 @@ fn main() {
         .unwrap();
 
     let mut threads = vec![];
-    // parallel threads
-    for x in 0..10 {
-        println!("spawning #: {} threads/connections", connections);
-        for y in 1..(connections + 1) {
-            let handler = thread::spawn(move || {
-                tokio::run(
-                    spawn_many(x, y)
-                        .map_err(|e| eprintln!("RPC Error: {}", e))
-                        .boxed()
-                        .compat(),
-                );
-            });
-            threads.push(handler);
-        }
-        for x in threads {
-            x.join().unwrap();
-        }
-        threads = vec![];
+    // parallel threads, each holding one session for all of its rounds
+    println!("spawning #: {} threads/connections", connections);
+    for y in 1..(connections + 1) {
+        let handler = thread::spawn(move || {
+            tokio::run(
+                spawn_many(10, y)
+                    .map_err(|e| eprintln!("RPC Error: {}", e))
+                    .boxed()
+                    .compat(),
+            );
+        });
+        threads.push(handler);
+    }
+    for x in threads {
+        x.join().unwrap();
     }
 }
@@ -1,40 +1,98 @@
-use sharedlib::client_util::wrap;
-use sharedlib::head_rpc::new_stub;
+use sharedlib::client_util::{keep_ratchet, load_ratchet, wrap};
+use sharedlib::conn::socket_addr;
+use sharedlib::epoch::{chain_pks, epoch_of};
+use sharedlib::head_rpc::{new_stub, FetchResult};
 use sharedlib::keys::{get, PartyType};
-use sharedlib::onion::derive;
+use sharedlib::onion::configure_suite;
+use sharedlib::session::Event;
 
+use std::collections::HashMap;
 use std::io;
-use std::net::{IpAddr, SocketAddr};
 use std::string::String;
 use std::time::Instant;
 use tarpc::{client, context};
 use tarpc_bincode_transport::connect;
 
-use crate::{SERVER_PUB_KEYS, MY_PRIV_KEY};
+use crate::{SERVER_IDENTITY_PKS, MY_PRIV_KEY};
 
+/// Simulate one user sending a message in each of `rounds` rounds over a
+/// single session, printing the latency of each round trip.
 pub async fn rpc_put(
     server_addr: String,
     port: u16,
     message: String,
-    _uid: usize,
+    uid: usize,
     remote_uid: usize,
-    thread_id: usize,
+    rounds: usize,
 ) -> io::Result<()> {
     //println!("running async");
-    let server_addr = SocketAddr::new(IpAddr::V4(server_addr.parse().unwrap()), port);
-    let transport = await!(connect(&server_addr)).unwrap();
-    let mut client = await!(new_stub(client::Config::default(), transport)).unwrap();
-    // get round num, this is temporary, actual client that times itself doesnt need this
-    //let rn = await!(client.getrn(context::current())).unwrap();
-    let rn = 0;
-    // get vec of server pkeys
-    let rpk = get(PartyType::Client.with_id(remote_uid * thread_id)).unwrap();
-    let dk = derive(&MY_PRIV_KEY, &rpk);
+    let server_addr = socket_addr(&server_addr, port)?;
+    let transport = await!(connect(&server_addr))?;
+    let mut client = await!(new_stub(client::Config::default(), transport))?;
+    let mut info = await!(client.round_info(context::current()))?;
+    if info.server_pks != *SERVER_IDENTITY_PKS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "server public keys do not match the chain's",
+        ));
+    }
+    configure_suite(info.suite);
+    let session = await!(client.open_session(context::current()))?;
 
-    let (_, enc_msg) = wrap(rn, message.as_bytes().to_vec(), &rpk, &dk, &SERVER_PUB_KEYS);
+    let mut sent = HashMap::new();
+    // each peer's conversation, kept in the key store like the client's
+    let mut ratchets = HashMap::new();
+    let mut done = 0;
+    while done < rounds {
+        let events = match await!(client.next_events(context::current(), session))? {
+            Some(e) => e,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("session {} expired", session),
+                ))
+            }
+        };
+        for e in events {
+            match e {
+                // submit once in each announced round
+                Event::RoundOpen { round, .. } if sent.len() + done < rounds => {
+                    let peer = remote_uid * (sent.len() + done);
+                    if !ratchets.contains_key(&peer) {
+                        let rpk = get(PartyType::Client.with_id(peer))?;
+                        ratchets.insert(peer, load_ratchet(uid, peer, &MY_PRIV_KEY, &rpk)?);
+                    }
+                    let ratchet = ratchets.get_mut(&peer).unwrap();
+                    let keys = ratchet.keys(round)?;
+                    keep_ratchet(uid, peer, ratchet)?;
+                    // the chain's keys are handed out ahead of each epoch
+                    let e = epoch_of(round);
+                    if chain_pks(&info.epoch_keys, &SERVER_IDENTITY_PKS, 0, e).is_err() {
+                        info = await!(client.round_info(context::current()))?;
+                    }
+                    let server_pks = chain_pks(&info.epoch_keys, &SERVER_IDENTITY_PKS, 0, e)?;
+                    let (_, enc_msg) = wrap(message.as_bytes().to_vec(), &keys, &server_pks)?;
 
-    let now = Instant::now();
-    let _return_msg = await!(client.put(context::current(), enc_msg.clone())).unwrap();
-    println!("{}", now.elapsed().as_millis());
+                    let now = Instant::now();
+                    match await!(client.session_put(context::current(), session, enc_msg))? {
+                        Ok(ticket) => {
+                            sent.insert(ticket, now);
+                        }
+                        Err(e) => println!("round {} refused our message: {:?}", round, e),
+                    }
+                }
+                Event::RoundResult { ticket, reply } => {
+                    if let Some(now) = sent.remove(&ticket) {
+                        match reply {
+                            FetchResult::Failed => println!("round {} failed", ticket.round),
+                            _ => println!("{}", now.elapsed().as_millis()),
+                        }
+                        done += 1;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,15 @@
+extern crate sharedlib;
+use crate::sharedlib::vectors;
+use std::process;
+
+// prints the known-answer vectors, to be saved as tests/vectors.txt
+// whenever the message format changes on purpose
+fn main() {
+    match vectors::generate() {
+        Ok(v) => print!("{}", vectors::format(&v)),
+        Err(e) => {
+            eprintln!("Failed to generate vectors: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -1,23 +1,29 @@
-use sharedlib::{client_util, laplace, onion, util};
+use sharedlib::batch::Batch;
+use sharedlib::ratchet::Ratchet;
+use sharedlib::{client_util, laplace, message, onion, util};
 
 #[test]
 fn crypto_integration_test() {
     // server keys
-    let (sk0, pk0) = onion::keygen();
-    let (sk1, pk1) = onion::keygen();
-    let (sk2, pk2) = onion::keygen();
+    let (sk0, pk0) = onion::keygen().unwrap();
+    let (sk1, pk1) = onion::keygen().unwrap();
+    let (sk2, pk2) = onion::keygen().unwrap();
     let server_pks = vec![pk0, pk1, pk2];
 
     // client keys
-    let (ska, pka) = onion::keygen();
-    let (skb, pkb) = onion::keygen();
-    let (skc, pkc) = onion::keygen();
+    let (ska, pka) = onion::keygen().unwrap();
+    let (skb, pkb) = onion::keygen().unwrap();
+    let (skc, pkc) = onion::keygen().unwrap();
 
-    // derived keys
-    let dka = onion::derive(&ska, &pkb);
-    let dkb = onion::derive(&skb, &pka);
-    assert_eq!(dka, dkb);
-    let dkc = onion::derive(&skc, &pkc);
+    // conversation keys for the round
+    let r = 3;
+    let mut ra = Ratchet::new(&ska, &pkb).unwrap();
+    let mut rb = Ratchet::new(&skb, &pka).unwrap();
+    let mut rc = Ratchet::new(&skc, &pkc).unwrap();
+    let ka = ra.keys(r).unwrap();
+    let kb = rb.keys(r).unwrap();
+    assert_eq!(ka.deaddrop, kb.deaddrop);
+    let kc = rc.keys(r).unwrap();
 
     // messages
     let ma = "Hello, Bob!".as_bytes().to_vec();
@@ -25,12 +31,12 @@ fn crypto_integration_test() {
     let mc = "Hello, Charlie!".as_bytes().to_vec();
 
     // wrap
-    let r = 3;
-    let (server_dksa, wa) = client_util::wrap(r, ma, &pkb, &dka, &server_pks);
-    let (server_dksb, wb) = client_util::wrap(r, mb, &pka, &dkb, &server_pks);
-    let (server_dksc, wc) = client_util::wrap(r, mc, &pkc, &dkc, &server_pks);
+    let (server_dksa, wa) = client_util::wrap(ma, &ka, &server_pks).unwrap();
+    let (server_dksb, wb) = client_util::wrap(mb, &kb, &server_pks).unwrap();
+    let (server_dksc, wc) = client_util::wrap(mc, &kc, &server_pks).unwrap();
     let in0 = vec![wa, wb, wc];
     println!("Message: {:?}, len: {}", in0[2], in0[2].len());
+    let in0 = Batch::from_messages(message::onion_size(server_pks.len()), &in0);
 
     // noise
     let laplace = laplace::Laplace::new(1.0, 10.0);
@@ -42,68 +48,73 @@ fn crypto_integration_test() {
         other_pks: server_pks[1..].to_vec(),
         sk: sk0,
         noise: noise.clone(),
+        pool: None,
     };
     let s1 = util::Settings {
         other_pks: server_pks[2..].to_vec(),
         sk: sk1,
         noise: noise.clone(),
+        pool: None,
     };
     let s2 = util::Settings {
         other_pks: server_pks[3..].to_vec(),
         sk: sk2,
         noise: noise.clone(),
+        pool: None,
     };
 
     // forward
     //    println!("in0 len: {}", in0.len());
-    println!("in0[0] len: {}", in0[0].len());
-    let (s0, in1) = util::forward(in0, &s0);
+    println!("in0[0] len: {}", in0.stride());
+    let (s0, in1) = util::forward(in0, &s0).unwrap();
     //println!("in1 len: {}", in1.len());
-    println!("in1[0] len: {}", in1[0].len());
-    let (s1, in2) = util::forward(in1, &s1);
+    println!("in1[0] len: {}", in1.stride());
+    let (s1, in2) = util::forward(in1, &s1).unwrap();
     //println!("in2 len: {}", in2.len());
-    println!("in2[0] len: {}", in2[0].len());
-    let (s2, in3) = util::forward(in2, &s2);
+    println!("in2[0] len: {}", in2.stride());
+    let (s2, in3) = util::forward(in2, &s2).unwrap();
     //println!("in3 len: {}", in3.len());
-    println!("in3[0] len: {}", in3[0].len());
+    println!("in3[0] len: {}", in3.stride());
 
     // deaddrop
     let out3 = util::deaddrop(in3);
     //println!("out3 len: {}", out3.len());
-    println!("out3[0] len: {}", out3[0].len());
+    println!("out3[0] len: {}", out3.stride());
 
     // backward
-    let out2 = util::backward(s2, out3);
+    let out2 = util::backward(s2, out3).unwrap();
     //println!("out2 len: {}", out2.len());
-    println!("out2[0] len: {}", out2[0].len());
-    let out1 = util::backward(s1, out2);
+    println!("out2[0] len: {}", out2.stride());
+    let out1 = util::backward(s1, out2).unwrap();
     //println!("out1 len: {}", out1.len());
-    println!("out1[0] len: {}", out1[0].len());
-    let out0 = util::backward(s0, out1);
+    println!("out1[0] len: {}", out1.stride());
+    let out0 = util::backward(s0, out1).unwrap();
     //println!("out0 len: {}", out0.len());
-    println!("out0[0] len: {}", out0[0].len());
+    println!("out0[0] len: {}", out0.stride());
+
+    let out0 = out0.to_messages();
 
     // unwrap and compare
     //println!("try unwrap Alice...");
-    let oa = client_util::unwrap(r, out0[0].clone(), &pka, &dka, server_dksa).unwrap();
+    let oa = client_util::unwrap(out0[0].clone(), &ka, &mut ra, server_dksa).unwrap();
     //println!("try unwrap Bob...");
-    let ob = client_util::unwrap(r, out0[1].clone(), &pkb, &dkb, server_dksb).unwrap();
+    let ob = client_util::unwrap(out0[1].clone(), &kb, &mut rb, server_dksb).unwrap();
     //println!("try unwrap Charlie...");
-    let oc = client_util::unwrap(r, out0[2].clone(), &pkc, &dkc, server_dksc).unwrap();
+    let oc = client_util::unwrap(out0[2].clone(), &kc, &mut rc, server_dksc).unwrap();
     //println!("Response: {:?}, len: {}", out0[2], out0[2].len());
 
-    let ra = std::str::from_utf8(&oa)
+    let ta = std::str::from_utf8(&oa)
         .unwrap()
         .trim_end_matches(0 as char);
-    let rb = std::str::from_utf8(&ob)
+    let tb = std::str::from_utf8(&ob)
         .unwrap()
         .trim_end_matches(0 as char);
-    let rc = std::str::from_utf8(&oc)
+    let tc = std::str::from_utf8(&oc)
         .unwrap()
         .trim_end_matches(0 as char);
 
     assert_eq!(
-        (ra, rb, rc),
+        (ta, tb, tc),
         ("Hello, Alice!", "Hello, Bob!", "Hello, Charlie!")
     );
 }
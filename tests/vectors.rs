@@ -0,0 +1,72 @@
+use sharedlib::onion::{self, EncryptionPurpose};
+use sharedlib::ratchet::Ratchet;
+use sharedlib::{client_util, message, vectors};
+
+// regenerate with `cargo run --bin vectors > tests/vectors.txt`, only when
+// the format is meant to change
+const RECORDED: &str = include_str!("vectors.txt");
+
+fn recorded(name: &str) -> Vec<u8> {
+    vectors::parse(RECORDED)
+        .unwrap()
+        .into_iter()
+        .find(|(n, _)| n == name)
+        .unwrap_or_else(|| panic!("no recorded vector {}", name))
+        .1
+}
+
+#[test]
+fn outputs_match_recorded_vectors() {
+    let expected = vectors::parse(RECORDED).unwrap();
+    let got = vectors::generate().unwrap();
+    let names: Vec<&String> = got.iter().map(|(n, _)| n).collect();
+    assert_eq!(names, expected.iter().map(|(n, _)| n).collect::<Vec<_>>());
+    for ((name, bytes), (_, want)) in got.iter().zip(expected.iter()) {
+        assert_eq!(bytes, want, "vector {} changed", name);
+    }
+}
+
+#[test]
+fn recorded_onion_peels_to_message() {
+    let mut w = recorded("onion");
+    for sk in vectors::server_sks() {
+        let (pk, c) = message::unwrap(&w).unwrap();
+        let dk = onion::derive(&sk, &pk).unwrap();
+        w = onion::decrypt(&dk, c, EncryptionPurpose::Forward).unwrap();
+    }
+    assert_eq!(w, vectors::MESSAGE);
+}
+
+#[test]
+fn recorded_wrap_reaches_bob() {
+    let mut w = recorded("wrap");
+    assert_eq!(w.len(), message::onion_size(sharedlib::NUM_SERVERS));
+    for sk in vectors::server_sks() {
+        let (pk, c) = message::unwrap(&w).unwrap();
+        let dk = onion::derive(&sk, &pk).unwrap();
+        w = onion::decrypt(&dk, c, EncryptionPurpose::Forward).unwrap();
+    }
+    let (_, drop) = message::unpack(w).unwrap();
+    assert_eq!(drop.location().to_be_bytes().to_vec(), recorded("deaddrop"));
+}
+
+#[test]
+fn recorded_reply_opens_for_alice() {
+    let server_pks: Vec<onion::PublicKey> = vectors::server_sks()
+        .iter()
+        .map(|sk| onion::public_key(sk).unwrap())
+        .collect();
+    let bob_pk = onion::public_key(&vectors::bob_sk()).unwrap();
+    let mut ratchet = Ratchet::new(&vectors::alice_sk(), &bob_pk).unwrap();
+    let keys = ratchet.keys(vectors::ROUND).unwrap();
+    let (server_dks, _) = client_util::wrap_with(
+        vectors::MESSAGE.to_vec(),
+        &keys,
+        &server_pks,
+        &vectors::wrap_ephemeral_sks(),
+    )
+    .unwrap();
+
+    let reply = message::backward_onion_decrypt(&server_dks, recorded("reply")).unwrap();
+    assert_eq!(reply, vec![vectors::REPLY_BYTE; *message::CONTENT_SIZE]);
+}